@@ -1,16 +1,5 @@
 use_config = true
 
-local function writeb_serial(adr, x) 
-	-- print("Being Written to at", adr, "with", x)
-	io.write(string.char(x))
-end
-
-local function readb_serial(adr) 
-	print("Being Read - Nothing Good")
-	print(adr)
-	return 0
-end
-
 addresses = {
 	bootloader = {
 		origin = 0,
@@ -24,13 +13,11 @@ addresses = {
 
 		len = 1000,
 	},
-	{
+	serial = {
 		origin = 500,
-		type = "func",
+		type = "uart",
 
-		len = 100,
-		readb = readb_serial,
-		writeb = writeb_serial,
+		backend = "stdout",
 	}
 }
 
@@ -1,6 +1,31 @@
 use crate::core::{AByte, AHalfWord, AWord};
+use crate::snapshot::StateReader;
+use crate::vcd::Probe;
 
-const LITTLE_ENDIAN: bool = true;
+/// Byte order of data accesses, instructions are always fetched little endian
+///
+/// ARMv6-M fixes this at reset and reports it in AIRCR.ENDIANNESS, `Big` is BE-8, so the byte at
+/// each address is the same in both modes, only halfwords and words are assembled differently.
+/// The Private Peripheral Bus(`PPB`) is little endian in both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+impl Endian {
+    /// System registers(NVIC, SCB, SysTick...), always accessed little endian
+    pub const PPB: std::ops::RangeInclusive<AWord> = 0xe0000000..=0xe00fffff;
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "little" => Some(Self::Little),
+            "big" => Some(Self::Big),
+            _ => None,
+        }
+    }
+}
 
 pub trait AddressSpace {
     fn origin(&self) -> AWord;
@@ -11,7 +36,7 @@ pub trait AddressSpace {
 
     // Reads
     fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {
-        debug_assert!(adr % 2 == 0);
+        debug_assert!(adr.is_multiple_of(2));
         let bytes: [AByte; 2] = [
             self.readb(adr),
             self.readb(adr+1)
@@ -20,7 +45,7 @@ pub trait AddressSpace {
     }
     fn read_hw_be(&mut self, adr: AWord) -> AHalfWord {self.read_hw_le(adr).swap_bytes()}
     fn read_w_le(&mut self, adr: AWord) -> AWord {
-        debug_assert!(adr % 4 == 0);
+        debug_assert!(adr.is_multiple_of(4));
         let bytes: [AByte; 4] = [
             self.readb(adr),
             self.readb(adr+1),
@@ -34,14 +59,14 @@ pub trait AddressSpace {
 
     // Writes
     fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
-        debug_assert!(adr % 2 == 0);
+        debug_assert!(adr.is_multiple_of(2));
         let bytes = x.to_le_bytes();
         self.writeb(adr, bytes[0]);
         self.writeb(adr + 1, bytes[1]);
     }
     fn write_hw_be(&mut self, adr: AWord, x: AHalfWord) {self.write_hw_le(adr, x.swap_bytes());}
     fn write_w_le(&mut self, adr: AWord, x: AWord) {
-        debug_assert!(adr % 2 == 0);
+        debug_assert!(adr.is_multiple_of(2));
         let bytes = x.to_le_bytes();
         self.writeb(adr, bytes[0]);
         self.writeb(adr + 1, bytes[1]);
@@ -50,31 +75,116 @@ pub trait AddressSpace {
     }
     fn write_w_be(&mut self, adr: AWord, x: AWord) {self.write_w_le(adr, x.swap_bytes());}
 
-    // Aliases
+    /// Instruction fetch, unlike data reads this needs execute permission
+    fn fetch_hw(&mut self, adr: AWord) -> AHalfWord {self.read_hw_le(adr)}
+
+    // Aliases, in the byte order of the core
+    fn endian(&self) -> Endian {Endian::Little}
+    /// Byte order of data accesses at `adr`
+    fn endian_at(&self, adr: AWord) -> Endian {
+        match Endian::PPB.contains(&adr) {
+            true => Endian::Little,
+            false => self.endian(),
+        }
+    }
     fn read_hw(&mut self, adr: AWord) -> AHalfWord {
-        match LITTLE_ENDIAN {
-            true => self.read_hw_le(adr),
-            false => self.read_hw_be(adr)
+        match self.endian_at(adr) {
+            Endian::Little => self.read_hw_le(adr),
+            Endian::Big => self.read_hw_be(adr)
         }
     }
     fn read_w(&mut self, adr: AWord) -> AWord {
-        match LITTLE_ENDIAN {
-            true => self.read_w_le(adr),
-            false => self.read_w_be(adr)
+        match self.endian_at(adr) {
+            Endian::Little => self.read_w_le(adr),
+            Endian::Big => self.read_w_be(adr)
         }
     }
     fn write_hw(&mut self, adr: AWord, x: AHalfWord) {
-        match LITTLE_ENDIAN {
-            true => self.write_hw_le(adr, x),
-            false => self.write_hw_be(adr, x)
+        match self.endian_at(adr) {
+            Endian::Little => self.write_hw_le(adr, x),
+            Endian::Big => self.write_hw_be(adr, x)
         }
     }
     fn write_w(&mut self, adr: AWord, x: AWord) {
-        match LITTLE_ENDIAN {
-            true => self.write_w_le(adr, x),
-            false => self.write_w_be(adr, x)
+        match self.endian_at(adr) {
+            Endian::Little => self.write_w_le(adr, x),
+            Endian::Big => self.write_w_be(adr, x)
+        }
+    }
+
+    // Snapshots
+    /// Append whatever is needed to restore this region bit-exactly later on
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+    /// Inverse of `save_state`, given exactly the bytes it produced
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Called after every instruction with the cycles it took, for peripherals that model time
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Report the signals shown in a waveform of the run, eg pin levels
+    fn probe(&self, _probe: &mut Probe) {}
+
+    /// Whether the state survives a system reset, eg flash or battery backed RAM
+    fn non_volatile(&self) -> bool {false}
+    /// Like `save_state`, but only what a system reset puts back, nothing when non volatile
+    fn save_volatile(&self, out: &mut Vec<u8>) {
+        if !self.non_volatile() {
+            self.save_state(out);
+        }
+    }
+    /// Inverse of `save_volatile`, a system reset
+    fn restore_volatile(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match self.non_volatile() {
+            true => Ok(()),
+            false => self.restore_state(state),
+        }
+    }
+
+    /// Write anything backed by host files back to them
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// A device behind a peripheral, eg a flash chip on an SPI bus, saved along with the peripheral
+pub trait DeviceState {
+    /// Like `AddressSpace::save_state`
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+    /// Whether the contents survive a system reset, eg a flash chip
+    fn non_volatile(&self) -> bool {false}
+    /// Write anything backed by host files back to them
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Append the state of each of `devices` preceded by its length, an empty one for non volatile
+/// devices when only `volatile` state is saved
+pub fn save_devices<'d>(devices: impl Iterator<Item = &'d dyn DeviceState>, out: &mut Vec<u8>, volatile: bool) {
+    for device in devices {
+        let mut state = Vec::new();
+        if !(volatile && device.non_volatile()) {
+            device.save_state(&mut state);
+        }
+        out.extend((state.len() as AWord).to_le_bytes());
+        out.extend(state);
+    }
+}
+/// Inverse of `save_devices`
+pub fn restore_devices<'d>(devices: impl Iterator<Item = &'d mut dyn DeviceState>, reader: &mut StateReader, volatile: bool)
+    -> Result<(), Box<dyn std::error::Error>> {
+    for device in devices {
+        let state = reader.counted()?;
+        if !(volatile && device.non_volatile()) {
+            device.restore_state(state)?;
         }
     }
+    Ok(())
 }
 
 impl std::fmt::Debug for dyn AddressSpace {
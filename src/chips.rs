@@ -0,0 +1,381 @@
+use crate::description::{Description, Format};
+
+/// What `chip = "..."` accepts
+pub const CHIP_NAMES: &str = "one of \"stm32f030\", \"nrf51822\", \"rp2040-core\", \"samd21\" or \"lpc1114\"";
+
+// Only the memories and the peripherals the emulator models are mapped, the program goes into
+// `flash` through its `path`. Peripherals take the base address and IRQ of one of their
+// counterparts on the part, with the registers of the emulator's model, and `frequency` is the
+// core clock the part usually runs at. The core boots from the vector table at 0, so parts booting
+// from elsewhere get a `boot` alias there.
+
+/// STM32F030x8, 64KiB of flash mirrored at 0 and its flash interface, 8KiB of SRAM
+const STM32F030: &str = r#"
+frequency = 48000000
+[addresses.flash]
+type = "flash"
+origin = 0x08000000
+len = 0x10000
+page_size = 1024
+controller = 0x40022000
+[addresses.boot]
+type = "alias"
+origin = 0
+target = "flash"
+[addresses.sram]
+type = "ram"
+origin = 0x20000000
+len = 0x2000
+[addresses.nvic]
+type = "nvic"
+origin = 0xe000e100
+[addresses.scb]
+type = "scb"
+origin = 0xe000ed00
+cpuid = 0x410cc200
+[addresses.uart]
+type = "uart"
+origin = 0x40013800
+irq = 27
+[addresses.gpio]
+type = "gpio"
+origin = 0x48000000
+irq = 7
+[addresses.timer]
+type = "timer"
+origin = 0x40000400
+irq = 16
+[addresses.spi]
+type = "spi"
+origin = 0x40013000
+irq = 25
+[addresses.i2c]
+type = "i2c"
+origin = 0x40005400
+irq = 23
+[addresses.dma]
+type = "dma"
+origin = 0x40020000
+irq = 9
+channels = 5
+[addresses.watchdog]
+type = "watchdog"
+origin = 0x40003000
+[addresses.rtc]
+type = "rtc"
+origin = 0x40002800
+irq = 2
+"#;
+
+/// nRF51822-QFAA, 256KiB of flash and 16KiB of RAM
+const NRF51822: &str = r#"
+frequency = 16000000
+[addresses.flash]
+type = "flash"
+origin = 0
+len = 0x40000
+page_size = 1024
+[addresses.sram]
+type = "ram"
+origin = 0x20000000
+len = 0x4000
+[addresses.nvic]
+type = "nvic"
+origin = 0xe000e100
+[addresses.scb]
+type = "scb"
+origin = 0xe000ed00
+cpuid = 0x410cc200
+[addresses.uart]
+type = "uart"
+origin = 0x40002000
+irq = 2
+[addresses.gpio]
+type = "gpio"
+origin = 0x50000000
+irq = 6
+[addresses.timer]
+type = "timer"
+origin = 0x40008000
+irq = 8
+[addresses.spi]
+type = "spi"
+origin = 0x40004000
+irq = 4
+[addresses.i2c]
+type = "i2c"
+origin = 0x40003000
+irq = 3
+[addresses.watchdog]
+type = "watchdog"
+origin = 0x40010000
+[addresses.rtc]
+type = "rtc"
+origin = 0x4000b000
+irq = 11
+"#;
+
+/// RP2040 as one core sees it, 2MiB of XIP flash and 264KiB of striped SRAM
+///
+/// Images start with the 256 byte boot2 stage, which the boot ROM runs before jumping through the
+/// vector table right after it. The `boot` alias maps that vector table at 0 in place of the ROM,
+/// so the program starts as if boot2 had run.
+const RP2040_CORE: &str = r#"
+frequency = 125000000
+[addresses.flash]
+type = "flash"
+origin = 0x10000000
+len = 0x200000
+page_size = 4096
+[addresses.boot]
+type = "alias"
+origin = 0
+target = "flash"
+offset = 0x100
+len = 0x4000
+[addresses.sram]
+type = "ram"
+origin = 0x20000000
+len = 0x42000
+[addresses.nvic]
+type = "nvic"
+origin = 0xe000e100
+[addresses.scb]
+type = "scb"
+origin = 0xe000ed00
+cpuid = 0x410cc601
+[addresses.uart]
+type = "uart"
+origin = 0x40034000
+irq = 20
+[addresses.gpio]
+type = "gpio"
+origin = 0xd0000000
+irq = 13
+[addresses.timer]
+type = "timer"
+origin = 0x40054000
+irq = 0
+[addresses.spi]
+type = "spi"
+origin = 0x4003c000
+irq = 18
+[addresses.i2c]
+type = "i2c"
+origin = 0x40044000
+irq = 23
+[addresses.dma]
+type = "dma"
+origin = 0x50000000
+irq = 11
+[addresses.watchdog]
+type = "watchdog"
+origin = 0x40058000
+[addresses.rtc]
+type = "rtc"
+origin = 0x4005c000
+irq = 25
+"#;
+
+/// ATSAMD21J18A, 256KiB of flash in 64 byte pages and 32KiB of SRAM
+const SAMD21: &str = r#"
+frequency = 48000000
+[addresses.flash]
+type = "flash"
+origin = 0
+len = 0x40000
+page_size = 64
+[addresses.sram]
+type = "ram"
+origin = 0x20000000
+len = 0x8000
+[addresses.nvic]
+type = "nvic"
+origin = 0xe000e100
+[addresses.scb]
+type = "scb"
+origin = 0xe000ed00
+cpuid = 0x410cc601
+[addresses.uart]
+type = "uart"
+origin = 0x42000800
+irq = 9
+[addresses.gpio]
+type = "gpio"
+origin = 0x41004400
+irq = 4
+[addresses.timer]
+type = "timer"
+origin = 0x42002c00
+irq = 18
+[addresses.spi]
+type = "spi"
+origin = 0x42001800
+irq = 13
+[addresses.i2c]
+type = "i2c"
+origin = 0x42001400
+irq = 12
+[addresses.dma]
+type = "dma"
+origin = 0x41004800
+irq = 6
+[addresses.watchdog]
+type = "watchdog"
+origin = 0x40001000
+[addresses.rtc]
+type = "rtc"
+origin = 0x40001400
+irq = 3
+"#;
+
+/// LPC1114/302, 32KiB of flash in 4KiB sectors and 8KiB of SRAM
+const LPC1114: &str = r#"
+frequency = 48000000
+[addresses.flash]
+type = "flash"
+origin = 0
+len = 0x8000
+page_size = 4096
+[addresses.sram]
+type = "ram"
+origin = 0x10000000
+len = 0x2000
+[addresses.nvic]
+type = "nvic"
+origin = 0xe000e100
+[addresses.scb]
+type = "scb"
+origin = 0xe000ed00
+cpuid = 0x410cc200
+[addresses.uart]
+type = "uart"
+origin = 0x40008000
+irq = 21
+[addresses.gpio]
+type = "gpio"
+origin = 0x50000000
+irq = 31
+[addresses.timer]
+type = "timer"
+origin = 0x40014000
+irq = 18
+[addresses.spi]
+type = "spi"
+origin = 0x40040000
+irq = 20
+[addresses.i2c]
+type = "i2c"
+origin = 0x40000000
+irq = 15
+[addresses.watchdog]
+type = "watchdog"
+origin = 0x40004000
+"#;
+
+const CHIPS: [(&str, &str); 5] = [
+    ("stm32f030", STM32F030),
+    ("nrf51822", NRF51822),
+    ("rp2040-core", RP2040_CORE),
+    ("samd21", SAMD21),
+    ("lpc1114", LPC1114),
+];
+
+pub fn preset(name: &str) -> Option<Description> {
+    let (_, text) = CHIPS.iter().find(|(chip, _)| *chip == name)?;
+    Some(Description::parse(text, Format::Toml).expect("Presets are valid"))
+}
+
+/// Whether `fields` of a config region should be merged into the preset region `base` rather
+/// than replace it, which is the case unless they give another `type`
+pub fn overrides(base: &serde_json::Map<String, serde_json::Value>, rtype: Option<&str>) -> bool {
+    rtype.is_none_or(|rtype| base.get("type").and_then(|base| base.as_str()) == Some(rtype))
+}
+
+/// Put the top level fields and regions of a TOML or JSON config over the preset, a region
+/// labeled like a preset one only changes the fields it has and `false` removes it
+pub fn overlay(preset: &Description, config: serde_json::Value) -> Result<Description, String> {
+    let mut merged = serde_json::to_value(preset).map_err(|err| err.to_string())?;
+    let serde_json::Value::Object(config) = config else {
+        return Err("expected a table at the top level".to_string());
+    };
+    for (key, value) in config {
+        match (key.as_str(), value) {
+            ("chip", _) => {},
+            ("addresses", serde_json::Value::Object(regions)) => {
+                let addresses = merged["addresses"].as_object_mut().expect("Descriptions have addresses");
+                for (label, region) in regions {
+                    match (addresses.get_mut(&label), region) {
+                        (_, serde_json::Value::Bool(false)) => {
+                            addresses.remove(&label);
+                        },
+                        (Some(serde_json::Value::Object(base)), serde_json::Value::Object(fields))
+                            if overrides(base, fields.get("type").and_then(|rtype| rtype.as_str())) => base.extend(fields),
+                        (_, region) => {
+                            addresses.insert(label, region);
+                        },
+                    }
+                }
+            },
+            (_, value) => {
+                merged[key] = value;
+            },
+        }
+    }
+    serde_json::from_value(merged).map_err(|err| err.to_string())
+}
+
+#[test]
+fn test_chip_presets() {
+    use crate::adr::AddressSpace;
+    use crate::description::RegionKind;
+    for (name, _) in CHIPS {
+        let description = preset(name).unwrap();
+        assert!(description.addresses.contains_key("flash") && description.addresses.contains_key("scb"), "{name}");
+    }
+
+    let config = "chip = \"stm32f030\"
+[addresses]
+scb = false
+[addresses.sram]
+len = 0x1000
+[addresses.boot]
+type = \"ram\"
+origin = 0
+len = 16
+";
+    let description = Description::parse(config, Format::Toml).unwrap();
+    assert!(matches!(description.addresses["sram"].kind, RegionKind::Ram {len: 0x1000}));
+    assert_eq!(description.addresses["sram"].origin, 0x2000_0000);
+    assert!(matches!(description.addresses["boot"].kind, RegionKind::Ram {len: 16}));
+    assert!(!description.addresses.contains_key("scb"));
+    assert!(Description::parse("chip = \"z80\"", Format::Toml).unwrap_err().contains("unknown chip"));
+
+    // CPUID and AIRCR through the bus, and the vector table after boot2 at 0
+    let path = std::env::temp_dir().join("cm0-chip-test.lua");
+    let image = std::env::temp_dir().join("cm0-chip-test.bin");
+    let mut boot2 = vec![0; 0x108];
+    boot2[0x100..].copy_from_slice(&[0x00, 0x10, 0x00, 0x20, 0x01, 0x01, 0x00, 0x10]);
+    std::fs::write(&image, boot2).unwrap();
+    let config = format!("use_config = true chip = \"rp2040-core\" addresses = {{ sram = {{ len = 0x100 }}, flash = {{ path = {:?} }} }}", image.to_str().unwrap());
+    std::fs::write(&path, config).unwrap();
+    let mut memory = crate::config::load(path.to_str().unwrap(), &Default::default()).unwrap().memory;
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&image).unwrap();
+    assert_eq!(memory.read_w(0xe000ed00), 0x410c_c601);
+    assert_eq!(memory.read_w(0xe000ed0c), 0xfa05_0000);
+    assert_eq!((memory.read_w(0), memory.read_w(4)), (0x2000_1000, 0x1000_0101));
+    assert_eq!(memory.regions().find(|region| region.label == "sram").unwrap().space.len(), 0x100);
+
+    // Every preset maps without overlaps, with the modelled peripherals it has
+    for (name, _) in CHIPS {
+        std::fs::write(&path, format!("use_config = true chip = {name:?} addresses = {{}}")).unwrap();
+        let machine = crate::config::load(path.to_str().unwrap(), &Default::default()).unwrap();
+        assert!(machine.frequency.is_some(), "{name}");
+        let kinds: Vec<_> = machine.memory.regions().map(|region| region.kind).collect();
+        assert!(["uart", "gpio", "timer", "spi", "i2c", "watchdog"].iter().all(|kind| kinds.contains(kind)), "{name}");
+        assert_eq!(kinds.contains(&"dma"), !["nrf51822", "lpc1114"].contains(&name), "{name}");
+        assert_eq!(kinds.contains(&"rtc"), name != "lpc1114", "{name}");
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,80 +1,850 @@
-use crate::adr::AddressSpace;
-use crate::core::{AByte, AWord};
-use crate::memory::{AddressDeMultiplexer, BufferMemory, FunctionalAddressSpace};
+use std::collections::HashMap;
+use crate::adr::{AddressSpace, Endian};
+use crate::core::AWord;
+use crate::memory::{AddressDeMultiplexer, Alias, BufferMemory, BusMaster, FunctionalAddressSpace, MapError, MappedRegion, Permissions, PersistentMemory, ReadFn, RestoreFn, SaveFn, TickFn, WriteFn};
+use crate::context::{DmaLines, SharedContext};
+use crate::description::{Description, Format, I2cDeviceSpec, RegionKind, RegionSpec, SpiDeviceSpec, Stimulus};
+use crate::chips::{self, CHIP_NAMES};
+use crate::exceptions::{NvicRegisters, ScbRegisters, CPUID_M0, IRQ_COUNT};
+use crate::hooks::Hooks;
+use crate::scripting::{Sandbox, Script};
 use crate::fstools::read_file_buffer;
+use crate::flash::{Flash, FlashArray, FlashController};
+use crate::uart::{Host, Uart};
+use crate::gpio::{self, Event, Gpio, SharedPins};
+use crate::timer::{Timer, MAX_CHANNELS};
+use crate::spi::{Loopback, NorFlash, Spi, SpiDevice};
+use crate::i2c::{Eeprom, I2c, I2cDevice, Sensor, UpdateFn};
+use crate::dma::{self, Dma};
+use crate::watchdog::Watchdog;
+use crate::rtc::Rtc;
 
-pub fn load() -> Box<dyn AddressSpace> {
-    // Load the Config
-    let config_file = std::fs::read_to_string("./config.lua").expect("Bad File");
-    let lua = mlua::Lua::new();
-    lua.load_std_libs(mlua::StdLib::ALL_SAFE).expect("Failed to load lua stdlib");
-    let module = lua.load(config_file.as_str());
+/// Everything that can be wrong with a config, meant to be shown to the user as is
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file itself couldn't be read
+    Read { path: String, source: std::io::Error },
+    /// Syntax or runtime error while running the config, the message contains the location
+    Lua(mlua::Error),
+    /// A global is missing or has the wrong type
+    Global { name: &'static str, expected: &'static str, found: &'static str },
+    /// `use_config` is false
+    Disabled,
+    /// A region field is missing or has the wrong type
+    Field { region: String, field: &'static str, expected: &'static str, found: &'static str },
+    /// `type` isn't one of the known region types
+    RegionType { region: String, found: String },
+    /// A file referred to by a region couldn't be read
+    File { region: String, path: String, source: Box<dyn std::error::Error> },
+    /// The fields are fine on their own but don't make sense together
+    Invalid { region: String, reason: String },
+    /// The region doesn't fit in the memory map
+    Map(MapError),
+    /// An entry of the `hooks` table is wrong
+    Hook { hook: String, reason: String },
+    /// A TOML or JSON config doesn't describe a machine, the message contains the location
+    Parse { path: String, reason: String },
+    /// The converted config couldn't be written
+    Write { path: String, source: std::io::Error },
+    /// The config uses something the target format can't express
+    Convert(String),
+}
 
-    // Run the Config
-    module.exec().expect("Error in lua code");
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { path, source } => write!(f, "Failed to read {path}: {source}"),
+            Self::Lua(err) => write!(f, "Error in lua code: {err}"),
+            Self::Global { name, expected, found } =>
+                write!(f, "Global `{name}` should be {expected}, found {found}"),
+            Self::Disabled => write!(f, "No usable configuration, `use_config` is false"),
+            Self::Field { region, field, expected, found } =>
+                write!(f, "Region `{region}`: field `{field}` should be {expected}, found {found}"),
+            Self::RegionType { region, found } =>
+                write!(f, "Region `{region}`: invalid type \"{found}\", expected \"file\", \"ram\", \"nvram\", \"flash\", \"func\", \"nvic\", \"scb\", \"uart\", \"gpio\", \"timer\", \"spi\", \"i2c\", \"dma\", \"watchdog\", \"rtc\" or \"alias\""),
+            Self::File { region, path, source } =>
+                write!(f, "Region `{region}`: failed to read {path}: {source}"),
+            Self::Invalid { region, reason } => write!(f, "Region `{region}`: {reason}"),
+            Self::Map(err) => write!(f, "{err}"),
+            Self::Hook { hook, reason } => write!(f, "Hook `{hook}` {reason}"),
+            Self::Parse { path, reason } => write!(f, "Failed to parse {path}: {reason}"),
+            Self::Write { path, source } => write!(f, "Failed to write {path}: {source}"),
+            Self::Convert(reason) => write!(f, "Can't convert the config: {reason}"),
+        }
+    }
+}
 
-    // Examine Results
-    let use_config: bool = lua.globals().get("use_config").expect("No Config");
-    let address_specs: mlua::Table = lua.globals().get("addresses")
-        .expect("No Memory Map");
-    assert_eq!(use_config, true, "No Usable Configuration");
+impl std::error::Error for ConfigError {}
 
-    // Parsing memory
-    let mut addresses = AddressDeMultiplexer::full();
-    address_specs.for_each(|_: String, props: mlua::Table| -> mlua::Result<()> {
-        // _ represents a label
-        let origin: u32 = props.get("origin").expect("Expected Origin");
-        let rtype: String = props.get("type").expect("Expected Type");
-
-        let region: Box<dyn AddressSpace> = match rtype.as_str() {
-            "file" => {
-                let filepath: String = props.get("path")
-                    .expect("Need filepath for type = file");
-                let binary = read_file_buffer(&filepath)
-                    .expect("Invalid Filepath");
-
-                Box::new(BufferMemory {
-                    origin,
-                    buffer: binary
-                })
+impl From<mlua::Error> for ConfigError {
+    fn from(err: mlua::Error) -> Self {
+        Self::Lua(err)
+    }
+}
+
+fn global<T: mlua::FromLua>(lua: &mlua::Lua, name: &'static str, expected: &'static str) -> Result<T, ConfigError> {
+    let value: mlua::Value = lua.globals().get(name)?;
+    let found = value.type_name();
+    lua.unpack(value).map_err(|_| ConfigError::Global { name, expected, found })
+}
+
+/// Typed access to the fields of a region table
+struct Region<'a> {
+    lua: &'a mlua::Lua,
+    label: String,
+    props: mlua::Table,
+}
+impl Region<'_> {
+    fn get<T: mlua::FromLua>(&self, field: &'static str, expected: &'static str) -> Result<T, ConfigError> {
+        let value: mlua::Value = self.props.get(field)?;
+        let found = value.type_name();
+        self.lua.unpack(value).map_err(|_| ConfigError::Field {
+            region: self.label.clone(), field, expected, found
+        })
+    }
+    fn get_optional<T: mlua::FromLua>(&self, field: &'static str, expected: &'static str) -> Result<Option<T>, ConfigError> {
+        match self.props.get::<mlua::Value>(field)? {
+            mlua::Value::Nil => Ok(None),
+            _ => self.get(field, expected).map(Some),
+        }
+    }
+    fn perm(&self) -> Result<Option<Permissions>, ConfigError> {
+        match self.get_optional::<String>("perm", "a string like \"rx\"")? {
+            Some(text) => Permissions::parse(&text).map(Some).ok_or(ConfigError::Field {
+                region: self.label.clone(), field: "perm", expected: "a combination of r, w and x", found: "string"
+            }),
+            None => Ok(None),
+        }
+    }
+    fn stimulus(&self) -> Result<Option<Stimulus>, ConfigError> {
+        const EXPECTED: &str = "a file path or a table of {cycle, pin, level} events";
+        Ok(match self.get_optional::<mlua::Value>("stimulus", EXPECTED)? {
+            Some(mlua::Value::Table(_)) => {
+                let events: Vec<Vec<u64>> = self.get("stimulus", EXPECTED)?;
+                let events = events.into_iter().map(|event| match event[..] {
+                    [cycle, pin, level] if pin <= u32::MAX as u64 && level <= 1 => Ok((cycle, pin as u32, level as u8)),
+                    _ => Err(ConfigError::Field { region: self.label.clone(), field: "stimulus", expected: EXPECTED, found: "table" }),
+                }).collect::<Result<_, _>>()?;
+                Some(Stimulus::Events(events))
             },
-            "ram" => {
-                let len: u32 = props.get("len").expect("Expected Length");
-                let buffer = (0..len).map(|_| 0).collect::<Box<[u8]>>();
-
-                Box::new(BufferMemory {
-                    origin,
-                    buffer,
-                })
+            Some(_) => Some(Stimulus::File(self.get("stimulus", EXPECTED)?)),
+            None => None,
+        })
+    }
+    /// Devices on a bus, each a table with a `type` like a region, `describe` knows the types in
+    /// `expected`
+    fn devices<T>(&self, expected: &'static str, describe: impl Fn(&Region, &str) -> Result<Option<T>, ConfigError>) -> Result<Vec<T>, ConfigError> {
+        let Some(devices) = self.get_optional::<mlua::Table>("devices", "a table of devices")? else {
+            return Ok(Vec::new());
+        };
+        devices.sequence_values::<mlua::Value>().enumerate().map(|(idx, props)| {
+            let label = format!("{}.devices[{}]", self.label, idx + 1);
+            let props = props?;
+            let found = props.type_name();
+            let mlua::Value::Table(props) = props else {
+                return Err(ConfigError::Field { region: label, field: "(device)", expected: "a table", found });
+            };
+            let device = Region { lua: self.lua, label, props };
+            let dtype: String = device.get("type", "a string")?;
+            describe(&device, &dtype)?.ok_or(ConfigError::Field {
+                region: device.label, field: "type", expected, found: "string"
+            })
+        }).collect()
+    }
+    fn spi_device(device: &Region, dtype: &str) -> Result<Option<SpiDeviceSpec>, ConfigError> {
+        Ok(Some(match dtype {
+            "nor" => SpiDeviceSpec::Nor {
+                len: device.get("len", "an unsigned 32 bit integer")?,
+                path: device.get_optional("path", "a file path")?,
             },
-            "func" => {
-                let length: u32 = props.get("len").expect("Expected Length");
-                let readb_fl: mlua::Function = props.get("readb")
-                    .expect("Expected readb function");
-                let writeb_fl: mlua::Function = props.get("writeb")
-                    .expect("Expected writeb function");
-
-                let readb_f = Box::new(move |adr: AWord| -> AByte {
-                    readb_fl.call(adr).expect("Invalid Function Return")
-                });
-                let writeb_f = Box::new(move |adr: AWord, x: AByte| {
-                    writeb_fl.call((adr, x)).expect("Invalid Function Return")
-                });
+            "loopback" => SpiDeviceSpec::Loopback,
+            _ => return Ok(None),
+        }))
+    }
+    fn i2c_device(device: &Region, dtype: &str) -> Result<Option<I2cDeviceSpec>, ConfigError> {
+        let address = || device.get::<u8>("address", "a 7 bit address");
+        Ok(Some(match dtype {
+            "eeprom" => I2cDeviceSpec::Eeprom {
+                address: address()?,
+                len: device.get("len", "an unsigned 32 bit integer")?,
+                page_size: device.get_optional("page_size", "an unsigned 32 bit integer")?,
+                path: device.get_optional("path", "a file path")?,
+            },
+            "sensor" => I2cDeviceSpec::Sensor {
+                address: address()?,
+                registers: device.get_optional("registers", "a table of bytes")?,
+                script: device.get_optional("script", "a file path")?,
+                update: device.get_optional("update", "a function(cycles) -> registers")?,
+            },
+            _ => return Ok(None),
+        }))
+    }
 
-                Box::new(FunctionalAddressSpace {
-                    origin,
-                    length,
-                    readb_f,
-                    writeb_f
-                })
+    /// The region as a TOML or JSON config would have it, functions of func regions are kept
+    /// as they are
+    fn describe(&self) -> Result<RegionSpec, ConfigError> {
+        let origin: u32 = self.get("origin", "an unsigned 32 bit integer")?;
+        let rtype: String = self.get("type", "a string")?;
+        let priority = self.get_optional("priority", "an integer")?;
+        let perm = self.perm()?;
+        let len = || self.get::<u32>("len", "an unsigned 32 bit integer");
+
+        let kind = match rtype.as_str() {
+            "file" => RegionKind::File { path: self.get("path", "a file path")? },
+            "ram" => RegionKind::Ram { len: len()? },
+            "nvram" => RegionKind::Nvram {
+                len: len()?,
+                path: self.get("path", "a file path")?,
+                fill: self.get_optional("fill", "a byte")?,
+            },
+            "flash" => RegionKind::Flash {
+                len: len()?,
+                page_size: self.get_optional("page_size", "an unsigned 32 bit integer")?,
+                path: self.get_optional("path", "a file path")?,
+                persist: self.get_optional("persist", "a boolean")?,
+                controller: self.get_optional("controller", "an unsigned 32 bit integer")?,
+            },
+            "func" => {
+                let script: Option<String> = self.get_optional("script", "a file path")?;
+                // Without a script the functions are fields of the region
+                let functions = script.is_none().then(|| self.props.clone());
+                RegionKind::Func { len: len()?, script, functions }
+            },
+            "nvic" => RegionKind::Nvic,
+            "uart" => RegionKind::Uart {
+                backend: self.get_optional("backend", "a string like \"stdout\" or \"tcp:4000\"")?,
+                irq: self.get_optional("irq", "an IRQ number")?,
+                fifo: self.get_optional("fifo", "an unsigned 32 bit integer")?,
+                dma_rx: self.get_optional("dma_rx", "a DMA request line")?,
+                dma_tx: self.get_optional("dma_tx", "a DMA request line")?,
+            },
+            "gpio" => RegionKind::Gpio {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                stimulus: self.stimulus()?,
+                log: self.get_optional("log", "a file path")?,
             },
-            _ => panic!("Invalid Region Type")
+            "timer" => RegionKind::Timer {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                channels: self.get_optional("channels", "a channel count")?,
+                gpio: self.get_optional("gpio", "a region label")?,
+                pins: self.get_optional("pins", "a table of pin numbers")?,
+            },
+            "spi" => RegionKind::Spi {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                fifo: self.get_optional("fifo", "an unsigned 32 bit integer")?,
+                dma_rx: self.get_optional("dma_rx", "a DMA request line")?,
+                dma_tx: self.get_optional("dma_tx", "a DMA request line")?,
+                devices: self.devices("\"nor\" or \"loopback\"", Self::spi_device)?,
+            },
+            "i2c" => RegionKind::I2c {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                devices: self.devices("\"eeprom\" or \"sensor\"", Self::i2c_device)?,
+            },
+            "dma" => RegionKind::Dma {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                channels: self.get_optional("channels", "a channel count")?,
+            },
+            "watchdog" => RegionKind::Watchdog {},
+            "rtc" => RegionKind::Rtc {
+                irq: self.get_optional("irq", "an IRQ number")?,
+                epoch: self.get_optional("epoch", "seconds since 1970")?,
+            },
+            "scb" => RegionKind::Scb { cpuid: self.get_optional("cpuid", "an unsigned 32 bit integer")? },
+            "alias" => RegionKind::Alias {
+                target: self.get("target", "a region label")?,
+                offset: self.get_optional("offset", "an unsigned 32 bit integer")?,
+                mask: self.get_optional("mask", "an unsigned 32 bit integer")?,
+                len: self.get_optional("len", "an unsigned 32 bit integer")?,
+            },
+            _ => return Err(ConfigError::RegionType { region: self.label.clone(), found: rtype }),
         };
-        addresses.add_region(region);
+        Ok(RegionSpec { origin, priority, perm, kind })
+    }
+}
+
+/// Everything a config describes
+///
+/// The Lua state lives as long as the func regions and hooks calling into it, dropping the
+/// machine, or the emulator built from it, frees it.
+pub struct Machine {
+    pub memory: AddressDeMultiplexer<'static>,
+    pub hooks: Hooks,
+    /// Core clock in Hz, if the config gives one
+    pub frequency: Option<u64>,
+}
+
+/// Load a Lua, TOML or JSON config, picked by the extension of `path`
+pub fn load(path: &str, sandbox: &Sandbox) -> Result<Machine, ConfigError> {
+    let script = Script::new(sandbox)?;
+    // Peripherals can look at the core and interrupt it through `context`
+    let mut addresses = AddressDeMultiplexer::full();
+    let context = addresses.context();
+    let (description, hooks) = read(path, &script, &context)?;
+
+    if description.frequency == Some(0) {
+        return Err(ConfigError::Global { name: "frequency", expected: "a frequency in Hz", found: "0" });
+    }
+
+    // Parsing memory
+    addresses.set_endian(description.endianness);
+    // Aliases are added last, their targets have to exist by then
+    let (aliases, regions): (Vec<_>, Vec<_>) = description.addresses.iter()
+        .partition(|(_, spec)| matches!(spec.kind, RegionKind::Alias {..}));
+    let mut wiring = Wiring::default();
+    for (label, spec) in regions {
+        for mapped in load_region(label, spec, &description, &script, &context, &mut wiring)? {
+            addresses.add_region(mapped).map_err(ConfigError::Map)?;
+        }
+    }
+    for master in wiring.masters {
+        addresses.add_master(master);
+    }
+    for (label, spec) in aliases {
+        let mapped = load_alias(label, spec, &mut addresses)?;
+        addresses.add_region(mapped).map_err(ConfigError::Map)?;
+    }
+
+    // Scripting
+    let hooks = match hooks {
+        Some(table) => Hooks::load(&script, table)?,
+        None => Hooks::default(),
+    };
+
+    Ok(Machine { memory: addresses, hooks, frequency: description.frequency })
+}
+
+/// Write the config at `from` in the format of `to`, eg `config.lua` as `machine.toml`
+pub fn convert(from: &str, to: &str, sandbox: &Sandbox) -> Result<(), ConfigError> {
+    let script = Script::new(sandbox)?;
+    let (description, hooks) = read(from, &script, &SharedContext::default())?;
+    if hooks.is_some() {
+        return Err(ConfigError::Convert("`hooks` can only be written in Lua".to_string()));
+    }
+    let inline = description.addresses.iter()
+        .find(|(_, spec)| spec.kind.has_inline_functions());
+    if let Some((label, _)) = inline {
+        return Err(ConfigError::Convert(format!(
+            "region `{label}` has inline functions, move them to a file given as `script`"
+        )));
+    }
+    let text = description.to_text(Format::of(to)).map_err(ConfigError::Convert)?;
+    std::fs::write(to, text).map_err(|source| ConfigError::Write { path: to.to_string(), source })
+}
+
+/// Run a Lua config, which may also have hooks, or parse a declarative one
+fn read(path: &str, script: &Script, context: &SharedContext) -> Result<(Description, Option<mlua::Table>), ConfigError> {
+    // Load the Config
+    let config_file = std::fs::read_to_string(path)
+        .map_err(|source| ConfigError::Read { path: path.to_string(), source })?;
+    let format = Format::of(path);
+    if format != Format::Lua {
+        let description = Description::parse(&config_file, format)
+            .map_err(|reason| ConfigError::Parse { path: path.to_string(), reason })?;
+        return Ok((description, None));
+    }
+    let lua = &script.lua;
+    // Named so errors point at `config.lua:<line>`
+    let module = lua.load(config_file.as_str()).set_name(format!("@{path}"));
+    lua.globals().set("context", context_table(lua, context)?)?;
+
+    // Run the Config
+    script.eval::<()>(module)?;
+
+    // Examine Results
+    let use_config: bool = global(lua, "use_config", "a boolean")?;
+    let address_specs: mlua::Table = global(lua, "addresses", "a table of regions")?;
+    if !use_config {
+        return Err(ConfigError::Disabled);
+    }
+
+    // Optional, a chip whose regions `addresses` changes and adds to
+    let chip: Option<String> = global(lua, "chip", "a chip name")?;
+    let mut description = match chip {
+        Some(chip) => chips::preset(&chip).ok_or(ConfigError::Global { name: "chip", expected: CHIP_NAMES, found: "string" })?,
+        None => Description::default(),
+    };
+    // Optional, data accesses of the core are little endian by default
+    let endian: Option<String> = global(lua, "endianness", "\"little\" or \"big\"")?;
+    if let Some(endian) = endian {
+        description.endianness = Endian::parse(&endian).ok_or(ConfigError::Global {
+            name: "endianness", expected: "\"little\" or \"big\"", found: "string"
+        })?;
+    }
+    // Optional, only needed by real time runs and RTCs
+    let frequency: Option<u64> = global(lua, "frequency", "a frequency in Hz")?;
+    if frequency.is_some() {
+        description.frequency = frequency;
+    }
+    for pair in address_specs.pairs::<mlua::Value, mlua::Value>() {
+        let (key, props) = pair?;
+        // Regions without a key are labeled by their position
+        let label = match key {
+            mlua::Value::Integer(idx) => format!("[{idx}]"),
+            key => key.to_string()?,
+        };
+        // `false` removes a region of the chip
+        if props == mlua::Value::Boolean(false) {
+            description.addresses.remove(&label);
+            continue;
+        }
+        let found = props.type_name();
+        let mlua::Value::Table(props) = props else {
+            return Err(ConfigError::Field { region: label, field: "(region)", expected: "a table", found });
+        };
+        // Fields left out of a region of the chip keep their preset values
+        if let Some(base) = description.addresses.get(&label) {
+            let serde_json::Value::Object(base) = serde_json::to_value(base).expect("Regions serialize") else {
+                unreachable!("Regions serialize to maps");
+            };
+            let rtype: Option<String> = props.get("type").ok().flatten();
+            if chips::overrides(&base, rtype.as_deref()) {
+                for (field, value) in base {
+                    if props.get::<mlua::Value>(field.as_str())?.is_nil() {
+                        props.set(field, lua_value(lua, value)?)?;
+                    }
+                }
+            }
+        }
+        let region = Region { lua, label, props };
+        let spec = region.describe()?;
+        description.addresses.insert(region.label, spec);
+    }
+
+    let hooks = global::<Option<mlua::Table>>(lua, "hooks", "a table of functions")?;
+    Ok((description, hooks))
+}
+
+/// The fields of a region, which are strings, integers, booleans or arrays of them
+fn lua_value(lua: &mlua::Lua, value: serde_json::Value) -> mlua::Result<mlua::Value> {
+    Ok(match value {
+        serde_json::Value::Array(items) => mlua::Value::Table(lua.create_sequence_from(
+            items.into_iter().map(|item| lua_value(lua, item)).collect::<mlua::Result<Vec<_>>>()?
+        )?),
+        serde_json::Value::String(text) => mlua::Value::String(lua.create_string(text)?),
+        serde_json::Value::Number(n) => mlua::Value::Integer(n.as_i64().expect("Region fields are integers")),
+        serde_json::Value::Bool(set) => mlua::Value::Boolean(set),
+        _ => mlua::Value::Nil,
+    })
+}
+
+/// `context.pc()`, `context.cycles()` and `context.irq(n)`
+fn context_table(lua: &mlua::Lua, context: &SharedContext) -> Result<mlua::Table, mlua::Error> {
+    let table = lua.create_table()?;
+    let pc_context = context.clone();
+    table.set("pc", lua.create_function(move |_, ()| Ok(pc_context.borrow().pc))?)?;
+    let cycles_context = context.clone();
+    table.set("cycles", lua.create_function(move |_, ()| Ok(cycles_context.borrow().cycles))?)?;
+    let irq_context = context.clone();
+    table.set("irq", lua.create_function(move |_, irq: u32| {
+        if IRQ_COUNT <= irq {
+            return Err(mlua::Error::runtime(format!("IRQ {irq} doesn't exist, there are {IRQ_COUNT}")));
+        }
+        irq_context.borrow_mut().nvic.raise(irq);
         Ok(())
-    }).expect("Invalid Config Format");
+    })?)?;
+    Ok(table)
+}
+
+/// A function of a func region
+///
+/// The bus has no way to fail an access, so an error is left in the context for the emulator to
+/// stop on and the access goes on with a default value.
+struct Callback {
+    script: Script,
+    context: SharedContext,
+    label: String,
+    name: &'static str,
+    function: mlua::Function,
+}
+impl Callback {
+    fn call<R: mlua::FromLuaMulti + Default>(&self, args: impl mlua::IntoLuaMulti) -> R {
+        self.try_call(args).unwrap_or_else(|reason| {
+            // Only the first error is kept, the rest usually follow from it
+            self.context.borrow_mut().error.get_or_insert(reason);
+            R::default()
+        })
+    }
+    fn try_call<R: mlua::FromLuaMulti>(&self, args: impl mlua::IntoLuaMulti) -> Result<R, String> {
+        self.script.call(&self.function, args)
+            .map_err(|err| format!("Region `{}`: `{}` failed: {err}", self.label, self.name))
+    }
+}
+
+fn reader<T: mlua::FromLua + Default + 'static>(callback: Callback) -> ReadFn<T> {
+    Box::new(move |adr: AWord| callback.call(adr))
+}
+fn writer<T: mlua::IntoLua + 'static>(callback: Callback) -> WriteFn<T> {
+    Box::new(move |adr: AWord, x: T| callback.call((adr, x)))
+}
+
+/// Connections between peripherals, made while loading them in any order
+#[derive(Default)]
+struct Wiring {
+    /// Of GPIO regions by label
+    pins: HashMap<String, SharedPins>,
+    /// Bus masters besides the core, eg DMA controllers
+    masters: Vec<Box<dyn BusMaster>>,
+}
+impl Wiring {
+    fn pins(&mut self, gpio: &str) -> SharedPins {
+        self.pins.entry(gpio.to_string()).or_default().clone()
+    }
+}
+
+/// Most types map a single region, some bring their own control registers
+fn load_region(label: &str, spec: &RegionSpec, machine: &Description, script: &Script, context: &SharedContext, wiring: &mut Wiring) -> Result<Vec<MappedRegion<'static>>, ConfigError> {
+    let (origin, endian) = (spec.origin, machine.endianness);
+    let priority = spec.priority.unwrap_or(0);
+    let perm = spec.perm.unwrap_or(Permissions::ALL);
+    let invalid = |reason: String| ConfigError::Invalid { region: label.to_string(), reason };
+    let file_error = |path: &str, source| ConfigError::File { region: label.to_string(), path: path.to_string(), source };
+    let dma_lines = |rx: &Option<u32>, tx: &Option<u32>| match rx.iter().chain(tx).find(|&&line| 32 <= line) {
+        Some(line) => Err(invalid(format!("DMA request {line} doesn't exist, there are 32"))),
+        None => Ok(DmaLines {rx: *rx, tx: *tx}),
+    };
+    let check_irq = |irq: &Option<u32>| match *irq {
+        Some(irq) if IRQ_COUNT <= irq => Err(invalid(format!("IRQ {irq} doesn't exist, there are {IRQ_COUNT}"))),
+        irq => Ok(irq),
+    };
+
+    let (kind, space): (&'static str, Box<dyn AddressSpace>) = match &spec.kind {
+        RegionKind::File { path } => {
+            let binary = read_file_buffer(path).map_err(|source| file_error(path, source))?;
+
+            ("file", Box::new(BufferMemory {
+                origin,
+                buffer: binary
+            }))
+        },
+        &RegionKind::Ram { len } => {
+            let buffer = (0..len).map(|_| 0).collect::<Box<[u8]>>();
+
+            ("ram", Box::new(BufferMemory {
+                origin,
+                buffer,
+            }))
+        },
+        RegionKind::Nvram { len, path, fill } => {
+            let memory = PersistentMemory::open(origin, *len, path.clone(), fill.unwrap_or(0xff))
+                .map_err(|source| file_error(path, source))?;
+
+            ("nvram", Box::new(memory))
+        },
+        RegionKind::Flash { len, page_size, path, persist, controller } => {
+            let persist = persist.unwrap_or(false);
+            let image = match path {
+                Some(path) => read_file_buffer(path).map_err(|source| file_error(path, source))?,
+                None => Box::new([]),
+            };
+            if persist && path.is_none() {
+                return Err(invalid("`persist` needs a `path` to write to".to_string()));
+            }
+            let flash = Flash::new(origin, &image, *len, page_size.unwrap_or(1024), path.clone().filter(|_| persist)).map_err(invalid)?;
+            let flash = std::rc::Rc::new(std::cell::RefCell::new(flash));
+
+            let mut regions = Vec::new();
+            if let Some(controller) = *controller {
+                regions.push(MappedRegion {
+                    label: format!("{label}.controller"),
+                    kind: "flashc",
+                    priority,
+                    perm: Permissions::parse("rw").unwrap(),
+                    space: Box::new(FlashController::new(controller, flash.clone())),
+                });
+            }
+            regions.push(MappedRegion {
+                label: label.to_string(),
+                kind: "flash",
+                priority,
+                perm,
+                space: Box::new(FlashArray {flash}),
+            });
+            return Ok(regions);
+        },
+        RegionKind::Nvic => ("nvic", Box::new(NvicRegisters {origin, context: context.clone()})),
+        RegionKind::Uart { backend, irq, fifo, dma_rx, dma_tx } => {
+            let irq = check_irq(irq)?;
+            let dma = dma_lines(dma_rx, dma_tx)?;
+            let host = Host::open(backend.as_deref().unwrap_or("stdout")).map_err(invalid)?;
+            ("uart", Box::new(Uart::new(origin, context.clone(), irq, dma, fifo.unwrap_or(16) as usize, host)))
+        },
+        RegionKind::Gpio { irq, stimulus, log } => {
+            let irq = check_irq(irq)?;
+            let events = match stimulus {
+                Some(Stimulus::File(path)) => {
+                    let text = std::fs::read_to_string(path).map_err(|source| file_error(path, source.into()))?;
+                    gpio::parse_events(&text).map_err(|reason| invalid(format!("{path}: {reason}")))?
+                },
+                Some(Stimulus::Events(events)) => events.iter()
+                    .map(|&(cycle, pin, level)| Event {cycle, pin, high: level != 0})
+                    .collect(),
+                None => Vec::new(),
+            };
+            let log: Option<Box<dyn std::io::Write>> = match log {
+                Some(path) => Some(Box::new(std::io::BufWriter::new(
+                    std::fs::File::create(path).map_err(|source| file_error(path, source.into()))?
+                ))),
+                None => None,
+            };
+            let gpio = Gpio::new(origin, label.to_string(), context.clone(), irq, events, log, wiring.pins(label)).map_err(invalid)?;
+            ("gpio", Box::new(gpio))
+        },
+        RegionKind::Timer { irq, channels, gpio, pins } => {
+            let irq = check_irq(irq)?;
+            let pins_of = match gpio {
+                Some(gpio) if matches!(machine.addresses.get(gpio).map(|spec| &spec.kind), Some(RegionKind::Gpio {..})) =>
+                    Some(wiring.pins(gpio)),
+                Some(gpio) => return Err(invalid(format!("No GPIO region `{gpio}`"))),
+                None => None,
+            };
+            let channels = channels.map_or(MAX_CHANNELS, |channels| channels as usize);
+            let timer = Timer::new(origin, context.clone(), irq, channels, pins_of, pins.clone().unwrap_or_default()).map_err(invalid)?;
+            ("timer", Box::new(timer))
+        },
+        RegionKind::Spi { irq, fifo, dma_rx, dma_tx, devices } => {
+            let irq = check_irq(irq)?;
+            let devices = devices.iter().map(|device| Ok(match device {
+                SpiDeviceSpec::Nor { len, path } => Box::new(NorFlash::new(*len, path.clone())?) as Box<dyn SpiDevice>,
+                SpiDeviceSpec::Loopback => Box::new(Loopback),
+            })).collect::<Result<_, String>>().map_err(invalid)?;
+            let dma = dma_lines(dma_rx, dma_tx)?;
+            let spi = Spi::new(origin, context.clone(), irq, dma, fifo.unwrap_or(8) as usize, devices).map_err(invalid)?;
+            ("spi", Box::new(spi))
+        },
+        RegionKind::I2c { irq, devices } => {
+            let irq = check_irq(irq)?;
+            let mut connected: Vec<Box<dyn I2cDevice>> = Vec::new();
+            for device in devices {
+                connected.push(match device {
+                    I2cDeviceSpec::Eeprom { address, len, page_size, path } =>
+                        Box::new(Eeprom::new(*address, *len, *page_size, path.clone()).map_err(invalid)?),
+                    I2cDeviceSpec::Sensor { address, registers, script: path, update } => {
+                        let function = match (update, path) {
+                            (Some(update), _) => Some(update.clone()),
+                            (None, Some(path)) => {
+                                let source = std::fs::read_to_string(path).map_err(|source| file_error(path, source.into()))?;
+                                Some(script.eval(script.lua.load(source).set_name(format!("@{path}")))?)
+                            },
+                            (None, None) => None,
+                        };
+                        // Returns a table of register values to change
+                        let update = function.map(|function| {
+                            let callback = Callback {
+                                script: script.clone(), context: context.clone(), label: label.to_string(), name: "update", function
+                            };
+                            Box::new(move |cycles: u64| {
+                                callback.call::<Option<HashMap<u8, u8>>>(cycles).unwrap_or_default().into_iter().collect()
+                            }) as UpdateFn
+                        });
+                        let initial = registers.as_deref().unwrap_or_default();
+                        Box::new(Sensor::new(*address, context.clone(), initial, update).map_err(invalid)?)
+                    },
+                });
+            }
+            ("i2c", Box::new(I2c::new(origin, context.clone(), irq, connected)))
+        },
+        RegionKind::Dma { irq, channels } => {
+            let irq = check_irq(irq)?;
+            let channels = channels.map_or(dma::MAX_CHANNELS, |channels| channels as usize);
+            let dma = Dma::new(origin, context.clone(), irq, channels).map_err(invalid)?;
+            wiring.masters.push(Box::new(dma.master()));
+            ("dma", Box::new(dma))
+        },
+        RegionKind::Watchdog {} => ("watchdog", Box::new(Watchdog::new(origin, context.clone()))),
+        RegionKind::Rtc { irq, epoch } => {
+            let irq = check_irq(irq)?;
+            let Some(frequency) = machine.frequency else {
+                return Err(invalid("An RTC needs the core `frequency`".to_string()));
+            };
+            let epoch = epoch.unwrap_or_else(|| {
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |now| now.as_secs() as AWord)
+            });
+            ("rtc", Box::new(Rtc::new(origin, context.clone(), irq, frequency, epoch)))
+        },
+        RegionKind::Scb { cpuid } => ("scb", Box::new(ScbRegisters {origin, cpuid: cpuid.unwrap_or(CPUID_M0), endian})),
+        RegionKind::Func { len, script: path, functions } => {
+            let functions = match (functions, path) {
+                (Some(functions), _) => functions.clone(),
+                (None, Some(path)) => {
+                    let source = std::fs::read_to_string(path).map_err(|source| file_error(path, source.into()))?;
+                    script.eval(script.lua.load(source).set_name(format!("@{path}")))?
+                },
+                (None, None) => return Err(invalid("`script` should be a Lua file returning the functions".to_string())),
+            };
+            let region = Region { lua: &script.lua, label: label.to_string(), props: functions };
+            let callback = |name: &'static str, function: mlua::Function| Callback {
+                script: script.clone(), context: context.clone(), label: label.to_string(), name, function
+            };
+            let optional = |name: &'static str, expected: &'static str| -> Result<Option<Callback>, ConfigError> {
+                Ok(region.get_optional(name, expected)?.map(|function| callback(name, function)))
+            };
+            let readb_fl = callback("readb", region.get("readb", "a function(adr) -> byte")?);
+            let writeb_fl = callback("writeb", region.get("writeb", "a function(adr, byte)")?);
+            // Optional whole halfword and word accesses
+            let readh_fl = optional("readh", "a function(adr) -> halfword")?;
+            let writeh_fl = optional("writeh", "a function(adr, halfword)")?;
+            let readw_fl = optional("readw", "a function(adr) -> word")?;
+            let writew_fl = optional("writew", "a function(adr, word)")?;
+            let tick_fl = optional("tick", "a function(cycles)")?;
+            let tick_f = tick_fl.map(|tick_fl| Box::new(move |cycles: u64| tick_fl.call(cycles)) as TickFn);
+
+            // Optional snapshot support, `save` returns a string that is later handed to
+            // `restore`
+            let save_fl = optional("save", "a function() -> string")?;
+            let restore_fl = optional("restore", "a function(string)")?;
+            let save_f = save_fl.map(|save_fl| Box::new(move || -> Vec<u8> {
+                save_fl.call::<mlua::BString>(()).into()
+            }) as SaveFn);
+            let restore_f = restore_fl.map(|restore_fl| Box::new(move |state: &[u8]| {
+                restore_fl.try_call(mlua::BString::from(state)).map_err(Into::into)
+            }) as RestoreFn);
+
+            ("func", Box::new(FunctionalAddressSpace {
+                origin,
+                length: *len,
+                readb_f: reader(readb_fl),
+                writeb_f: writer(writeb_fl),
+                readh_f: readh_fl.map(reader),
+                writeh_f: writeh_fl.map(writer),
+                readw_f: readw_fl.map(reader),
+                writew_f: writew_fl.map(writer),
+                tick_f,
+                save_f,
+                restore_f,
+            }))
+        },
+        RegionKind::Alias {..} => unreachable!("Aliases are mapped by `load_alias`"),
+    };
+    Ok(vec![MappedRegion { label: label.to_string(), kind, priority, perm, space }])
+}
+
+/// Another window onto the storage of the region labeled `target`
+fn load_alias(label: &str, spec: &RegionSpec, addresses: &mut AddressDeMultiplexer<'static>) -> Result<MappedRegion<'static>, ConfigError> {
+    let RegionKind::Alias { target, offset, mask, len } = &spec.kind else {
+        unreachable!("Only aliases are mapped by `load_alias`");
+    };
+    let offset = offset.unwrap_or(0);
+    let invalid = |reason: String| ConfigError::Invalid { region: label.to_string(), reason };
+
+    let target_region = addresses.regions().find(|other| &other.label == target)
+        .ok_or_else(|| invalid(format!("No region `{target}` to alias")))?;
+    if target_region.kind == "alias" {
+        return Err(invalid(format!("`{target}` is an alias itself")));
+    }
+    let len = len.unwrap_or(target_region.space.len().saturating_sub(offset));
+    let shared = addresses.share(target).expect("Target region exists");
+    let alias = Alias::new(spec.origin, len, offset, mask.unwrap_or(u32::MAX), shared).map_err(invalid)?;
+
+    Ok(MappedRegion {
+        label: label.to_string(),
+        kind: "alias",
+        priority: spec.priority.unwrap_or(0),
+        perm: spec.perm.unwrap_or(Permissions::ALL),
+        space: Box::new(alias),
+    })
+}
+
+#[test]
+fn test_config_errors() {
+    let check = |name: &str, lua_code: &str| -> String {
+        let path = std::env::temp_dir().join(format!("cm0-config-test-{name}.lua"));
+        std::fs::write(&path, lua_code).unwrap();
+        let result = load(path.to_str().unwrap(), &Sandbox::default());
+        std::fs::remove_file(&path).unwrap();
+        result.err().map(|err| err.to_string()).unwrap_or_default()
+    };
+
+    let missing_len = check("missing", "use_config = true addresses = { mem = { origin = 0, type = \"ram\" } }");
+    assert_eq!(missing_len, "Region `mem`: field `len` should be an unsigned 32 bit integer, found nil");
+    let bad_type = check("type", "use_config = true addresses = { { origin = 0, type = \"rom\" } }");
+    assert!(bad_type.starts_with("Region `[1]`: invalid type \"rom\""));
+    let syntax = check("syntax", "use_config = true\naddresses = {\n");
+    assert!(syntax.contains(".lua:3:"), "{syntax}");
+    let overlap = check("overlap", "use_config = true addresses = { { origin = 0, type = \"ram\", len = 8 }, \
+        { origin = 4, type = \"ram\", len = 8 } }");
+    assert!(overlap.contains("overlaps"), "{overlap}");
+    let alias = check("alias", "use_config = true addresses = { mirror = { origin = 0, type = \"alias\", target = \"sram\" } }");
+    assert_eq!(alias, "Region `mirror`: No region `sram` to alias");
+    let endian = check("endian", "use_config = true endianness = \"middle\" addresses = {}");
+    assert_eq!(endian, "Global `endianness` should be \"little\" or \"big\", found string");
+    assert_eq!(check("empty", "use_config = true addresses = {}"), "");
+}
+
+#[test]
+fn test_lua_peripheral() {
+    let path = std::env::temp_dir().join("cm0-config-test-peripheral.lua");
+    std::fs::write(&path, "use_config = true
+        local ticks = 0
+        addresses = { timer = {
+            origin = 0x100, type = \"func\", len = 4,
+            readb = function(adr) return 0xff end,
+            writeb = function(adr, x) end,
+            readw = function(adr) return ticks end,
+            tick = function(cycles)
+                ticks = ticks + cycles
+                if ticks == 2 then context.irq(3) end
+            end,
+        } }").unwrap();
+    let mut memory = load(path.to_str().unwrap(), &Sandbox::default()).unwrap().memory;
+    std::fs::remove_file(&path).unwrap();
+
+    memory.tick(1);
+    assert_eq!(memory.read_w(0x100), 1);
+    assert_eq!(memory.read_hw(0x100), 0xffff);
+    memory.tick(1);
+    assert_eq!(memory.context().borrow().nvic.pending, 1 << 3);
+}
+
+#[test]
+fn test_i2c_sensor_script() {
+    let path = std::env::temp_dir().join("cm0-config-test-sensor.lua");
+    std::fs::write(&path, "use_config = true
+        addresses = { i2c = {
+            origin = 0x200, type = \"i2c\",
+            devices = { { type = \"sensor\", address = 0x48, update = function(cycles) return { [0] = cycles // 100 } end } },
+        } }").unwrap();
+    let mut memory = load(path.to_str().unwrap(), &Sandbox::default()).unwrap().memory;
+    std::fs::remove_file(&path).unwrap();
+
+    // Start, address the sensor for reading, then read a byte and stop
+    memory.context().borrow_mut().cycles = 500;
+    memory.write_w(0x200, 0x48 << 1 | 1);
+    memory.write_w(0x204, 0b11);
+    memory.tick(1);
+    memory.write_w(0x204, 0b11100);
+    memory.tick(1);
+    assert_eq!(memory.read_w(0x200), 5);
+}
+
+#[test]
+fn test_config_formats() {
+    let dir = std::env::temp_dir();
+    let path = |name: &str| dir.join(format!("cm0-formats-test-{name}")).to_str().unwrap().to_string();
+    std::fs::write(path("uart.lua"), "return { readb = function(adr) return 0x41 end, writeb = function(adr, x) end }").unwrap();
+    std::fs::write(path("config.lua"), format!("use_config = true
+        addresses = {{
+            sram = {{ origin = 0x20000000, type = \"ram\", len = 0x100, perm = \"rw\" }},
+            mirror = {{ origin = 0x30000000, type = \"alias\", target = \"sram\", len = 0x1000, mask = 0xff }},
+            uart = {{ origin = 0x40000000, type = \"func\", len = 4, script = {:?} }},
+        }}", path("uart.lua"))).unwrap();
+
+    for target in ["machine.toml", "machine.json", "converted.lua"] {
+        convert(&path("config.lua"), &path(target), &Sandbox::default()).unwrap();
+        let mut memory = load(&path(target), &Sandbox::default()).unwrap().memory;
+        memory.writeb(0x20000010, 7);
+        assert_eq!(memory.readb(0x30000110), 7, "{target}");
+        assert_eq!(memory.readb(0x40000000), 0x41, "{target}");
+        std::fs::remove_file(path(target)).unwrap();
+    }
 
-    // Return
-    std::mem::forget(lua);
-    Box::new(addresses)
+    std::fs::write(path("inline.lua"), "use_config = true addresses = { uart = { origin = 0, type = \"func\", len = 4, \
+        readb = function(adr) return 0 end, writeb = function(adr, x) end } }").unwrap();
+    let inline = convert(&path("inline.lua"), &path("inline.toml"), &Sandbox::default()).unwrap_err().to_string();
+    assert!(inline.contains("region `uart` has inline functions"), "{inline}");
+    std::fs::write(path("bad.toml"), "[addresses.sram]\ntype = \"ram\"\norigin = 0\n").unwrap();
+    let missing = load(&path("bad.toml"), &Sandbox::default()).err().unwrap().to_string();
+    assert!(missing.contains("missing field `len`"), "{missing}");
+    for name in ["uart.lua", "config.lua", "inline.lua", "bad.toml"] {
+        std::fs::remove_file(path(name)).unwrap();
+    }
 }
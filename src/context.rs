@@ -0,0 +1,45 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::core::AWord;
+use crate::exceptions::Nvic;
+
+/// The part of the core peripherals can observe and act on, shared through the bus
+#[derive(Debug, Default)]
+pub struct CoreContext {
+    /// Address of the instruction being executed
+    pub pc: AWord,
+    pub cycles: u64,
+    pub nvic: Nvic,
+    /// DMA request lines, set by peripherals on every tick while they want a transfer
+    pub requests: AWord,
+    /// Set by a peripheral that failed in a way the bus can't report, eg a Lua error
+    pub error: Option<String>,
+    /// Set by a peripheral to reset the system once the current step is done
+    pub reset: Option<ResetCause>,
+    /// Causes of the resets since they were last cleared, a bit per `ResetCause`
+    pub resets: AWord,
+}
+
+/// Why the system was reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetCause {
+    PowerOn = 1 << 0,
+    Watchdog = 1 << 1,
+}
+
+impl CoreContext {
+    pub fn request(&mut self, line: Option<u32>, active: bool) {
+        if let Some(line) = line {
+            self.requests = if active {self.requests | 1 << line} else {self.requests & !(1 << line)};
+        }
+    }
+}
+
+/// The DMA request lines of a peripheral, asserted while it has a byte to take or room for one
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DmaLines {
+    pub rx: Option<u32>,
+    pub tx: Option<u32>,
+}
+
+pub type SharedContext = Rc<RefCell<CoreContext>>;
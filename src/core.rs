@@ -2,6 +2,14 @@ pub type AByte = u8;
 pub type AHalfWord = u16;
 pub type AWord = u32;
 
+/// Decimal or `0x` prefixed hexadecimal
+pub fn parse_word(text: &str) -> Option<AWord> {
+    match text.strip_prefix("0x") {
+        Some(hex) => AWord::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
 pub fn bitidx<I>(x: I, ptr: usize, len: usize) -> I where 
 I : std::ops::Shl<usize, Output = I> +
 std::ops::Shr<usize, Output = I> + 
@@ -9,8 +17,7 @@ Copy {
     let bitcount = std::mem::size_of::<I>() * 8;
     let move_dist = bitcount - len - ptr;
     let topped = x << move_dist;
-    let bottomed = topped >> (ptr + move_dist);
-    bottomed
+    topped >> (ptr + move_dist)
 }
 
 #[test]
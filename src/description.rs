@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::adr::Endian;
+use crate::core::AWord;
+use crate::memory::Permissions;
+
+/// Fields written in hex when converting to Lua
+const HEX_FIELDS: [&str; 7] = ["origin", "len", "offset", "mask", "controller", "cpuid", "address"];
+
+/// The file formats a machine can be described in, picked by extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Lua,
+    Toml,
+    Json,
+}
+impl Format {
+    pub fn of(path: &str) -> Self {
+        match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("json") => Self::Json,
+            _ => Self::Lua,
+        }
+    }
+}
+
+/// A machine as written down, before any file is read or region mapped
+///
+/// Running a Lua config produces one, TOML and JSON configs are one:
+///
+/// ```toml
+/// endianness = "little"
+///
+/// [addresses.flash]
+/// type = "file"
+/// origin = 0
+/// path = "build/program"
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Description {
+    #[serde(default)]
+    pub endianness: Endian,
+    /// Core clock in Hz, for real time runs and RTCs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u64>,
+    /// Regions by label
+    #[serde(default)]
+    pub addresses: BTreeMap<String, RegionSpec>,
+}
+
+/// The part of a config looked at before the rest
+#[derive(Deserialize)]
+struct Chip {
+    chip: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionSpec {
+    pub origin: AWord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perm: Option<Permissions>,
+    #[serde(flatten)]
+    pub kind: RegionKind,
+}
+
+/// The `type` of a region and the fields that go with it, optional fields are left unset rather
+/// than defaulted so conversions keep configs as short as they were
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RegionKind {
+    File {
+        path: String,
+    },
+    Ram {
+        len: AWord,
+    },
+    Nvram {
+        len: AWord,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fill: Option<u8>,
+    },
+    Flash {
+        len: AWord,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page_size: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        persist: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        controller: Option<AWord>,
+    },
+    Func {
+        len: AWord,
+        /// Lua file returning the table of functions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        script: Option<String>,
+        /// Functions written inline in a Lua config, they can't be converted
+        #[serde(skip)]
+        functions: Option<mlua::Table>,
+    },
+    Nvic,
+    Scb {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cpuid: Option<AWord>,
+    },
+    Uart {
+        /// `stdout` when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backend: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fifo: Option<u32>,
+        /// DMA request lines for received bytes and room to send
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dma_rx: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dma_tx: Option<u32>,
+    },
+    Gpio {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stimulus: Option<Stimulus>,
+        /// File the outputs are written to, logged at info level when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<String>,
+    },
+    Timer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        /// 4 when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channels: Option<u32>,
+        /// Label of the GPIO region `pins` belong to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gpio: Option<String>,
+        /// Pin of each channel, in order
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pins: Option<Vec<u32>>,
+    },
+    Spi {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fifo: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dma_rx: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dma_tx: Option<u32>,
+        /// Device on each chip select line, in order
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        devices: Vec<SpiDeviceSpec>,
+    },
+    I2c {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        devices: Vec<I2cDeviceSpec>,
+    },
+    Dma {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        /// 8 when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channels: Option<u32>,
+    },
+    Watchdog {},
+    Rtc {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        irq: Option<u32>,
+        /// Seconds since 1970 at power on, the host clock when unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        epoch: Option<AWord>,
+    },
+    Alias {
+        target: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<AWord>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mask: Option<AWord>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<AWord>,
+    },
+}
+
+/// A device on the bus of an SPI region
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SpiDeviceSpec {
+    /// JEDEC NOR flash, erased on every start without a `path` to keep it in
+    Nor {
+        len: AWord,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    Loopback,
+}
+
+/// A device on the bus of an I2C region, at a 7 bit `address`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum I2cDeviceSpec {
+    /// 24Cxx EEPROM, erased on every start without a `path` to keep it in
+    Eeprom {
+        address: u8,
+        len: AWord,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page_size: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    Sensor {
+        address: u8,
+        /// Initial values from register 0 on, the rest are 0
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        registers: Option<Vec<u8>>,
+        /// Lua file returning the `update` function
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        script: Option<String>,
+        /// Written inline in a Lua config, it can't be converted
+        #[serde(skip)]
+        update: Option<mlua::Function>,
+    },
+}
+
+/// Input changes of a GPIO region, a file of `<cycle> <pin> <level>` lines or the events
+/// themselves, eg `{ {100, 3, 1}, {500, 3, 0} }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stimulus {
+    File(String),
+    Events(Vec<(u64, u32, u8)>),
+}
+
+impl RegionKind {
+    /// Whether Lua functions are written inline in a Lua config, which can't be converted
+    pub fn has_inline_functions(&self) -> bool {
+        match self {
+            Self::Func { functions, .. } => functions.is_some(),
+            Self::I2c { devices, .. } => devices.iter()
+                .any(|device| matches!(device, I2cDeviceSpec::Sensor { update: Some(_), .. })),
+            _ => false,
+        }
+    }
+}
+
+impl Description {
+    /// Only TOML and JSON, Lua configs have to be run
+    pub fn parse(text: &str, format: Format) -> Result<Self, String> {
+        // Without a chip the config is parsed directly, keeping the location in errors
+        let chip = match Self::decode::<Chip>(text, format)?.chip {
+            Some(chip) => chip,
+            None => return Self::decode(text, format),
+        };
+        let preset = crate::chips::preset(&chip)
+            .ok_or_else(|| format!("unknown chip \"{chip}\", expected {}", crate::chips::CHIP_NAMES))?;
+        crate::chips::overlay(&preset, Self::decode(text, format)?)
+    }
+    fn decode<T: serde::de::DeserializeOwned>(text: &str, format: Format) -> Result<T, String> {
+        match format {
+            Format::Toml => toml::from_str(text).map_err(|err| err.to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|err| err.to_string()),
+            Format::Lua => Err("Lua configs have to be run".to_string()),
+        }
+    }
+
+    pub fn to_text(&self, format: Format) -> Result<String, String> {
+        match format {
+            Format::Toml => toml::to_string_pretty(self).map_err(|err| err.to_string()),
+            Format::Json => serde_json::to_string_pretty(self).map_err(|err| err.to_string()),
+            Format::Lua => self.to_lua(),
+        }
+    }
+
+    /// A `config.lua` with one line per region
+    fn to_lua(&self) -> Result<String, String> {
+        let endianness = match self.endianness {
+            Endian::Little => "little",
+            Endian::Big => "big",
+        };
+        let mut out = format!("use_config = true\nendianness = \"{endianness}\"\n");
+        if let Some(frequency) = self.frequency {
+            out += &format!("frequency = {frequency}\n");
+        }
+        out += "\naddresses = {\n";
+        for (label, region) in &self.addresses {
+            let fields = serde_json::to_value(region).map_err(|err| err.to_string())?;
+            let key = match is_identifier(label) {
+                true => label.clone(),
+                false => format!("[{label:?}]"),
+            };
+            out += &format!("\t{key} = {},\n", lua_literal("", &fields));
+        }
+        out += "}\n";
+        Ok(out)
+    }
+}
+
+/// The value of `field` as Lua, arrays and maps become tables with `type` first like in hand
+/// written configs
+fn lua_literal(field: &str, value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Number(n) if HEX_FIELDS.contains(&field) =>
+            format!("{:#x}", n.as_u64().expect("Addresses are unsigned")),
+        serde_json::Value::Array(items) =>
+            format!("{{ {} }}", items.iter().map(|item| lua_literal("", item)).collect::<Vec<_>>().join(", ")),
+        serde_json::Value::Object(fields) => {
+            let entries = fields.get_key_value("type").into_iter()
+                .chain(fields.iter().filter(|(field, _)| *field != "type"))
+                .map(|(field, value)| format!("{field} = {}", lua_literal(field, value)));
+            format!("{{ {} }}", entries.collect::<Vec<_>>().join(", "))
+        },
+        value => value.to_string(),
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Written like the `perm` field of a Lua config, eg `"rx"`
+impl Serialize for Permissions {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let flags = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')];
+        serializer.collect_str(&flags.iter().filter(|(set, _)| *set).map(|(_, c)| c).collect::<String>())
+    }
+}
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Permissions::parse(&text).ok_or_else(|| serde::de::Error::custom(format!(
+            "invalid permissions \"{text}\", expected a combination of r, w and x"
+        )))
+    }
+}
+
+#[test]
+fn test_description_formats() {
+    let toml_text = "endianness = \"big\"
+
+[addresses.flash]
+type = \"flash\"
+origin = 0x08000000
+len = 0x10000
+controller = 0x40022000
+perm = \"rx\"
+
+[addresses.\"[1]\"]
+type = \"ram\"
+origin = 0x20000000
+len = 4096
+
+[addresses.boot]
+type = \"alias\"
+origin = 0
+target = \"flash\"
+
+[addresses.gpio]
+type = \"gpio\"
+origin = 0x50000000
+stimulus = [[100, 3, 1], [500, 3, 0]]
+";
+    let description = Description::parse(toml_text, Format::Toml).unwrap();
+    assert_eq!(description.endianness, Endian::Big);
+    assert!(matches!(description.addresses["flash"].kind, RegionKind::Flash {len: 0x10000, page_size: None, ..}));
+
+    // Every format reads back to the same description
+    let json_text = description.to_text(Format::Json).unwrap();
+    let from_json = Description::parse(&json_text, Format::Json).unwrap();
+    assert_eq!(from_json.to_text(Format::Toml).unwrap(), description.to_text(Format::Toml).unwrap());
+    let lua_text = description.to_text(Format::Lua).unwrap();
+    assert!(lua_text.contains("\tflash = { type = \"flash\", controller = 0x40022000, len = 0x10000, origin = 0x8000000, perm = \"rx\" },"), "{lua_text}");
+    assert!(lua_text.contains("\t[\"[1]\"] = { type = \"ram\""), "{lua_text}");
+    assert!(lua_text.contains("stimulus = { { 100, 3, 1 }, { 500, 3, 0 } }"), "{lua_text}");
+
+    let bad_perm = Description::parse("[addresses.ram]\ntype = \"ram\"\norigin = 0\nlen = 4\nperm = \"rwz\"\n", Format::Toml);
+    assert!(bad_perm.unwrap_err().contains("invalid permissions \"rwz\""));
+}
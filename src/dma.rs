@@ -0,0 +1,289 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::adr::AddressSpace;
+use crate::context::SharedContext;
+use crate::core::*;
+use crate::memory::{BusMaster, MasterPort};
+
+// Register offsets within a channel, channel n starts at n * CHANNEL_LEN
+const CTRL: AWord = 0x00;
+const SRC: AWord = 0x04;
+const DST: AWord = 0x08;
+/// Transfers left, counts down as they're done
+const COUNT: AWord = 0x0c;
+/// Write 1 to clear
+const STATUS: AWord = 0x10;
+const CHANNEL_LEN: AWord = 0x20;
+
+const CTRL_EN: AWord = 1 << 0;
+/// Interrupt enables, laid out like STATUS
+const CTRL_IE_SHIFT: AWord = 1;
+/// 0 bytes, 1 halfwords, 2 words
+const CTRL_SIZE_SHIFT: AWord = 4;
+const CTRL_SRC_INC: AWord = 1 << 6;
+const CTRL_DST_INC: AWord = 1 << 7;
+/// Start over once COUNT reaches 0 instead of disabling the channel
+const CTRL_CIRCULAR: AWord = 1 << 8;
+/// Only transfer while the request line is asserted, otherwise run memory to memory
+const CTRL_REQ: AWord = 1 << 9;
+const CTRL_LINE_SHIFT: AWord = 16;
+
+const STATUS_COMPLETE: AWord = 1 << 0;
+const STATUS_HALF: AWord = 1 << 1;
+const STATUS_ERROR: AWord = 1 << 2;
+
+/// Cycles the core waits for each transfer, a read and a write
+const TRANSFER_CYCLES: u64 = 2;
+pub const MAX_CHANNELS: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Channel {
+    ctrl: AWord,
+    src: AWord,
+    dst: AWord,
+    count: AWord,
+    status: AWord,
+    /// Latched when the channel is enabled
+    next_src: AWord,
+    next_dst: AWord,
+    reload: AWord,
+}
+
+impl Channel {
+    fn size(&self) -> Option<AWord> {
+        match self.ctrl >> CTRL_SIZE_SHIFT & 3 {
+            0 => Some(1),
+            1 => Some(2),
+            2 => Some(4),
+            _ => None,
+        }
+    }
+    fn ready(&self, requests: AWord) -> bool {
+        let line = self.ctrl >> CTRL_LINE_SHIFT & 0x1f;
+        self.ctrl & CTRL_EN != 0 && self.count != 0 && (self.ctrl & CTRL_REQ == 0 || requests & 1 << line != 0)
+    }
+    fn enable(&mut self) {
+        (self.next_src, self.next_dst, self.reload) = (self.src, self.dst, self.count);
+    }
+    /// Account for a transfer, a failed one stops the channel
+    fn advance(&mut self, size: AWord, ok: bool) {
+        if !ok {
+            self.status |= STATUS_ERROR;
+            self.ctrl &= !CTRL_EN;
+            return;
+        }
+        if self.ctrl & CTRL_SRC_INC != 0 {
+            self.next_src = self.next_src.wrapping_add(size);
+        }
+        if self.ctrl & CTRL_DST_INC != 0 {
+            self.next_dst = self.next_dst.wrapping_add(size);
+        }
+        self.count -= 1;
+        if 2 <= self.reload && self.count == self.reload / 2 {
+            self.status |= STATUS_HALF;
+        }
+        if self.count == 0 {
+            self.status |= STATUS_COMPLETE;
+            match self.ctrl & CTRL_CIRCULAR != 0 {
+                true => (self.next_src, self.next_dst, self.count) = (self.src, self.dst, self.reload),
+                false => self.ctrl &= !CTRL_EN,
+            }
+        }
+    }
+}
+
+struct Controller {
+    context: SharedContext,
+    irq: Option<u32>,
+    channels: Vec<Channel>,
+}
+
+impl Controller {
+    fn interrupt(&self) {
+        let flagged = self.channels.iter().any(|channel| channel.status & channel.ctrl >> CTRL_IE_SHIFT != 0);
+        if let Some(irq) = self.irq && flagged {
+            self.context.borrow_mut().nvic.raise(irq);
+        }
+    }
+}
+
+/// A DMA controller with up to 8 channels, moving bytes, halfwords or words over the bus
+///
+/// Each enabled channel does a transfer after every step of the core while it has any left,
+/// lower channels first, either freely or while its request line is asserted. Transfers go
+/// through the bus like the core's accesses, so permissions and watchpoints apply, and the core
+/// is stalled for 2 cycles per transfer. An unaligned address or a refused access sets the error
+/// flag and disables the channel.
+pub struct Dma {
+    pub origin: AWord,
+    controller: Rc<RefCell<Controller>>,
+}
+
+impl Dma {
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, channels: usize) -> Result<Self, String> {
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(format!("A DMA controller has 1 to {MAX_CHANNELS} channels, not {channels}"));
+        }
+        let controller = Controller {context, irq, channels: vec![Channel::default(); channels]};
+        Ok(Self {origin, controller: Rc::new(RefCell::new(controller))})
+    }
+    /// The side doing the transfers, to be added to the bus
+    pub fn master(&self) -> DmaMaster {
+        DmaMaster(self.controller.clone())
+    }
+}
+
+impl AddressSpace for Dma {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {self.controller.borrow().channels.len() as AWord * CHANNEL_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let channel = self.controller.borrow().channels[(adr / CHANNEL_LEN) as usize];
+        let word = match (adr % CHANNEL_LEN) & !3 {
+            CTRL => channel.ctrl,
+            SRC => channel.src,
+            DST => channel.dst,
+            COUNT => channel.count,
+            STATUS => channel.status,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let lane = |word: AWord| word & !(0xff << shift) | (x as AWord) << shift;
+        let mut controller = self.controller.borrow_mut();
+        let channel = &mut controller.channels[(adr / CHANNEL_LEN) as usize];
+        // The addresses and count are fixed while the channel is enabled
+        let enabled = channel.ctrl & CTRL_EN != 0;
+        match (adr % CHANNEL_LEN) & !3 {
+            CTRL => {
+                channel.ctrl = lane(channel.ctrl);
+                if !enabled && channel.ctrl & CTRL_EN != 0 {
+                    channel.enable();
+                }
+            },
+            SRC if !enabled => channel.src = lane(channel.src),
+            DST if !enabled => channel.dst = lane(channel.dst),
+            COUNT if !enabled => channel.count = lane(channel.count),
+            STATUS => channel.status &= !((x as AWord) << shift),
+            _ => {},
+        }
+    }
+    fn tick(&mut self, _cycles: u64) {
+        self.controller.borrow().interrupt();
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for channel in &self.controller.borrow().channels {
+            let words = [channel.ctrl, channel.src, channel.dst, channel.count, channel.status,
+                channel.next_src, channel.next_dst, channel.reload];
+            for word in words {
+                out.extend(word.to_le_bytes());
+            }
+        }
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let channels = &mut self.controller.borrow_mut().channels;
+        if state.len() != channels.len() * 8 * 4 {
+            return Err("Invalid DMA state".into());
+        }
+        for (channel, state) in channels.iter_mut().zip(state.chunks(8 * 4)) {
+            let word = |idx: usize| AWord::from_le_bytes(state[idx * 4..idx * 4 + 4].try_into().unwrap());
+            (channel.ctrl, channel.src, channel.dst, channel.count) = (word(0), word(1), word(2), word(3));
+            (channel.status, channel.next_src, channel.next_dst, channel.reload) = (word(4), word(5), word(6), word(7));
+        }
+        Ok(())
+    }
+}
+
+/// Does the transfers of a `Dma`, the controller isn't borrowed during an access so a channel can
+/// reach the registers of the controller itself
+pub struct DmaMaster(Rc<RefCell<Controller>>);
+
+impl BusMaster for DmaMaster {
+    fn run(&mut self, port: &mut MasterPort) -> u64 {
+        let requests = self.0.borrow().context.borrow().requests;
+        let (mut cycles, channels) = (0, self.0.borrow().channels.len());
+        for idx in 0..channels {
+            let channel = self.0.borrow().channels[idx];
+            if !channel.ready(requests) {
+                continue;
+            }
+            let size = channel.size();
+            let ok = match size {
+                Some(size) if channel.next_src.is_multiple_of(size) && channel.next_dst.is_multiple_of(size) => {
+                    cycles += TRANSFER_CYCLES;
+                    let result = port.read(channel.next_src, size)
+                        .and_then(|x| port.write(channel.next_dst, size, x));
+                    if let Err(fault) = result {
+                        log::debug!("DMA channel {idx} stopped by {fault:?}");
+                    }
+                    result.is_ok()
+                },
+                _ => false,
+            };
+            self.0.borrow_mut().channels[idx].advance(size.unwrap_or(0), ok);
+        }
+        self.0.borrow().interrupt();
+        cycles
+    }
+}
+
+#[test]
+fn test_dma() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    let mut memory = AddressDeMultiplexer::full();
+    let context = memory.context();
+    let dma = Dma::new(0x100, context.clone(), Some(7), 2).unwrap();
+    let master = Box::new(dma.master());
+    let mut add = |label: &str, perm: &str, space: Box<dyn AddressSpace>| memory.add_region(MappedRegion {
+        label: label.to_string(), kind: "test", priority: 0, perm: Permissions::parse(perm).unwrap(), space
+    }).unwrap();
+    add("ram", "rw", Box::new(BufferMemory {origin: 0, buffer: Box::new([0; 0x40])}));
+    add("dma", "rw", Box::new(dma));
+    add("rom", "r", Box::new(BufferMemory {origin: 0x200, buffer: Box::new([0x11, 0x22, 0x33, 0x44, 0x55, 0x66])}));
+    memory.add_master(master);
+    assert!(Dma::new(0, context.clone(), None, 9).is_err());
+
+    // Halfwords from ROM to RAM, memory to memory
+    memory.write_w(0x100 + SRC, 0x200);
+    memory.write_w(0x100 + DST, 0x10);
+    memory.write_w(0x100 + COUNT, 3);
+    memory.write_w(0x100 + CTRL, 1 << CTRL_SIZE_SHIFT | CTRL_SRC_INC | CTRL_DST_INC | STATUS_COMPLETE << CTRL_IE_SHIFT | CTRL_EN);
+    let mut writes = Vec::new();
+    assert_eq!(memory.run_masters(Some(&mut writes)), 2);
+    assert_eq!(writes, [0x10, 0x11]);
+    assert_eq!(memory.read_w(0x100 + COUNT), 2);
+    assert_eq!(memory.read_w(0x100 + STATUS), 0);
+    assert_eq!(memory.run_masters(None) + memory.run_masters(None) + memory.run_masters(None), 4);
+    assert_eq!(memory.read_w(0x10), 0x44332211);
+    assert_eq!(memory.read_hw(0x14), 0x6655);
+    assert_eq!(memory.read_w(0x100 + STATUS), STATUS_COMPLETE | STATUS_HALF);
+    assert_eq!(memory.read_w(0x100 + CTRL) & CTRL_EN, 0);
+    assert_eq!(context.borrow().nvic.pending, 1 << 7);
+
+    // Bytes from request line 5 into ROM, which refuses the write without stopping the core
+    memory.write_w(0x120 + SRC, 0);
+    memory.write_w(0x120 + DST, 0x200);
+    memory.write_w(0x120 + COUNT, 1);
+    memory.write_w(0x120 + CTRL, 5 << CTRL_LINE_SHIFT | CTRL_REQ | CTRL_EN);
+    assert_eq!(memory.run_masters(None), 0);
+    context.borrow_mut().request(Some(5), true);
+    assert_eq!(memory.run_masters(None), 2);
+    assert_eq!(memory.read_w(0x120 + STATUS), STATUS_ERROR);
+    assert_eq!(memory.readb(0x200), 0x11);
+    assert_eq!(memory.take_fault(), None);
+    // Circular bytes start over from SRC and DST with the latched count
+    memory.write_w(0x100 + STATUS, STATUS_COMPLETE | STATUS_HALF);
+    memory.write_w(0x100 + SRC, 0x202);
+    memory.write_w(0x100 + DST, 0x20);
+    memory.write_w(0x100 + COUNT, 2);
+    memory.write_w(0x100 + CTRL, CTRL_SRC_INC | CTRL_DST_INC | CTRL_CIRCULAR | CTRL_EN);
+    assert_eq!(memory.run_masters(None) + memory.run_masters(None), 4);
+    assert_eq!(memory.read_w(0x100 + COUNT), 2);
+    assert_eq!(memory.read_w(0x100 + STATUS), STATUS_COMPLETE | STATUS_HALF);
+    memory.write_w(0x20, 0);
+    assert_eq!(memory.run_masters(None), 2);
+    assert_eq!(memory.read_w(0x20), 0x33);
+    assert_eq!(memory.read_w(0x100 + COUNT), 1);
+    assert_ne!(memory.read_w(0x100 + CTRL) & CTRL_EN, 0);
+}
@@ -0,0 +1,226 @@
+use crate::adr::AddressSpace;
+use crate::core::AWord;
+use crate::context::ResetCause;
+use crate::exceptions::{self, Nvic};
+use crate::ins::LoaderExecuter;
+use crate::instructions::load_basic_instructions;
+use crate::memory::{AddressDeMultiplexer, BusFault, WriteLog};
+use crate::registers::{Registers, PC_IDX, SP_IDX};
+use crate::vcd::Vcd;
+
+/// Why execution should pause after a step
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// The next instruction is at a breakpoint
+    Breakpoint(AWord),
+    /// The last instruction wrote to a watched address
+    Watchpoint(AWord),
+    /// The next instruction made an access the bus refused, it was not executed. Also raised when
+    /// stacking for an interrupt is refused, the handler is entered regardless.
+    Fault(BusFault),
+    /// A peripheral failed, eg a Lua function of a func region raised an error. The instruction
+    /// accessing it was not executed.
+    Error(String),
+}
+
+/// Everything needed to run a program: the core, the bus and a clock
+pub struct Emulator {
+    pub cpu: Registers,
+    pub memory: AddressDeMultiplexer<'static>,
+    pub instructions: LoaderExecuter,
+    /// Every instruction currently takes a single cycle, plus any the core waits for the bus
+    pub cycles: u64,
+    pub breakpoints: Vec<AWord>,
+    /// Byte addresses, any write to one of them stops execution
+    pub watchpoints: Vec<AWord>,
+    /// Exception number entered during the last step
+    pub exception: Option<u32>,
+    /// Waveform sampled after every step, see `record`
+    pub vcd: Option<Vcd>,
+    writes: Vec<AWord>,
+    /// Volatile state of the regions before the first step, what a system reset goes back to
+    power_on: Vec<u8>,
+}
+
+impl Emulator {
+    pub fn new(memory: AddressDeMultiplexer<'static>) -> Self {
+        let mut cpu = Registers::default();
+        cpu.r[PC_IDX] = 2; // PC Points to currently executing instruction + 4
+        cpu.r[SP_IDX] = 2000; // kinda a hack to start with(at the top of a memory page defined in lua
+                              // config)
+
+        let mut instructions = LoaderExecuter::new();
+        load_basic_instructions(&mut instructions);
+        memory.context().borrow_mut().resets = ResetCause::PowerOn as AWord;
+        let mut power_on = Vec::new();
+        memory.save_volatile(&mut power_on);
+
+        let mut emulator = Self {
+            cpu,
+            memory,
+            instructions,
+            cycles: 0,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            exception: None,
+            vcd: None,
+            writes: Vec::new(),
+            power_on,
+        };
+        if emulator.memory.has_vector_table() {
+            emulator.boot();
+        }
+        emulator
+    }
+    /// Take the initial SP and the reset handler from the vector table at 0
+    fn boot(&mut self) {
+        self.cpu.r[SP_IDX] = self.memory.read_w(0);
+        self.cpu.r[PC_IDX] = (self.memory.read_w(4) & !1).wrapping_add(2);
+    }
+    /// Address of the next instruction to be executed
+    pub fn pc(&self) -> AWord {
+        self.cpu.r[PC_IDX].wrapping_sub(2)
+    }
+    /// Start a waveform at the current state
+    pub fn record(&mut self, mut vcd: Vcd) -> std::io::Result<()> {
+        vcd.sample(self.cycles, self.pc(), &self.memory)?;
+        self.vcd = Some(vcd);
+        Ok(())
+    }
+    /// Reset the system like a watchdog would: regions but the non volatile ones go back to their
+    /// power on state, and the core takes its SP and PC from the vector table at 0
+    pub fn reset(&mut self, cause: ResetCause) {
+        let context = self.memory.context();
+        let resets = context.borrow().resets | cause as AWord;
+        self.memory.restore_volatile(&self.power_on).expect("The power on state is of these regions");
+        {
+            let mut context = context.borrow_mut();
+            context.nvic = Nvic::default();
+            (context.requests, context.resets) = (0, resets);
+        }
+        self.cpu = Registers::default();
+        self.boot();
+        self.exception = None;
+    }
+    pub fn step(&mut self) -> Option<Stop> {
+        let context = self.memory.context();
+        {
+            let mut context = context.borrow_mut();
+            (context.pc, context.cycles) = (self.pc(), self.cycles);
+        }
+        self.exception = None;
+        // Restored on a fault so the faulting instruction can be inspected, memory writes it made
+        // before faulting stay
+        let before = self.cpu.clone();
+        if self.watchpoints.is_empty() {
+            crate::step(&self.instructions, &mut self.cpu, &mut self.memory);
+        } else {
+            self.writes.clear();
+            let mut logged = WriteLog {inner: &mut self.memory, writes: &mut self.writes};
+            crate::step(&self.instructions, &mut self.cpu, &mut logged);
+        }
+        if let Some(fault) = self.memory.take_fault() {
+            self.cpu = before;
+            return Some(Stop::Fault(fault));
+        }
+        let error = context.borrow_mut().error.take();
+        if let Some(error) = error {
+            self.cpu = before;
+            return Some(Stop::Error(error));
+        }
+        if exceptions::is_return(&self.cpu) {
+            exceptions::leave(&mut self.cpu, &mut self.memory);
+            context.borrow_mut().nvic.active = 0;
+        }
+        self.cycles += 1;
+        context.borrow_mut().cycles = self.cycles;
+        self.memory.tick(1);
+        let writes = (!self.watchpoints.is_empty()).then_some(&mut self.writes);
+        let stalled = self.memory.run_masters(writes);
+        if stalled != 0 {
+            self.cycles += stalled;
+            context.borrow_mut().cycles = self.cycles;
+            self.memory.tick(stalled);
+        }
+        let error = context.borrow_mut().error.take();
+        if let Some(error) = error {
+            return Some(Stop::Error(error));
+        }
+        let reset = context.borrow_mut().reset.take();
+        if let Some(cause) = reset {
+            log::warn!("{cause:?} reset at cycle {}", self.cycles);
+            self.reset(cause);
+        }
+
+        let irq = context.borrow().nvic.next();
+        if let Some(irq) = irq {
+            let number = exceptions::IRQ_BASE + irq;
+            {
+                let nvic = &mut context.borrow_mut().nvic;
+                nvic.pending &= !(1 << irq);
+                nvic.active = number;
+            }
+            exceptions::enter(&mut self.cpu, &mut self.memory, number);
+            self.exception = Some(number);
+        }
+        if let Some(fault) = self.memory.take_fault() {
+            return Some(Stop::Fault(fault));
+        }
+        let pc = self.pc();
+        if let Some(vcd) = &mut self.vcd && let Err(err) = vcd.sample(self.cycles, pc, &self.memory) {
+            self.vcd = None;
+            return Some(Stop::Error(format!("Failed to write the VCD: {err}")));
+        }
+
+        let watched = self.writes.iter().find(|adr| self.watchpoints.contains(adr));
+        if let Some(&adr) = watched {
+            return Some(Stop::Watchpoint(adr));
+        }
+        self.breakpoints.contains(&pc).then_some(Stop::Breakpoint(pc))
+    }
+}
+
+#[test]
+fn test_data_endianness() {
+    use crate::adr::Endian;
+    use crate::memory::{BufferMemory, MappedRegion, Permissions};
+    // str r0, [r1]; ldr r2, [r1]; ldrb r3, [r1]
+    let program = [0x08, 0x60, 0x0a, 0x68, 0x0b, 0x78];
+    for (endian, first_byte) in [(Endian::Little, 0x44), (Endian::Big, 0x11)] {
+        let mut buffer = [0u8; 64];
+        buffer[..program.len()].copy_from_slice(&program);
+        let mut memory = AddressDeMultiplexer::full();
+        memory.set_endian(endian);
+        let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+        memory.add_region(MappedRegion {label: "ram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+        let mut emulator = Emulator::new(memory);
+        (emulator.cpu.r[0], emulator.cpu.r[1]) = (0x11223344, 32);
+
+        for _ in 0..3 {
+            assert_eq!(emulator.step(), None);
+        }
+        assert_eq!(emulator.cpu.r[2], 0x11223344);
+        assert_eq!(emulator.cpu.r[3], first_byte);
+    }
+}
+
+#[test]
+fn test_boot_from_vector_table() {
+    // Initial SP, the reset handler at 8; movs r0, #42; b .
+    let image = [0x00, 0x10, 0x00, 0x20, 0x09, 0x00, 0x00, 0x08, 0x2a, 0x20, 0xfe, 0xe7];
+    let dir = std::env::temp_dir();
+    let (program, config) = (dir.join("cm0-boot-test.bin"), dir.join("cm0-boot-test.lua"));
+    std::fs::write(&program, image).unwrap();
+    let text = format!("use_config = true chip = \"stm32f030\" addresses = {{ flash = {{ path = {:?} }} }}", program.to_str().unwrap());
+    std::fs::write(&config, text).unwrap();
+    let memory = crate::config::load(config.to_str().unwrap(), &Default::default()).unwrap().memory;
+    std::fs::remove_file(&program).unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    let mut emulator = Emulator::new(memory);
+    assert_eq!(emulator.cpu.r[SP_IDX], 0x2000_1000);
+    assert_eq!(emulator.pc(), 0x0800_0008);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.cpu.r[0], 42);
+    assert_eq!(emulator.pc(), 0x0800_000a);
+}
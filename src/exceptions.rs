@@ -0,0 +1,211 @@
+use crate::adr::{AddressSpace, Endian};
+use crate::context::SharedContext;
+use crate::core::*;
+use crate::registers::{Registers, LR_IDX, PC_IDX, SP_IDX};
+
+/// Exception number of IRQ0
+pub const IRQ_BASE: u32 = 16;
+pub const IRQ_COUNT: u32 = 32;
+/// Loaded into the PC to return from a handler to thread mode on the main stack
+pub const EXC_RETURN: AWord = 0xffff_fff9;
+
+// Register offsets from 0xE000E100
+const ISER: AWord = 0x000;
+const ICER: AWord = 0x080;
+const ISPR: AWord = 0x100;
+const ICPR: AWord = 0x180;
+const NVIC_LEN: AWord = 0x200;
+
+/// CPUID of a Cortex-M0 r0p0, a Cortex-M0+ r0p1 reads 0x410CC601
+pub const CPUID_M0: AWord = 0x410c_c200;
+// Register offsets from 0xE000ED00
+const CPUID: AWord = 0x00;
+const AIRCR: AWord = 0x0c;
+const SCB_LEN: AWord = 0x40;
+/// Read from the key field of AIRCR
+const VECTKEYSTAT: AWord = 0xfa05 << 16;
+
+/// Interrupt state, a bit per IRQ
+///
+/// Handlers are never preempted and there are no priorities, the lowest pending IRQ is taken
+/// once the core is back in thread mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nvic {
+    pub enabled: u32,
+    pub pending: u32,
+    /// Exception number being handled, 0 in thread mode
+    pub active: u32,
+}
+impl Nvic {
+    /// Make `irq` pending. Peripherals are level triggered, they raise their IRQ every tick while
+    /// one of their enabled flags is set, so it is taken again until the handler clears the flag.
+    pub fn raise(&mut self, irq: u32) {
+        if irq < IRQ_COUNT {
+            self.pending |= 1 << irq;
+        }
+    }
+    /// The IRQ to take now, if any
+    pub fn next(&self) -> Option<u32> {
+        let ready = self.enabled & self.pending;
+        (self.active == 0 && ready != 0).then(|| ready.trailing_zeros())
+    }
+}
+
+/// The ISER, ICER, ISPR and ICPR registers, at 0xE000E100 on every Cortex-M0
+pub struct NvicRegisters {
+    pub origin: AWord,
+    pub context: SharedContext,
+}
+impl AddressSpace for NvicRegisters {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {NVIC_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let nvic = self.context.borrow().nvic;
+        let word = match adr & !3 {
+            ISER | ICER => nvic.enabled,
+            ISPR | ICPR => nvic.pending,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    // Set and clear registers work bit by bit, so each byte can be applied on its own
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let bits = (x as u32) << ((adr % 4) * 8);
+        let nvic = &mut self.context.borrow_mut().nvic;
+        match adr & !3 {
+            ISER => nvic.enabled |= bits,
+            ICER => nvic.enabled &= !bits,
+            ISPR => nvic.pending |= bits,
+            ICPR => nvic.pending &= !bits,
+            _ => {},
+        }
+    }
+}
+
+/// The identification registers of the system control block, at 0xE000ED00
+///
+/// CPUID and AIRCR read like on hardware, the rest reads as 0 and ignores writes.
+pub struct ScbRegisters {
+    pub origin: AWord,
+    pub cpuid: AWord,
+    pub endian: Endian,
+}
+impl AddressSpace for ScbRegisters {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {SCB_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            CPUID => self.cpuid,
+            AIRCR => VECTKEYSTAT | ((self.endian == Endian::Big) as AWord) << 15,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, _adr: AWord, _x: AByte) {}
+}
+
+/// Stack r0-r3, r12, lr, the return address and xPSR, then continue at the handler of exception
+/// `number` from the vector table at 0
+pub fn enter(cpu: &mut Registers, memory: &mut dyn AddressSpace, number: u32) {
+    let return_address = cpu.r[PC_IDX].wrapping_sub(2);
+    let flags = [cpu.n, cpu.z, cpu.c, cpu.v].iter().fold(0, |acc, &flag| (acc << 1) | flag as AWord);
+    let mut xpsr = flags << 28 | 1 << 24;
+    // The frame is 8 byte aligned, xPSR bit 9 records the padding
+    if !cpu.r[SP_IDX].is_multiple_of(8) {
+        xpsr |= 1 << 9;
+    }
+    let sp = (cpu.r[SP_IDX] & !7).wrapping_sub(32);
+    let frame = [cpu.r[0], cpu.r[1], cpu.r[2], cpu.r[3], cpu.r[12], cpu.r[LR_IDX], return_address, xpsr];
+    for (idx, value) in frame.into_iter().enumerate() {
+        memory.write_w(sp.wrapping_add(4 * idx as AWord), value);
+    }
+    cpu.r[SP_IDX] = sp;
+    cpu.r[LR_IDX] = EXC_RETURN;
+    let handler = memory.read_w(4 * number);
+    cpu.r[PC_IDX] = (handler & !1).wrapping_add(2);
+}
+
+/// Whether the last instruction loaded an EXC_RETURN value into the PC
+pub fn is_return(cpu: &Registers) -> bool {
+    cpu.r[PC_IDX] & 0xffff_fff0 == 0xffff_fff0
+}
+
+/// Inverse of `enter`
+pub fn leave(cpu: &mut Registers, memory: &mut dyn AddressSpace) {
+    let sp = cpu.r[SP_IDX];
+    let frame: [AWord; 8] = std::array::from_fn(|idx| memory.read_w(sp.wrapping_add(4 * idx as AWord)));
+    cpu.r[..4].copy_from_slice(&frame[..4]);
+    cpu.r[12] = frame[4];
+    cpu.r[LR_IDX] = frame[5];
+    cpu.r[PC_IDX] = (frame[6] & !1).wrapping_add(2);
+    let xpsr = frame[7];
+    (cpu.n, cpu.z, cpu.c, cpu.v) = (xpsr & 1 << 31 != 0, xpsr & 1 << 30 != 0, xpsr & 1 << 29 != 0, xpsr & 1 << 28 != 0);
+    cpu.r[SP_IDX] = sp.wrapping_add(if xpsr & 1 << 9 != 0 {36} else {32});
+}
+
+#[test]
+fn test_interrupt_round_trip() {
+    use crate::emulator::Emulator;
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    let mut buffer = [0u8; 0x200];
+    // IRQ0 vector
+    buffer[64..68].copy_from_slice(&0x181u32.to_le_bytes());
+    // b .
+    buffer[0x100..0x102].copy_from_slice(&[0xfe, 0xe7]);
+    // adds r4, #1; bx lr
+    buffer[0x180..0x184].copy_from_slice(&[0x01, 0x34, 0x70, 0x47]);
+    let mut memory = AddressDeMultiplexer::full();
+    let context = memory.context();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+    memory.add_region(MappedRegion {label: "ram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let space = Box::new(NvicRegisters {origin: 0xe000e100, context: context.clone()});
+    memory.add_region(MappedRegion {label: "nvic".to_string(), kind: "nvic", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut emulator = Emulator::new(memory);
+    emulator.cpu.r[PC_IDX] = 0x102;
+    emulator.cpu.r[SP_IDX] = 0x1fc;
+    emulator.cpu.z = true;
+
+    // Pending but not enabled
+    context.borrow_mut().nvic.raise(0);
+    emulator.step();
+    assert_eq!(emulator.pc(), 0x100);
+    emulator.memory.write_w(0xe000e100, 1);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.pc(), 0x180);
+    assert_eq!(emulator.cpu.r[SP_IDX], 0x1d8);
+    assert_eq!(emulator.cpu.r[LR_IDX], EXC_RETURN);
+    assert_eq!(context.borrow().nvic, Nvic {enabled: 1, pending: 0, active: IRQ_BASE});
+
+    emulator.step();
+    emulator.step();
+    assert_eq!(emulator.cpu.r[4], 1);
+    assert_eq!(emulator.pc(), 0x100);
+    assert_eq!(emulator.cpu.r[SP_IDX], 0x1fc);
+    assert!(emulator.cpu.z);
+    assert_eq!(context.borrow().nvic.active, 0);
+}
+
+#[test]
+fn test_big_endian_system_registers() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    let mut memory = AddressDeMultiplexer::full();
+    memory.set_endian(Endian::Big);
+    let context = memory.context();
+    let space = Box::new(BufferMemory {origin: 0x2000_0000, buffer: Box::new([0; 4])});
+    memory.add_region(MappedRegion {label: "sram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let space = Box::new(NvicRegisters {origin: 0xe000e100, context: context.clone()});
+    memory.add_region(MappedRegion {label: "nvic".to_string(), kind: "nvic", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let space = Box::new(ScbRegisters {origin: 0xe000ed00, cpuid: CPUID_M0, endian: Endian::Big});
+    memory.add_region(MappedRegion {label: "scb".to_string(), kind: "scb", priority: 0, perm: Permissions::ALL, space}).unwrap();
+
+    // The private peripheral bus stays little endian
+    assert_eq!(memory.read_w(0xe000ed00), CPUID_M0);
+    assert_ne!(memory.read_w(0xe000ed0c) & 1 << 15, 0);
+    memory.write_w(0xe000e100, 1 << 5);
+    assert_eq!(context.borrow().nvic.enabled, 1 << 5);
+    memory.write_hw(0xe000e100, 1);
+    assert_eq!(context.borrow().nvic.enabled, 1 << 5 | 1);
+
+    memory.write_w(0x2000_0000, 1 << 5);
+    assert_eq!(memory.readb(0x2000_0003), 1 << 5);
+}
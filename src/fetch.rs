@@ -7,19 +7,14 @@ pub fn fetch_instruction(ip: &mut AWord, memory: &mut dyn AddressSpace) -> InsDa
     let mut load_half_word = || -> AHalfWord {
         // Load Instruction
         let load_adr = ip.wrapping_sub(2);
-        let half_word = memory.read_hw_le(load_adr);
+        let half_word = memory.fetch_hw(load_adr);
         // Advance to next instruction
         *ip = ip.wrapping_add(2);
-        return half_word;
+        half_word
     };
     let instruction = load_half_word();
     let load_another_byte_clue = instruction >> (16-5);
-    let load_extended_32_instruction = match load_another_byte_clue {
-        0b11101 |
-        0b11110 |
-        0b11111 => true,
-        _ => false,
-    };
+    let load_extended_32_instruction = matches!(load_another_byte_clue, 0b11101..=0b11111);
     match load_extended_32_instruction {
         false => InsData{ hdr: instruction, ext: None },
         true => {
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::adr::AddressSpace;
+use crate::core::*;
+
+pub const ERASED: AByte = 0xff;
+
+// Controller registers, laid out like the STM32F0 flash interface
+const KEYR: AWord = 0x04;
+const SR: AWord = 0x0c;
+const CR: AWord = 0x10;
+const AR: AWord = 0x14;
+const CONTROLLER_LEN: AWord = 0x18;
+
+const KEY1: AWord = 0x45670123;
+const KEY2: AWord = 0xcdef89ab;
+
+const SR_BSY: AWord = 1 << 0;
+const SR_PGERR: AWord = 1 << 2;
+const SR_EOP: AWord = 1 << 5;
+const CR_PG: AWord = 1 << 0;
+const CR_PER: AWord = 1 << 1;
+const CR_MER: AWord = 1 << 2;
+const CR_STRT: AWord = 1 << 6;
+const CR_LOCK: AWord = 1 << 7;
+
+/// Flash storage and the state of its controller, shared by `FlashArray` and `FlashController`
+///
+/// Programming can only clear bits, setting them again takes a page erase. Operations complete
+/// immediately, so `BSY` never reads as set.
+pub struct Flash {
+    /// Address of the array, AR holds addresses rather than offsets
+    origin: AWord,
+    data: Box<[u8]>,
+    page_size: AWord,
+    /// Written back on `flush` when set
+    path: Option<String>,
+    dirty: bool,
+    /// Number of correct keys written to KEYR
+    keys: u8,
+    sr: AWord,
+    cr: AWord,
+    ar: AWord,
+}
+
+impl Flash {
+    /// An array at `origin`, `image` is padded with the erased value up to `len`
+    pub fn new(origin: AWord, image: &[u8], len: AWord, page_size: AWord, path: Option<String>) -> Result<Self, String> {
+        if page_size == 0 || !len.is_multiple_of(page_size) {
+            return Err(format!("Length {len} is not a multiple of the page size {page_size}"));
+        }
+        if len < image.len() as AWord {
+            return Err(format!("Image of {} bytes does not fit in {len} bytes", image.len()));
+        }
+        let mut data = vec![ERASED; len as usize].into_boxed_slice();
+        data[..image.len()].copy_from_slice(image);
+        Ok(Self {origin, data, page_size, path, dirty: false, keys: 0, sr: 0, cr: CR_LOCK, ar: 0})
+    }
+    fn unlocked(&self) -> bool {
+        self.cr & CR_LOCK == 0
+    }
+    fn erase(&mut self, from: usize, len: usize) {
+        self.data[from..from + len].fill(ERASED);
+        self.dirty = true;
+        self.sr |= SR_EOP;
+    }
+    fn program(&mut self, adr: AWord, x: AByte) {
+        if !self.unlocked() || self.cr & CR_PG == 0 {
+            self.sr |= SR_PGERR;
+            return;
+        }
+        self.data[adr as usize] &= x;
+        self.dirty = true;
+        self.sr |= SR_EOP;
+    }
+    fn write_register(&mut self, offset: AWord, x: AWord) {
+        match offset {
+            KEYR => {
+                let expected = if self.keys == 0 {KEY1} else {KEY2};
+                self.keys = if x == expected {self.keys + 1} else {0};
+                if self.keys == 2 {
+                    self.cr &= !CR_LOCK;
+                    self.keys = 0;
+                }
+            },
+            // Write one to clear
+            SR => self.sr &= !(x & (SR_PGERR | SR_EOP)),
+            CR if x & CR_LOCK != 0 => self.cr = CR_LOCK,
+            CR if self.unlocked() => {
+                self.cr = x & (CR_PG | CR_PER | CR_MER);
+                if x & CR_STRT != 0 && x & CR_MER != 0 {
+                    self.erase(0, self.data.len());
+                } else if x & CR_STRT != 0 && x & CR_PER != 0 {
+                    match self.ar.checked_sub(self.origin).filter(|&offset| offset < self.data.len() as AWord) {
+                        Some(offset) => {
+                            let page_size = self.page_size as usize;
+                            self.erase((offset / self.page_size) as usize * page_size, page_size);
+                        },
+                        None => self.sr |= SR_PGERR,
+                    }
+                }
+            },
+            AR => self.ar = x,
+            _ => {},
+        }
+    }
+    fn read_register(&self, offset: AWord) -> AWord {
+        match offset {
+            SR => self.sr & !SR_BSY,
+            CR => self.cr,
+            AR => self.ar,
+            _ => 0,
+        }
+    }
+}
+
+/// The flash contents, writes are program operations
+pub struct FlashArray {
+    pub flash: Rc<RefCell<Flash>>,
+}
+impl AddressSpace for FlashArray {
+    fn origin(&self) -> AWord {self.flash.borrow().origin}
+    fn len(&self) -> AWord {self.flash.borrow().data.len() as AWord}
+    fn readb(&mut self, adr: AWord) -> AByte {self.flash.borrow().data[adr as usize]}
+    fn writeb(&mut self, adr: AWord, x: AByte) {self.flash.borrow_mut().program(adr, x)}
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.flash.borrow().data);
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut flash = self.flash.borrow_mut();
+        if state.len() != flash.data.len() {
+            return Err(format!("Flash at {} is {} bytes, snapshot has {}", flash.origin, flash.data.len(), state.len()).into());
+        }
+        flash.data.copy_from_slice(state);
+        flash.dirty = true;
+        Ok(())
+    }
+    fn non_volatile(&self) -> bool {true}
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut flash = self.flash.borrow_mut();
+        if let Some(path) = flash.path.as_ref().filter(|_| flash.dirty) {
+            std::fs::write(path, &flash.data)?;
+            flash.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Memory mapped registers controlling unlock, erase and program operations
+///
+/// Byte and halfword writes change their part of a register, KEYR only takes keys written whole.
+pub struct FlashController {
+    pub origin: AWord,
+    pub flash: Rc<RefCell<Flash>>,
+}
+impl FlashController {
+    pub fn new(origin: AWord, flash: Rc<RefCell<Flash>>) -> Self {
+        Self {origin, flash}
+    }
+    /// Write the bits of `mask` at `adr`, leaving the rest of the register as it is
+    fn write_part(&mut self, adr: AWord, x: AWord, mask: AWord) {
+        let (offset, shift) = (adr & !3, (adr % 4) * 8);
+        let mut flash = self.flash.borrow_mut();
+        let value = match offset {
+            // Taken as written, SR clears the bits written as one
+            KEYR | SR => x << shift,
+            _ => flash.read_register(offset) & !(mask << shift) | x << shift,
+        };
+        flash.write_register(offset, value);
+    }
+}
+impl AddressSpace for FlashController {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {CONTROLLER_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = self.flash.borrow().read_register(adr & !3);
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {self.write_part(adr, x as AWord, 0xff)}
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {self.write_part(adr, x as AWord, 0xffff)}
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {self.write_part(adr, x, AWord::MAX)}
+    fn save_state(&self, out: &mut Vec<u8>) {
+        let flash = self.flash.borrow();
+        out.push(flash.keys);
+        for register in [flash.sr, flash.cr, flash.ar] {
+            out.extend(register.to_le_bytes());
+        }
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 13 {
+            return Err("Invalid flash controller state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[1 + idx * 4..5 + idx * 4].try_into().unwrap());
+        let mut flash = self.flash.borrow_mut();
+        flash.keys = state[0];
+        (flash.sr, flash.cr, flash.ar) = (word(0), word(1), word(2));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flash_program_and_erase() {
+    let flash = Rc::new(RefCell::new(Flash::new(0, &[0x0f, 0xf0], 8, 4, None).unwrap()));
+    let mut array = FlashArray {flash: flash.clone()};
+    let mut controller = FlashController::new(0x100, flash.clone());
+    assert_eq!(array.read_w(4), 0xffffffff);
+
+    // Locked
+    array.writeb(4, 0);
+    assert_eq!(array.readb(4), ERASED);
+    assert_ne!(controller.read_w(SR) & SR_PGERR, 0);
+
+    controller.write_w(KEYR, KEY1);
+    controller.write_w(KEYR, KEY2);
+    controller.write_w(CR, CR_PG);
+    array.write_hw(0, 0xff33);
+    assert_eq!(array.read_hw(0), 0xf003);
+
+    controller.write_w(AR, 2);
+    controller.write_w(CR, CR_PER | CR_STRT);
+    assert_eq!(array.read_w(0), 0xffffffff);
+    assert_ne!(controller.read_w(SR) & SR_EOP, 0);
+
+    controller.write_w(CR, CR_LOCK);
+    assert_eq!(controller.read_w(CR), CR_LOCK);
+
+    // Byte and halfword writes, to an array that doesn't start at a multiple of its length
+    let flash = Rc::new(RefCell::new(Flash::new(0x104, &[0; 8], 8, 4, None).unwrap()));
+    let mut array = FlashArray {flash: flash.clone()};
+    let mut controller = FlashController::new(0x100, flash.clone());
+    controller.write_w(KEYR, KEY1);
+    controller.write_w(KEYR, KEY2);
+    controller.write_hw(AR, 0x106);
+    controller.write_hw(CR, (CR_PER | CR_STRT) as AHalfWord);
+    assert_eq!((array.read_w(0), array.read_w(4)), (0xffffffff, 0));
+    assert_eq!(controller.read_w(CR), CR_PER);
+
+    // Past the end of the array
+    controller.writeb(AR, 0x0c);
+    controller.writeb(CR, (CR_PER | CR_STRT) as AByte);
+    assert_eq!(array.read_w(4), 0);
+    assert_ne!(controller.read_w(SR) & SR_PGERR, 0);
+    controller.writeb(SR, SR_PGERR as AByte);
+    assert_eq!(controller.read_w(SR) & SR_PGERR, 0);
+}
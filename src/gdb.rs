@@ -0,0 +1,360 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::adr::AddressSpace;
+use crate::core::AWord;
+use crate::emulator::{Emulator, Stop};
+use crate::registers::PC_IDX;
+use crate::reverse::History;
+
+/// Registers as a Cortex-M has them, so GDB doesn't assume a classic ARM layout
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>arm</architecture>
+<feature name="org.gnu.gdb.arm.m-profile">
+<reg name="r0" bitsize="32"/>
+<reg name="r1" bitsize="32"/>
+<reg name="r2" bitsize="32"/>
+<reg name="r3" bitsize="32"/>
+<reg name="r4" bitsize="32"/>
+<reg name="r5" bitsize="32"/>
+<reg name="r6" bitsize="32"/>
+<reg name="r7" bitsize="32"/>
+<reg name="r8" bitsize="32"/>
+<reg name="r9" bitsize="32"/>
+<reg name="r10" bitsize="32"/>
+<reg name="r11" bitsize="32"/>
+<reg name="r12" bitsize="32"/>
+<reg name="sp" bitsize="32" type="data_ptr"/>
+<reg name="lr" bitsize="32"/>
+<reg name="pc" bitsize="32" type="code_ptr"/>
+<reg name="xpsr" bitsize="32"/>
+</feature>
+</target>
+"#;
+/// Index of xPSR in `g` packets, after r0-r15
+const XPSR_IDX: usize = 16;
+/// Steps between looks for a ctrl-c from GDB while running
+const INTERRUPT_CHECK: u64 = 4096;
+
+/// Stop reply for running back into the start of history
+const HISTORY_BEGIN: &str = "T05replaylog:begin;";
+
+/// `$packet#checksum` as sent on the wire
+fn frame(packet: &str) -> String {
+    let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${packet}#{checksum:02x}")
+}
+
+/// Registers travel as little endian hex
+fn hex_word(word: AWord) -> String {
+    word.to_le_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+fn parse_hex_word(text: &str) -> Option<AWord> {
+    let bytes = parse_hex_bytes(text)?;
+    Some(AWord::from_le_bytes(bytes.try_into().ok()?))
+}
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok()).collect()
+}
+/// `addr,len` as found in memory and breakpoint packets
+fn parse_range(text: &str) -> Option<(AWord, AWord)> {
+    let (adr, len) = text.split_once(',')?;
+    Some((AWord::from_str_radix(adr, 16).ok()?, AWord::from_str_radix(len, 16).ok()?))
+}
+
+/// GDB remote serial protocol server, including reverse step and continue
+///
+/// Only one connection is served, the run ends when GDB detaches or kills the target.
+pub struct GdbStub {
+    pub emulator: Emulator,
+    history: History,
+    attached: bool,
+}
+
+impl GdbStub {
+    pub fn new(emulator: Emulator) -> Self {
+        Self {emulator, history: History::new(1000), attached: true}
+    }
+
+    /// Wait for GDB on `address`, eg `localhost:3333`, and serve it
+    pub fn serve(&mut self, address: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("Waiting for GDB, `target remote {}`", listener.local_addr()?);
+        let (mut stream, peer) = listener.accept()?;
+        log::info!("GDB connected from {peer}");
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        while let Some(packet) = receive(&mut reader)? {
+            log::debug!("GDB <- {packet}");
+            let probe = stream.try_clone()?;
+            let reply = self.handle(&packet, &mut || interrupted(&probe));
+            let Some(reply) = reply else { break };
+            log::debug!("GDB -> {reply}");
+            stream.write_all(frame(&reply).as_bytes())?;
+            if !self.attached {
+                break;
+            }
+        }
+        log::info!("GDB disconnected at cycle {}", self.emulator.cycles);
+        Ok(())
+    }
+
+    /// Answer a packet, without the framing. Returns nothing when GDB killed the target.
+    /// `interrupted` is polled while running and tells whether GDB asked to stop.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let reply = match packet {
+            "?" => "S05".to_string(),
+            "k" => return None,
+            "D" => {
+                self.attached = false;
+                "OK".to_string()
+            },
+            "g" => (0..=XPSR_IDX).map(|idx| hex_word(self.register(idx).unwrap())).collect(),
+            "c" => self.resume(interrupted),
+            "s" => {
+                let stop = self.history.step(&mut self.emulator);
+                self.stop_reply(stop)
+            },
+            "bs" => match self.history.step_back(&mut self.emulator) {
+                Ok(()) => "S05".to_string(),
+                Err(_) => HISTORY_BEGIN.to_string(),
+            },
+            "bc" => match self.history.reverse_continue(&mut self.emulator) {
+                Ok(Some(stop)) => self.stop_reply(Some(stop)),
+                Ok(None) | Err(_) => HISTORY_BEGIN.to_string(),
+            },
+            "qAttached" => "1".to_string(),
+            "qSymbol::" => "OK".to_string(),
+            _ if packet.starts_with("qSupported") =>
+                "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string(),
+            _ if packet.starts_with('H') => "OK".to_string(),
+            _ => self.handle_with_args(packet).unwrap_or_else(|| "E01".to_string()),
+        };
+        Some(reply)
+    }
+    /// Packets with arguments, nothing when they're malformed or refer to something missing
+    fn handle_with_args(&mut self, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at_checked(1)?;
+        let reply = match command {
+            "p" => hex_word(self.register(usize::from_str_radix(args, 16).ok()?)?),
+            "P" => {
+                let (idx, value) = args.split_once('=')?;
+                self.set_register(usize::from_str_radix(idx, 16).ok()?, parse_hex_word(value)?)?;
+                "OK".to_string()
+            },
+            "G" => {
+                let words = (0..=XPSR_IDX).map(|idx| parse_hex_word(args.get(idx * 8..idx * 8 + 8)?))
+                    .collect::<Option<Vec<_>>>()?;
+                for (idx, word) in words.into_iter().enumerate() {
+                    self.set_register(idx, word)?;
+                }
+                "OK".to_string()
+            },
+            "m" => {
+                let (adr, len) = parse_range(args)?;
+                // Stops short at the first unmapped byte
+                let mapped = (0..len).take_while(|&offset| self.is_mapped(adr.wrapping_add(offset))).count();
+                let mut memory = self.emulator.memory.debug_view();
+                let bytes: String = (0..mapped as AWord)
+                    .map(|offset| format!("{:02x}", memory.readb(adr.wrapping_add(offset))))
+                    .collect();
+                if bytes.is_empty() {
+                    return None;
+                }
+                bytes
+            },
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (adr, len) = parse_range(range)?;
+                let bytes = parse_hex_bytes(data)?;
+                if bytes.len() != len as usize || !(0..len).all(|offset| self.is_mapped(adr.wrapping_add(offset))) {
+                    return None;
+                }
+                let mut memory = self.emulator.memory.debug_view();
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    memory.writeb(adr.wrapping_add(offset as AWord), byte);
+                }
+                self.history.forget_from(self.emulator.cycles);
+                "OK".to_string()
+            },
+            "Z" | "z" => {
+                let (kind, range) = args.split_once(',')?;
+                let (adr, len) = parse_range(range)?;
+                let insert = command == "Z";
+                match kind {
+                    // Software and hardware breakpoints are the same here
+                    "0" | "1" => {
+                        self.emulator.breakpoints.retain(|&b| b != adr);
+                        if insert {
+                            self.emulator.breakpoints.push(adr);
+                        }
+                    },
+                    // Write watchpoints cover every byte in the range
+                    "2" => for adr in (0..len).map(|offset| adr.wrapping_add(offset)) {
+                        self.emulator.watchpoints.retain(|&w| w != adr);
+                        if insert {
+                            self.emulator.watchpoints.push(adr);
+                        }
+                    },
+                    // Read and access watchpoints aren't supported
+                    _ => return Some(String::new()),
+                }
+                "OK".to_string()
+            },
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let (offset, len) = parse_range(packet.trim_start_matches("qXfer:features:read:target.xml:"))?;
+                let rest = TARGET_XML.get(offset as usize..).unwrap_or_default();
+                match rest.get(..len as usize) {
+                    Some(part) if part.len() < rest.len() => format!("m{part}"),
+                    _ => format!("l{rest}"),
+                }
+            },
+            // An empty reply tells GDB the packet isn't supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Run until a stop or until GDB interrupts
+    fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        for steps in 1.. {
+            if let Some(stop) = self.history.step(&mut self.emulator) {
+                return self.stop_reply(Some(stop));
+            }
+            if steps % INTERRUPT_CHECK == 0 && interrupted() {
+                break;
+            }
+        }
+        // SIGINT
+        "S02".to_string()
+    }
+    fn stop_reply(&self, stop: Option<Stop>) -> String {
+        match stop {
+            Some(Stop::Watchpoint(adr)) => format!("T05watch:{adr:x};"),
+            Some(Stop::Fault(fault)) => {
+                log::error!("Bus fault at pc {:#010x}: {fault:?}", self.emulator.pc());
+                // SIGSEGV
+                "S0b".to_string()
+            },
+            Some(Stop::Error(err)) => {
+                log::error!("{err}");
+                // SIGABRT
+                "S06".to_string()
+            },
+            Some(Stop::Breakpoint(_)) | None => "S05".to_string(),
+        }
+    }
+
+    fn register(&self, idx: usize) -> Option<AWord> {
+        let cpu = &self.emulator.cpu;
+        match idx {
+            PC_IDX => Some(self.emulator.pc()),
+            XPSR_IDX => {
+                let flags = (cpu.n as AWord) << 31 | (cpu.z as AWord) << 30 | (cpu.c as AWord) << 29 | (cpu.v as AWord) << 28;
+                // Always in Thumb state
+                Some(flags | 1 << 24 | self.emulator.memory.context().borrow().nvic.active)
+            },
+            _ => cpu.r.get(idx).copied(),
+        }
+    }
+    fn set_register(&mut self, idx: usize, value: AWord) -> Option<()> {
+        let cpu = &mut self.emulator.cpu;
+        match idx {
+            // Keep the +2 convention of the PC
+            PC_IDX => cpu.r[PC_IDX] = value.wrapping_add(2),
+            XPSR_IDX => (cpu.n, cpu.z, cpu.c, cpu.v) = (value >> 31 & 1 != 0, value >> 30 & 1 != 0, value >> 29 & 1 != 0, value >> 28 & 1 != 0),
+            _ => *cpu.r.get_mut(idx)? = value,
+        }
+        self.history.forget_from(self.emulator.cycles);
+        Some(())
+    }
+    fn is_mapped(&self, adr: AWord) -> bool {
+        self.emulator.memory.regions().any(|region| region.contains(adr))
+    }
+}
+
+/// The next packet, skipping acknowledgements, nothing once GDB hung up
+fn receive(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<String>> {
+    let mut bytes = reader.bytes();
+    loop {
+        match bytes.next().transpose()? {
+            None => return Ok(None),
+            Some(b'$') => break,
+            // Acks, and ctrl-c while already stopped
+            Some(_) => {},
+        }
+    }
+    let mut packet = Vec::new();
+    loop {
+        match bytes.next().transpose()? {
+            None => return Ok(None),
+            Some(b'#') => break,
+            Some(byte) => packet.push(byte),
+        }
+    }
+    // The transport is reliable, the checksum is skipped rather than checked
+    for _ in 0..2 {
+        bytes.next().transpose()?;
+    }
+    reader.get_mut().write_all(b"+")?;
+    Ok(Some(String::from_utf8_lossy(&packet).into_owned()))
+}
+
+/// Whether GDB sent a ctrl-c, without waiting for one
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    let _ = stream.set_nonblocking(true);
+    let got = matches!(stream.peek(&mut byte), Ok(1)) && byte[0] == 0x03;
+    if got {
+        let _ = (&*stream).read_exact(&mut byte);
+    }
+    let _ = stream.set_nonblocking(false);
+    got
+}
+
+#[test]
+fn test_gdb_packets() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    // movs r0, #0; loop: adds r0, #1; strb r0, [r1]; b loop
+    let program = [0x00, 0x20, 0x01, 0x30, 0x08, 0x70, 0xfc, 0xe7];
+    let mut buffer = [0u8; 64];
+    buffer[..program.len()].copy_from_slice(&program);
+    let mut memory = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+    memory.add_region(MappedRegion {label: "flash".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut stub = GdbStub::new(Emulator::new(memory));
+    let mut send = |packet: &str| stub.handle(packet, &mut || false).unwrap();
+
+    assert_eq!(frame("OK"), "$OK#9a");
+    assert!(send("qSupported:multiprocess+;swbreak+").contains("ReverseContinue+"));
+    assert!(send("qXfer:features:read:target.xml:0,40").starts_with("m<?xml"));
+    assert_eq!(send("P1=20000000"), "OK");
+    assert_eq!(send("Z0,6,2"), "OK");
+    assert_eq!(send("c"), "S05");
+    assert_eq!(send("p f"), "E01");
+    assert_eq!(send("pf"), "06000000");
+    assert_eq!(send("m20,2"), "0100");
+    assert_eq!(send("m3e,4"), "0000");
+    assert_eq!(send("m40,4"), "E01");
+    assert_eq!(send("z0,6,2"), "OK");
+    assert_eq!(send("Z2,20,1"), "OK");
+    assert_eq!(send("c"), "T05watch:20;");
+    assert_eq!(send("m20,1"), "02");
+
+    // Back to the previous write, then to the start
+    assert_eq!(send("bs"), "S05");
+    assert_eq!(send("pf"), "04000000");
+    assert_eq!(send("bc"), "T05watch:20;");
+    assert_eq!(send("m20,1"), "01");
+    assert_eq!(send("bc"), HISTORY_BEGIN);
+    assert_eq!(&send("g")[15 * 8..], "0000000000000001");
+    assert_eq!(send("M20,1:ff"), "OK");
+    assert_eq!(send("m20,1"), "ff");
+    assert_eq!(send("X20,1:a"), "");
+    assert_eq!(send("D"), "OK");
+    assert!(!stub.attached);
+}
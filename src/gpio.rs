@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use crate::adr::AddressSpace;
+use crate::context::SharedContext;
+use crate::core::*;
+use crate::vcd::Probe;
+
+// Register offsets, a bit per pin in each
+const DIR: AWord = 0x00;
+/// Level of every pin, outputs read back what they drive
+const IN: AWord = 0x04;
+const OUT: AWord = 0x08;
+const SET: AWord = 0x0c;
+const CLR: AWord = 0x10;
+const TOGGLE: AWord = 0x14;
+/// Edges that set a flag
+const RISE: AWord = 0x18;
+const FALL: AWord = 0x1c;
+/// Edges seen, write 1 to clear
+const FLAGS: AWord = 0x20;
+const GPIO_LEN: AWord = 0x24;
+
+/// An input pin changing at a cycle, also the format of output logs: `<cycle> <pin> <level>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub cycle: u64,
+    pub pin: u32,
+    pub high: bool,
+}
+
+/// Pins of a GPIO region as other peripherals see them, eg a timer driving PWM onto some
+#[derive(Debug, Default)]
+pub struct Pins {
+    /// Pins taken over by another peripheral, whatever DIR says
+    pub driven: AWord,
+    /// Levels of the driven pins
+    pub drive: AWord,
+    /// Levels of every pin as of the last tick of the GPIO region
+    pub levels: AWord,
+}
+pub type SharedPins = Rc<RefCell<Pins>>;
+
+/// A line per event, `#` starts a comment
+pub fn parse_events(text: &str) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("Line {}: expected `<cycle> <pin> <0|1>`, found `{line}`", idx + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [cycle, pin, level] = fields[..] else { return Err(invalid()) };
+        let high = match level {
+            "0" => false,
+            "1" => true,
+            _ => return Err(invalid()),
+        };
+        events.push(Event {cycle: cycle.parse().map_err(|_| invalid())?, pin: pin.parse().map_err(|_| invalid())?, high});
+    }
+    Ok(events)
+}
+
+/// 32 pins with edge detection
+///
+/// Inputs follow a timeline of events, applied once the cycle count reaches them. Changes of the
+/// outputs, including pins driven by other peripherals through `pins`, are written to `log`, or
+/// logged at info level without one. The flags have no enables, any of them raises the interrupt.
+pub struct Gpio {
+    pub origin: AWord,
+    pub label: String,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    log: Option<Box<dyn Write>>,
+    pins: SharedPins,
+    /// Sorted by cycle
+    events: Vec<Event>,
+    /// First event not applied yet
+    next: usize,
+    dir: AWord,
+    out: AWord,
+    /// Levels driven from outside
+    inputs: AWord,
+    rise: AWord,
+    fall: AWord,
+    flags: AWord,
+    /// `pins.driven` and `pins.drive` as of the last tick
+    driven: AWord,
+    drive: AWord,
+}
+
+impl Gpio {
+    pub fn new(origin: AWord, label: String, context: SharedContext, irq: Option<u32>, mut events: Vec<Event>, log: Option<Box<dyn Write>>, pins: SharedPins) -> Result<Self, String> {
+        if let Some(event) = events.iter().find(|event| 32 <= event.pin) {
+            return Err(format!("Pin {} doesn't exist, there are 32", event.pin));
+        }
+        events.sort_by_key(|event| event.cycle);
+        Ok(Self {
+            origin, label, context, irq, log, pins, events,
+            next: 0, dir: 0, out: 0, inputs: 0, rise: 0, fall: 0, flags: 0, driven: 0, drive: 0,
+        })
+    }
+    pub fn levels(&self) -> AWord {
+        (self.out & self.dir | self.inputs & !self.dir) & !self.driven | self.drive & self.driven
+    }
+
+    /// Apply a change of the registers or inputs, flagging edges and logging outputs
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.levels();
+        change(self);
+        let after = self.levels();
+        self.flags |= after & !before & self.rise | before & !after & self.fall;
+
+        let changed = (after ^ before) & (self.dir | self.driven);
+        if changed == 0 {
+            return;
+        }
+        let cycle = self.context.borrow().cycles;
+        for pin in (0..32).filter(|pin| changed & 1 << pin != 0) {
+            let level = (after >> pin) & 1;
+            match &mut self.log {
+                Some(log) => {
+                    // Losing the log doesn't stop the firmware
+                    let _ = writeln!(log, "{cycle} {pin} {level}");
+                },
+                None => log::info!("{} pin {pin} = {level} at cycle {cycle}", self.label),
+            }
+        }
+    }
+}
+
+impl AddressSpace for Gpio {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {GPIO_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            DIR => self.dir,
+            IN => self.levels(),
+            OUT => self.out,
+            RISE => self.rise,
+            FALL => self.fall,
+            FLAGS => self.flags,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let bits = (x as AWord) << shift;
+        let lane = |word: AWord| word & !(0xff << shift) | bits;
+        match adr & !3 {
+            DIR => self.update(|gpio| gpio.dir = lane(gpio.dir)),
+            OUT => self.update(|gpio| gpio.out = lane(gpio.out)),
+            SET => self.update(|gpio| gpio.out |= bits),
+            CLR => self.update(|gpio| gpio.out &= !bits),
+            TOGGLE => self.update(|gpio| gpio.out ^= bits),
+            RISE => self.rise = lane(self.rise),
+            FALL => self.fall = lane(self.fall),
+            FLAGS => self.flags &= !bits,
+            _ => {},
+        }
+    }
+    fn tick(&mut self, _cycles: u64) {
+        let now = self.context.borrow().cycles;
+        let due = self.events[self.next..].iter().take_while(|event| event.cycle <= now).count();
+        if due != 0 {
+            let events = &self.events[self.next..self.next + due];
+            let inputs = events.iter().fold(self.inputs, |inputs, event| match event.high {
+                true => inputs | 1 << event.pin,
+                false => inputs & !(1 << event.pin),
+            });
+            self.next += due;
+            self.update(|gpio| gpio.inputs = inputs);
+        }
+        let (driven, drive) = {
+            let pins = self.pins.borrow();
+            (pins.driven, pins.drive)
+        };
+        if (driven, drive) != (self.driven, self.drive) {
+            self.update(|gpio| (gpio.driven, gpio.drive) = (driven, drive));
+        }
+        self.pins.borrow_mut().levels = self.levels();
+        if let Some(irq) = self.irq && self.flags != 0 {
+            self.context.borrow_mut().nvic.raise(irq);
+        }
+    }
+    fn probe(&self, probe: &mut Probe) {
+        let levels = self.levels();
+        for pin in 0..32 {
+            probe.signal(format_args!("pin{pin}"), 1, (levels >> pin & 1) as u64);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for word in [self.dir, self.out, self.inputs, self.rise, self.fall, self.flags, self.driven, self.drive, self.next as AWord] {
+            out.extend(word.to_le_bytes());
+        }
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 36 {
+            return Err("Invalid GPIO state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[idx * 4..idx * 4 + 4].try_into().unwrap());
+        (self.dir, self.out, self.inputs, self.rise, self.fall, self.flags) = (word(0), word(1), word(2), word(3), word(4), word(5));
+        (self.driven, self.drive) = (word(6), word(7));
+        self.next = (word(8) as usize).min(self.events.len());
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_gpio() {
+    let events = parse_events("# button on pin 3\n10 3 1\n20 3 0 # released\n").unwrap();
+    assert!(parse_events("10 3 high").is_err());
+    let path = std::env::temp_dir().join("cm0-gpio-test.log");
+    let log = Box::new(std::fs::File::create(&path).unwrap());
+    let context = SharedContext::default();
+    let mut gpio = Gpio::new(0x5000_0000, "gpio".to_string(), context.clone(), Some(2), events, Some(log), SharedPins::default()).unwrap();
+
+    gpio.write_w_le(FALL, 1 << 3);
+    gpio.write_w_le(DIR, 1 << 5);
+    context.borrow_mut().cycles = 7;
+    gpio.write_w_le(SET, 1 << 5);
+    gpio.write_w_le(TOGGLE, 1 << 5 | 1 << 3);
+    assert_eq!(gpio.read_w_le(OUT), 1 << 3);
+    // Only outputs show up on IN
+    assert_eq!(gpio.read_w_le(IN), 0);
+
+    context.borrow_mut().cycles = 10;
+    gpio.tick(1);
+    assert_eq!(gpio.read_w_le(IN), 1 << 3);
+    assert_eq!(gpio.read_w_le(FLAGS), 0);
+    context.borrow_mut().cycles = 25;
+    gpio.tick(1);
+    assert_eq!(gpio.read_w_le(FLAGS), 1 << 3);
+    assert_eq!(context.borrow().nvic.pending, 1 << 2);
+    gpio.write_w_le(FLAGS, 1 << 3);
+    assert_eq!(gpio.read_w_le(FLAGS), 0);
+
+    gpio.flush().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "7 5 1\n7 5 0\n");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use crate::adr::AddressSpace;
+use crate::config::ConfigError;
+use crate::core::{parse_word, AWord};
+use crate::emulator::Emulator;
+use crate::registers::{register_index, PC_IDX};
+use crate::scripting::Script;
+use crate::symbols::Symbols;
+
+const NAMES: [&str; 5] = ["before_step", "after_step", "on_address", "on_exception", "on_exit"];
+
+/// Lua functions from the `hooks` table of the config, called by the run loop
+///
+/// Each gets the emulator as its first argument, step hooks stop the run by returning `false`.
+#[derive(Default)]
+pub struct Hooks {
+    script: Option<Script>,
+    before_step: Option<mlua::Function>,
+    after_step: Option<mlua::Function>,
+    on_exception: Option<mlua::Function>,
+    on_exit: Option<mlua::Function>,
+    /// Keys of `on_address` as written, addresses or symbols, until `resolve`
+    targets: Vec<(String, mlua::Function)>,
+    on_address: HashMap<AWord, mlua::Function>,
+}
+
+impl Hooks {
+    pub fn load(script: &Script, table: mlua::Table) -> Result<Self, ConfigError> {
+        let invalid = |hook: &str, reason: String| ConfigError::Hook { hook: hook.to_string(), reason };
+        let mut hooks = Self {script: Some(script.clone()), ..Default::default()};
+        for pair in table.pairs::<String, mlua::Value>() {
+            let (name, value) = pair?;
+            if name == "on_address" {
+                let found = value.type_name();
+                let mlua::Value::Table(targets) = value else {
+                    return Err(invalid(&name, format!("should be a table of functions, found {found}")));
+                };
+                for pair in targets.pairs::<mlua::Value, mlua::Value>() {
+                    let (key, value) = pair?;
+                    let key = match key {
+                        mlua::Value::Integer(adr) => adr.to_string(),
+                        key => key.to_string()?,
+                    };
+                    let found = value.type_name();
+                    let mlua::Value::Function(function) = value else {
+                        return Err(invalid(&name, format!("`{key}` should be a function, found {found}")));
+                    };
+                    hooks.targets.push((key, function));
+                }
+                continue;
+            }
+            let slot = match name.as_str() {
+                "before_step" => &mut hooks.before_step,
+                "after_step" => &mut hooks.after_step,
+                "on_exception" => &mut hooks.on_exception,
+                "on_exit" => &mut hooks.on_exit,
+                _ => return Err(invalid(&name, format!("is not one of {}", NAMES.join(", ")))),
+            };
+            let found = value.type_name();
+            let mlua::Value::Function(function) = value else {
+                return Err(invalid(&name, format!("should be a function, found {found}")));
+            };
+            *slot = Some(function);
+        }
+        Ok(hooks)
+    }
+
+    /// Turn the keys of `on_address` into addresses
+    pub fn resolve(&mut self, symbols: &Symbols) -> Result<(), String> {
+        for (key, function) in self.targets.drain(..) {
+            let adr = parse_word(&key).or_else(|| symbols.lookup(&key))
+                .ok_or(format!("Unknown address or symbol {key} in `on_address`"))?;
+            self.on_address.insert(adr, function);
+        }
+        Ok(())
+    }
+
+    /// Before each instruction, `on_address` for the one about to execute then `before_step`.
+    /// Returns whether to go on.
+    pub fn before_step(&self, emulator: &mut Emulator) -> mlua::Result<bool> {
+        if let Some(function) = self.on_address.get(&emulator.pc())
+            && !self.call_step(function, emulator, ())? {
+            return Ok(false);
+        }
+        match &self.before_step {
+            Some(function) => self.call_step(function, emulator, ()),
+            None => Ok(true),
+        }
+    }
+    /// After each instruction, `on_exception(emu, number)` when one was entered then `after_step`
+    pub fn after_step(&self, emulator: &mut Emulator) -> mlua::Result<bool> {
+        if let (Some(function), Some(number)) = (&self.on_exception, emulator.exception)
+            && !self.call_step(function, emulator, number)? {
+            return Ok(false);
+        }
+        match &self.after_step {
+            Some(function) => self.call_step(function, emulator, ()),
+            None => Ok(true),
+        }
+    }
+    /// `on_exit(emu, reason)`, may return the exit code of the emulator
+    pub fn on_exit(&self, emulator: &mut Emulator, reason: &str) -> mlua::Result<Option<i32>> {
+        match &self.on_exit {
+            Some(function) => self.call(function, emulator, reason),
+            None => Ok(None),
+        }
+    }
+
+    fn call_step(&self, function: &mlua::Function, emulator: &mut Emulator, args: impl mlua::IntoLuaMulti) -> mlua::Result<bool> {
+        let go_on: Option<bool> = self.call(function, emulator, args)?;
+        Ok(go_on != Some(false))
+    }
+    /// The emulator is only reachable from Lua for the duration of the call
+    fn call<R: mlua::FromLuaMulti>(&self, function: &mlua::Function, emulator: &mut Emulator, args: impl mlua::IntoLuaMulti) -> mlua::Result<R> {
+        let script = self.script.as_ref().expect("Hooks are loaded with their Lua state");
+        script.lua.scope(|scope| {
+            let mut args = args.into_lua_multi(&script.lua)?;
+            args.push_front(mlua::Value::UserData(scope.create_userdata_ref_mut(emulator)?));
+            script.call(function, args)
+        })
+    }
+}
+
+fn register(name: &str) -> mlua::Result<usize> {
+    register_index(name).ok_or_else(|| mlua::Error::runtime(format!("Unknown register {name}")))
+}
+fn aligned(adr: AWord, size: AWord) -> mlua::Result<AWord> {
+    match adr.is_multiple_of(size) {
+        true => Ok(adr),
+        false => Err(mlua::Error::runtime(format!("{adr:#x} is not aligned to {size} bytes"))),
+    }
+}
+
+/// Raise an error a func region left behind during the access, rather than stopping the next step
+/// on it
+fn checked<T>(emulator: &Emulator, value: T) -> mlua::Result<T> {
+    match emulator.memory.context().borrow_mut().error.take() {
+        Some(error) => Err(mlua::Error::runtime(error)),
+        None => Ok(value),
+    }
+}
+
+/// `emu:reg("r0")`, `emu:set_reg("pc", 0x100)`, `emu:read32(adr)`, `emu:write8(adr, x)`, ...
+/// Memory access ignores permissions like a debugger.
+impl mlua::UserData for Emulator {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("pc", |_, emu, ()| Ok(emu.pc()));
+        methods.add_method("cycles", |_, emu, ()| Ok(emu.cycles));
+        methods.add_method("reg", |_, emu, name: String| Ok(match register(&name)? {
+            PC_IDX => emu.pc(),
+            idx => emu.cpu.r[idx],
+        }));
+        methods.add_method_mut("set_reg", |_, emu, (name, value): (String, AWord)| {
+            let idx = register(&name)?;
+            // Keep the +2 convention of the PC
+            emu.cpu.r[idx] = if idx == PC_IDX {value.wrapping_add(2)} else {value};
+            Ok(())
+        });
+        methods.add_method_mut("read8", |_, emu, adr: AWord| {
+            let x = emu.memory.debug_view().readb(adr);
+            checked(emu, x)
+        });
+        methods.add_method_mut("read16", |_, emu, adr: AWord| {
+            let x = emu.memory.debug_view().read_hw(aligned(adr, 2)?);
+            checked(emu, x)
+        });
+        methods.add_method_mut("read32", |_, emu, adr: AWord| {
+            let x = emu.memory.debug_view().read_w(aligned(adr, 4)?);
+            checked(emu, x)
+        });
+        methods.add_method_mut("write8", |_, emu, (adr, x): (AWord, u8)| {
+            emu.memory.debug_view().writeb(adr, x);
+            checked(emu, ())
+        });
+        methods.add_method_mut("write16", |_, emu, (adr, x): (AWord, u16)| {
+            emu.memory.debug_view().write_hw(aligned(adr, 2)?, x);
+            checked(emu, ())
+        });
+        methods.add_method_mut("write32", |_, emu, (adr, x): (AWord, AWord)| {
+            emu.memory.debug_view().write_w(aligned(adr, 4)?, x);
+            checked(emu, ())
+        });
+    }
+}
+
+#[test]
+fn test_hooks() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    // movs r0, #0; loop: adds r0, #1; strb r0, [r1]; b loop
+    let program = [0x00, 0x20, 0x01, 0x30, 0x08, 0x70, 0xfc, 0xe7];
+    let mut buffer = [0u8; 64];
+    buffer[..program.len()].copy_from_slice(&program);
+    let mut memory = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+    memory.add_region(MappedRegion {label: "ram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut emulator = Emulator::new(memory);
+
+    let script = Script::new(&Default::default()).unwrap();
+    let lua = &script.lua;
+    let table = lua.load(r#"{
+        before_step = function(emu) if emu:cycles() == 0 then emu:set_reg("r1", 32) end end,
+        on_address = { [4] = function(emu) stores = (stores or 0) + 1 end },
+        after_step = function(emu) return emu:reg("r0") ~= 3 end,
+        on_exit = function(emu, reason)
+            emu:write32(36, 0xdeadbeef)
+            return (reason == "hook" and emu:read8(32) == 2 and emu:read16(36) == 0xbeef) and 7 or 1
+        end,
+    }"#).eval().unwrap();
+    let mut hooks = Hooks::load(&script, table).unwrap();
+    hooks.resolve(&Symbols::default()).unwrap();
+
+    while hooks.before_step(&mut emulator).unwrap() {
+        emulator.step();
+        if !hooks.after_step(&mut emulator).unwrap() {
+            break;
+        }
+    }
+    assert_eq!(lua.globals().get::<u32>("stores").unwrap(), 2);
+    assert_eq!(hooks.on_exit(&mut emulator, "hook").unwrap(), Some(7));
+
+    let table = lua.load("{ on_address = { nowhere = function() end } }").eval().unwrap();
+    assert!(Hooks::load(&script, table).unwrap().resolve(&Symbols::default()).is_err());
+    let table = lua.load("{ on_step = function() end }").eval().unwrap();
+    assert!(Hooks::load(&script, table).is_err());
+}
@@ -0,0 +1,456 @@
+use crate::adr::{restore_devices, save_devices, AddressSpace, DeviceState};
+use crate::context::SharedContext;
+use crate::core::*;
+use crate::memory::{BufferMemory, PersistentMemory};
+use crate::snapshot::StateReader;
+
+// Register offsets
+const DATA: AWord = 0x00;
+/// Written to run a command, see `CMD_START` and the rest
+const CMD: AWord = 0x04;
+const STATUS: AWord = 0x08;
+const CTRL: AWord = 0x0c;
+/// Cycles per bit, 0 runs commands without delay
+const DIV: AWord = 0x10;
+const I2C_LEN: AWord = 0x14;
+
+// Parts of a command, carried out in this order
+/// Start condition, or a repeated start during a transaction
+const CMD_START: AWord = 1 << 0;
+/// Send DATA, after a start it's the address and direction
+const CMD_WRITE: AWord = 1 << 1;
+/// Receive a byte into DATA
+const CMD_READ: AWord = 1 << 2;
+const CMD_STOP: AWord = 1 << 3;
+/// Answer the byte read with a NACK instead of an ACK
+const CMD_NACK: AWord = 1 << 4;
+
+const STATUS_BUSY: AWord = 1 << 0;
+/// A command finished, write 1 to clear
+const STATUS_DONE: AWord = 1 << 1;
+/// The last byte sent wasn't acknowledged
+const STATUS_NACK: AWord = 1 << 2;
+/// Between a start and a stop condition
+const STATUS_BUS: AWord = 1 << 3;
+/// Interrupt while DONE is set
+const CTRL_IE: AWord = 1 << 0;
+
+/// A device on the bus, eg an EEPROM or a sensor
+pub trait I2cDevice: DeviceState {
+    /// A start condition was followed by `address`, 7 bits, returns whether the device answers
+    /// to it
+    fn start(&mut self, address: u8, read: bool) -> bool;
+    /// Take a byte written by the controller, returns the ACK
+    fn write(&mut self, byte: u8) -> bool;
+    /// A byte for the controller, `ack` is what it answers, a NACK ends reading
+    fn read(&mut self, ack: bool) -> u8;
+    /// The transaction ended with a stop condition
+    fn stop(&mut self) {}
+}
+
+/// A 24Cxx serial EEPROM, from a 128 byte 24C01 to a 64KiB 24C512
+///
+/// Up to 2KiB the word address is one byte and the 256 byte blocks answer to consecutive device
+/// addresses, bigger parts take two address bytes. Writes wrap around within a page and take no
+/// time.
+pub struct Eeprom {
+    address: u8,
+    storage: Box<dyn AddressSpace>,
+    page_size: AWord,
+    /// Next byte to read or write
+    pointer: AWord,
+    /// Bytes written since the device was addressed
+    position: usize,
+}
+
+impl Eeprom {
+    /// Kept in `path` if given, created erased when missing, otherwise erased on every start
+    pub fn new(address: u8, len: AWord, page_size: Option<AWord>, path: Option<String>) -> Result<Self, String> {
+        if !len.is_power_of_two() || !(128..=0x10000).contains(&len) {
+            return Err(format!("A 24Cxx EEPROM has a power of 2 size from 128 bytes to 64KiB, not {len:#x}"));
+        }
+        let page_size = page_size.unwrap_or(match len {
+            ..=0x100 => 8,
+            0x101..=0x800 => 16,
+            0x801..=0x2000 => 32,
+            0x2001..=0x8000 => 64,
+            _ => 128,
+        });
+        if !page_size.is_power_of_two() || len < page_size {
+            return Err(format!("Invalid page size {page_size}"));
+        }
+        let storage: Box<dyn AddressSpace> = match path {
+            Some(path) => Box::new(PersistentMemory::open(0, len, path.clone(), 0xff)
+                .map_err(|err| format!("Failed to open {path}: {err}"))?),
+            None => Box::new(BufferMemory {origin: 0, buffer: vec![0xff; len as usize].into_boxed_slice()}),
+        };
+        Ok(Self {address, storage, page_size, pointer: 0, position: 0})
+    }
+    fn len(&self) -> AWord {self.storage.len()}
+    fn address_bytes(&self) -> usize {
+        if self.len() <= 2048 {1} else {2}
+    }
+}
+
+impl I2cDevice for Eeprom {
+    fn start(&mut self, address: u8, read: bool) -> bool {
+        let blocks = if self.address_bytes() == 1 {self.len().div_ceil(256)} else {1};
+        let block = address.wrapping_sub(self.address) as AWord;
+        if blocks <= block {
+            return false;
+        }
+        if !read {
+            self.position = 0;
+            if self.address_bytes() == 1 {
+                self.pointer = (block << 8 | self.pointer & 0xff) & (self.len() - 1);
+            }
+        }
+        true
+    }
+    fn write(&mut self, byte: u8) -> bool {
+        if self.position < self.address_bytes() {
+            self.position += 1;
+            let high = if self.address_bytes() == 1 {self.pointer & !0xff} else {self.pointer << 8};
+            self.pointer = (high | byte as AWord) & (self.len() - 1);
+            return true;
+        }
+        self.storage.writeb(self.pointer, byte);
+        self.pointer = self.pointer & !(self.page_size - 1) | (self.pointer + 1) & (self.page_size - 1);
+        true
+    }
+    fn read(&mut self, _ack: bool) -> u8 {
+        let byte = self.storage.readb(self.pointer);
+        self.pointer = (self.pointer + 1) & (self.len() - 1);
+        byte
+    }
+}
+
+impl DeviceState for Eeprom {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(self.pointer.to_le_bytes());
+        out.extend((self.position as AWord).to_le_bytes());
+        self.storage.save_state(out);
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() < 8 {
+            return Err("Invalid EEPROM state".into());
+        }
+        self.pointer = AWord::from_le_bytes(state[..4].try_into().unwrap());
+        self.position = AWord::from_le_bytes(state[4..8].try_into().unwrap()) as usize;
+        self.storage.restore_state(&state[8..])
+    }
+    fn non_volatile(&self) -> bool {true}
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.storage.flush()
+    }
+}
+
+/// Given the cycle count, returns registers of a sensor to change and their new values
+pub type UpdateFn = Box<dyn FnMut(u64) -> Vec<(u8, u8)>>;
+
+/// A device with 256 byte registers, eg a temperature sensor
+///
+/// The first byte written selects a register, following bytes write it and the next ones, reads
+/// continue from the selected register. `update` runs whenever the sensor is addressed, so its
+/// values can follow the time of the emulator.
+pub struct Sensor {
+    address: u8,
+    context: SharedContext,
+    update: Option<UpdateFn>,
+    registers: [u8; 256],
+    pointer: u8,
+    /// Bytes written since the device was addressed
+    position: usize,
+}
+
+impl Sensor {
+    pub fn new(address: u8, context: SharedContext, initial: &[u8], update: Option<UpdateFn>) -> Result<Self, String> {
+        if 256 < initial.len() {
+            return Err(format!("{} values for 256 registers", initial.len()));
+        }
+        let mut registers = [0; 256];
+        registers[..initial.len()].copy_from_slice(initial);
+        Ok(Self {address, context, update, registers, pointer: 0, position: 0})
+    }
+}
+
+impl I2cDevice for Sensor {
+    fn start(&mut self, address: u8, read: bool) -> bool {
+        if address != self.address {
+            return false;
+        }
+        if let Some(update) = &mut self.update {
+            let cycles = self.context.borrow().cycles;
+            for (register, value) in update(cycles) {
+                self.registers[register as usize] = value;
+            }
+        }
+        if !read {
+            self.position = 0;
+        }
+        true
+    }
+    fn write(&mut self, byte: u8) -> bool {
+        if self.position == 0 {
+            self.pointer = byte;
+        } else {
+            self.registers[self.pointer as usize] = byte;
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+        self.position += 1;
+        true
+    }
+    fn read(&mut self, _ack: bool) -> u8 {
+        let value = self.registers[self.pointer as usize];
+        self.pointer = self.pointer.wrapping_add(1);
+        value
+    }
+}
+
+impl DeviceState for Sensor {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(self.registers);
+        out.push(self.pointer);
+        out.extend((self.position as AWord).to_le_bytes());
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 256 + 1 + 4 {
+            return Err("Invalid sensor state".into());
+        }
+        self.registers.copy_from_slice(&state[..256]);
+        self.pointer = state[256];
+        self.position = AWord::from_le_bytes(state[257..].try_into().unwrap()) as usize;
+        Ok(())
+    }
+}
+
+/// Where the controller is in a transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bus {
+    Idle,
+    /// After a start condition, the next byte is an address
+    Started,
+    /// No device answered the address
+    Ignored,
+    Addressed(usize),
+}
+
+/// An I2C controller running one command at a time
+///
+/// A command written to CMD can combine a start condition, sending or receiving a byte and a
+/// stop condition, eg `START | WRITE` with the address in DATA. It takes a bit time for each
+/// condition and 9 for a byte with its ACK, BUSY is set meanwhile and DONE afterwards. DONE is
+/// the interrupt flag.
+pub struct I2c {
+    pub origin: AWord,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    devices: Vec<Box<dyn I2cDevice>>,
+    data: u8,
+    ctrl: AWord,
+    div: AWord,
+    /// Command being carried out, 0 when idle
+    cmd: AWord,
+    elapsed: u64,
+    done: bool,
+    nack: bool,
+    bus: Bus,
+}
+
+impl I2c {
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, devices: Vec<Box<dyn I2cDevice>>) -> Self {
+        Self {
+            origin, context, irq, devices,
+            data: 0, ctrl: 0, div: 0, cmd: 0, elapsed: 0, done: false, nack: false, bus: Bus::Idle,
+        }
+    }
+    fn status(&self) -> AWord {
+        let flag = |set: bool, bit: AWord| if set {bit} else {0};
+        flag(self.cmd != 0, STATUS_BUSY) | flag(self.done, STATUS_DONE) | flag(self.nack, STATUS_NACK)
+            | flag(self.bus != Bus::Idle, STATUS_BUS)
+    }
+    fn duration(&self) -> u64 {
+        let bits = [(CMD_START, 1), (CMD_WRITE, 9), (CMD_READ, 9), (CMD_STOP, 1)].iter()
+            .filter(|(part, _)| self.cmd & part != 0)
+            .map(|(_, bits)| bits)
+            .sum::<u64>();
+        bits * self.div as u64
+    }
+    fn execute(&mut self) {
+        if self.cmd & CMD_START != 0 {
+            self.bus = Bus::Started;
+        }
+        if self.cmd & CMD_WRITE != 0 {
+            self.nack = match self.bus {
+                Bus::Started => {
+                    let (address, read) = (self.data >> 1, self.data & 1 != 0);
+                    match self.devices.iter_mut().position(|device| device.start(address, read)) {
+                        Some(idx) => self.bus = Bus::Addressed(idx),
+                        None => self.bus = Bus::Ignored,
+                    }
+                    self.bus == Bus::Ignored
+                },
+                Bus::Addressed(idx) => !self.devices[idx].write(self.data),
+                Bus::Idle | Bus::Ignored => true,
+            };
+        }
+        if self.cmd & CMD_READ != 0 {
+            self.data = match self.bus {
+                Bus::Addressed(idx) => self.devices[idx].read(self.cmd & CMD_NACK == 0),
+                // Nothing pulls SDA low
+                _ => 0xff,
+            };
+        }
+        if self.cmd & CMD_STOP != 0 {
+            if let Bus::Addressed(idx) = self.bus {
+                self.devices[idx].stop();
+            }
+            self.bus = Bus::Idle;
+        }
+        self.done = true;
+    }
+    /// Everything but non volatile devices when `volatile`, see `AddressSpace::save_volatile`
+    fn save(&self, out: &mut Vec<u8>, volatile: bool) {
+        let bus = match self.bus {
+            Bus::Idle => 0,
+            Bus::Started => 1,
+            Bus::Ignored => 2,
+            Bus::Addressed(idx) => 3 + idx as AWord,
+        };
+        for word in [self.data as AWord, self.ctrl, self.div, self.cmd, self.done as AWord, self.nack as AWord, bus] {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(self.elapsed.to_le_bytes());
+        save_devices(self.devices.iter().map(|device| device.as_ref() as &dyn DeviceState), out, volatile);
+    }
+    fn restore(&mut self, state: &[u8], volatile: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let invalid = "Invalid I2C state";
+        let mut reader = StateReader::new(state, invalid);
+        let registers: Vec<AWord> = (0..7).map(|_| reader.word()).collect::<Result<_, _>>()?;
+        let elapsed = reader.u64()?;
+        restore_devices(self.devices.iter_mut().map(|device| device.as_mut() as &mut dyn DeviceState), &mut reader, volatile)?;
+        let bus = match registers[6] {
+            0 => Bus::Idle,
+            1 => Bus::Started,
+            2 => Bus::Ignored,
+            idx if ((idx - 3) as usize) < self.devices.len() => Bus::Addressed((idx - 3) as usize),
+            _ => return Err(invalid.into()),
+        };
+        reader.finish()?;
+        (self.data, self.ctrl, self.div, self.cmd) = (registers[0] as u8, registers[1], registers[2], registers[3]);
+        (self.done, self.nack, self.bus, self.elapsed) = (registers[4] != 0, registers[5] != 0, bus, elapsed);
+        Ok(())
+    }
+}
+
+impl AddressSpace for I2c {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {I2C_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            DATA => self.data as AWord,
+            CMD => self.cmd,
+            STATUS => self.status(),
+            CTRL => self.ctrl,
+            DIV => self.div,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let lane = |word: AWord| word & !(0xff << shift) | (x as AWord) << shift;
+        match adr & !3 {
+            DATA if adr == DATA => self.data = x,
+            // Ignored while busy
+            CMD if adr == CMD && self.cmd == 0 => {
+                self.cmd = x as AWord & (CMD_START | CMD_WRITE | CMD_READ | CMD_STOP | CMD_NACK);
+                self.elapsed = 0;
+            },
+            STATUS if (x as AWord) << shift & STATUS_DONE != 0 => self.done = false,
+            CTRL => self.ctrl = lane(self.ctrl),
+            DIV => self.div = lane(self.div),
+            _ => {},
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        if self.cmd != 0 {
+            self.elapsed += cycles;
+            if self.duration() <= self.elapsed {
+                self.execute();
+                self.cmd = 0;
+            }
+        }
+        if let Some(irq) = self.irq && self.done && self.ctrl & CTRL_IE != 0 {
+            self.context.borrow_mut().nvic.raise(irq);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {self.save(out, false)}
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore(state, false)
+    }
+    fn save_volatile(&self, out: &mut Vec<u8>) {self.save(out, true)}
+    fn restore_volatile(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore(state, true)
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for device in self.devices.iter_mut() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_i2c() {
+    let context = SharedContext::default();
+    let eeprom = Eeprom::new(0x50, 1024, None, None).unwrap();
+    assert!(Eeprom::new(0x50, 1000, None, None).is_err());
+    let update: UpdateFn = Box::new(|cycles| vec![(0, (cycles / 10) as u8)]);
+    let sensor = Sensor::new(0x48, context.clone(), &[0, 0x42], Some(update)).unwrap();
+    let mut i2c = I2c::new(0, context.clone(), Some(7), vec![Box::new(eeprom), Box::new(sensor)]);
+    let mut command = |cmd: AWord, data: u8| -> (u8, AWord) {
+        i2c.writeb(DATA, data);
+        i2c.writeb(CMD, cmd as u8);
+        i2c.tick(1);
+        (i2c.readb(DATA), i2c.read_w_le(STATUS))
+    };
+
+    // Write a page of the second 256 byte block, wrapping around within it
+    assert_eq!(command(CMD_START | CMD_WRITE, 0x51 << 1), (0xa2, STATUS_DONE | STATUS_BUS));
+    command(CMD_WRITE, 0x0e);
+    for byte in [1, 2, 3] {
+        assert_eq!(command(CMD_WRITE, byte).1 & STATUS_NACK, 0);
+    }
+    command(CMD_STOP, 0);
+    // Random read: set the address, then read after a repeated start
+    command(CMD_START | CMD_WRITE, 0x51 << 1);
+    command(CMD_WRITE, 0x00);
+    command(CMD_START | CMD_WRITE, 0x51 << 1 | 1);
+    assert_eq!(command(CMD_READ, 0).0, 3);
+    assert_eq!(command(CMD_READ | CMD_NACK | CMD_STOP, 0), (0xff, STATUS_DONE));
+
+    // Nobody at 0x20
+    assert_eq!(command(CMD_START | CMD_WRITE, 0x20 << 1).1, STATUS_DONE | STATUS_NACK | STATUS_BUS);
+    command(CMD_STOP, 0);
+
+    // The sensor updates register 0 when addressed
+    context.borrow_mut().cycles = 250;
+    command(CMD_START | CMD_WRITE, 0x48 << 1);
+    command(CMD_WRITE, 0);
+    command(CMD_START | CMD_WRITE, 0x48 << 1 | 1);
+    assert_eq!(command(CMD_READ, 0).0, 25);
+    assert_eq!(command(CMD_READ | CMD_NACK | CMD_STOP, 0).0, 0x42);
+
+    // A byte takes 9 bits
+    i2c.write_w_le(DIV, 3);
+    i2c.write_w_le(CTRL, CTRL_IE);
+    i2c.write_w_le(STATUS, STATUS_DONE);
+    i2c.writeb(DATA, 0x50 << 1);
+    i2c.writeb(CMD, (CMD_START | CMD_WRITE) as u8);
+    i2c.tick(29);
+    assert_eq!(i2c.read_w_le(STATUS), STATUS_BUSY);
+    i2c.tick(1);
+    assert_eq!(i2c.read_w_le(STATUS), STATUS_DONE | STATUS_BUS);
+    assert_eq!(context.borrow().nvic.pending, 1 << 7);
+}
@@ -35,6 +35,10 @@ impl LoaderExecuter {
         let ins_type = InsType{name, is_me, execute};
         self.instruction_types.push(ins_type);
     }
+    /// Name of the matching instruction type, if any
+    pub fn identify(&self, instruction: &InsData) -> Option<&'static str> {
+        self.instruction_types.iter().find(|ins| (ins.is_me)(instruction)).map(|ins| ins.name)
+    }
     pub fn execute(&self, instruction: &InsData, regs: &mut registers::Registers, memory: &mut dyn AddressSpace) {
         let instruction_type = self.instruction_types.iter().find(|ins| (ins.is_me)(instruction))
             .unwrap_or(&InsType { name: "_INVALID(Skipped)", is_me: |_|true, execute: |_, _, _| {}});
@@ -205,7 +205,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
         |ins| {
             let t1 = ins.is_t1() && (ins.hdr.idx(12, 4) == 0b1101);
             let t2 = ins.is_t1() && (ins.hdr.idx(11, 5) == 0b11100);
-            return t1 || t2;
+            t1 || t2
         },
         |ins, cpu, _| {
             let t1 = ins.is_t1() && (ins.hdr.idx(12, 4) == 0b1101);
@@ -214,20 +214,20 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
                 let cond = ins.hdr.idx(8, 4) as usize;
                 let should_branch = match cond {
                     0b1110 => true,
-                    0b0000 => cpu.z == true,
-                    0b0001 => cpu.z == false,
-                    0b0010 => cpu.c == true,
-                    0b0011 => cpu.c == false,
-                    0b0100 => cpu.n == true,
-                    0b0101 => cpu.n == false,
-                    0b0110 => cpu.v == true,
-                    0b0111 => cpu.v == false,
-                    0b1000 => cpu.c == true && cpu.z == false,
-                    0b1001 => cpu.v == false && cpu.z == true,
+                    0b0000 => cpu.z,
+                    0b0001 => !cpu.z,
+                    0b0010 => cpu.c,
+                    0b0011 => !cpu.c,
+                    0b0100 => cpu.n,
+                    0b0101 => !cpu.n,
+                    0b0110 => cpu.v,
+                    0b0111 => !cpu.v,
+                    0b1000 => cpu.c && !cpu.z,
+                    0b1001 => !cpu.v && cpu.z,
                     0b1010 => cpu.n == cpu.v,
                     0b1011 => cpu.n != cpu.v,
-                    0b1100 => cpu.z == false && cpu.n == cpu.v,
-                    0b1101 => cpu.z == true && cpu.z != cpu.v,
+                    0b1100 => !cpu.z && cpu.n == cpu.v,
+                    0b1101 => cpu.z && cpu.z != cpu.v,
                     _ => false
                 };
                 if should_branch {
@@ -237,7 +237,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             else {
                 // Don't touch the next lines unless kyou know what you are doing
                 let imd11: i16 = ins.hdr.idx(0, 11) as i16;
-                let imd11_sign_ext: i16 = ((imd11 << 5) >> 5) as i16;
+                let imd11_sign_ext: i16 = (imd11 << 5) >> 5;
                 let imdoff: i32 = (imd11_sign_ext as i32) << 1;
                 cpu.r[PC_IDX] = cpu.r[PC_IDX].wrapping_add(imdoff as AWord).wrapping_add(2);
             }
@@ -272,7 +272,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(11, 5) == 0b11110;
             let second_part_good = ins.ext.unwrap().idx(14, 2) == 0b11;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, cpu, _| {
             let ext = ins.ext.unwrap();
@@ -289,7 +289,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             raw |= i1 << 21;
             raw |= i2 << 22;
             raw |= s << 23; // bit 24
-            raw = raw << 1; // 25th "bit"
+            raw <<= 1; // 25th "bit"
             let rawi: i32 = raw as i32;
             let address = (rawi << 7) >> 7;
             // Save PC to LR
@@ -387,7 +387,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(0, 16) == 0b1111001110111111;
             let second_part_good = ins.ext.unwrap().idx(12, 4) == 0b100011110101;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, _cpu, _| {
             let _ext = ins.ext.unwrap();
@@ -402,7 +402,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(0, 16) == 0b1111001110111111;
             let second_part_good = ins.ext.unwrap().idx(12, 4) == 0b100011110100;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, _cpu, _| {
             let _ext = ins.ext.unwrap();
@@ -430,7 +430,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(0, 16) == 0b1111001110111111;
             let second_part_good = ins.ext.unwrap().idx(12, 4) == 0b100011110110;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, _cpu, _| {
             let _ext = ins.ext.unwrap();
@@ -564,7 +564,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if 0 < imd {
                 cpu.r[rd_no] = cpu.r[rm_no] << (imd - 1);
                 cpu.c = bitidx(cpu.r[rm_no], 31, 1) > 0;
-                cpu.r[rd_no] = cpu.r[rd_no] << 1;
+                cpu.r[rd_no] <<= 1;
             }
             cpu.z = cpu.r[rd_no] == 0;
             cpu.n = 0 < (cpu.r[rd_no] & (1 << 31));
@@ -578,9 +578,9 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             let rdn_no = ins.hdr.idx(0, 3) as usize;
             let rm_no = ins.hdr.idx(3, 3) as usize;
             if 0 < cpu.r[rm_no] {
-                cpu.r[rdn_no] = cpu.r[rdn_no] << (cpu.r[rm_no] - 1);
+                cpu.r[rdn_no] <<= cpu.r[rm_no] - 1;
                 cpu.c = bitidx(cpu.r[rdn_no], 31, 1) > 0;
-                cpu.r[rdn_no] = cpu.r[rdn_no] << 1;
+                cpu.r[rdn_no] <<= 1;
             }
             cpu.z = cpu.r[rdn_no] == 0;
             cpu.n = 0 < (cpu.r[rdn_no] & (1 << 31));
@@ -597,7 +597,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if 0 < imd {
                 cpu.r[rd_no] = cpu.r[rm_no] >> (imd - 1);
                 cpu.c = bitidx(cpu.r[rd_no], 31, 1) > 0;
-                cpu.r[rd_no] = cpu.r[rd_no] >> 1;
+                cpu.r[rd_no] >>= 1;
             }
             cpu.z = cpu.r[rd_no] == 0;
             cpu.n = 0 < (cpu.r[rd_no] & (1 << 31));
@@ -613,7 +613,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if 0 < cpu.r[rm_no] {
                 cpu.r[rdn_no] = cpu.r[rm_no] >> (cpu.r[rm_no] - 1);
                 cpu.c = bitidx(cpu.r[rdn_no], 31, 1) > 0;
-                cpu.r[rdn_no] = cpu.r[rdn_no] >> 1;
+                cpu.r[rdn_no] >>= 1;
             }
             cpu.z = cpu.r[rdn_no] == 0;
             cpu.n = 0 < (cpu.r[rdn_no] & (1 << 31));
@@ -665,7 +665,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(0, 16) == 0b1111001111101111;
             let second_part_good = ins.ext.unwrap().idx(12, 4) == 1000;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, _cpu, _| {
             let _ext = ins.ext.unwrap();
@@ -680,7 +680,7 @@ pub fn load_basic_instructions(instructions: &mut LoaderExecuter) {
             if thumb1 {return false;}
             let first_part_good = ins.hdr.idx(4, 12) == 0b111100111000;
             let second_part_good = ins.ext.unwrap().idx(8, 8) == 0b10001000;
-            return !thumb1 && first_part_good && second_part_good;
+            !thumb1 && first_part_good && second_part_good
         },
         |ins, _cpu, _| {
             let _ext = ins.ext.unwrap();
@@ -7,8 +7,31 @@ mod adr;
 mod fstools;
 mod memory;
 mod config;
+mod emulator;
+mod snapshot;
+mod reverse;
+mod symbols;
+mod monitor;
+mod gdb;
+mod flash;
+mod context;
+mod exceptions;
+mod hooks;
+mod scripting;
+mod description;
+mod chips;
+mod uart;
+mod gpio;
+mod vcd;
+mod timer;
+mod spi;
+mod i2c;
+mod dma;
+mod watchdog;
+mod rtc;
+mod realtime;
 
-use crate::{adr::AddressSpace, fetch::fetch_instruction, instructions::load_basic_instructions, registers::{PC_IDX, SP_IDX}};
+use crate::{adr::AddressSpace, fetch::fetch_instruction, registers::PC_IDX};
 
 fn print_proc_state(cpu: &registers::Registers) {
     log::debug!("New CPU State: PC({}), R0-R7({}, {}, {}, {}, {}, {}, {}, {})",
@@ -16,35 +39,238 @@ fn print_proc_state(cpu: &registers::Registers) {
         )
 }
 
+/// Command line options, eg `--steps 500 --save-snapshot booted.snap`
+#[derive(Default)]
+struct Options {
+    config: String,
+    /// Only validate the config
+    check_config: bool,
+    /// Write the config in the format of this path instead of running it
+    convert: Option<String>,
+    steps: u64,
+    load_snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    /// Addresses or symbols
+    breakpoints: Vec<String>,
+    watchpoints: Vec<String>,
+    /// Instructions to undo after the run
+    step_back: u64,
+    reverse_continue: bool,
+    /// ELF file to take symbols from
+    symbols: Option<String>,
+    monitor: bool,
+    /// Address to serve GDB on instead of running, eg `localhost:3333`
+    gdb: Option<String>,
+    /// Value Change Dump to write the run to
+    vcd: Option<String>,
+    /// Include the PC in the VCD
+    vcd_pc: bool,
+    /// Pace the run to the core frequency of the config
+    realtime: bool,
+    /// Limits for the Lua code of the config
+    sandbox: scripting::Sandbox,
+}
+impl Options {
+    fn parse() -> Self {
+        let mut options = Options {config: "./config.lua".to_string(), steps: 2000, ..Default::default()};
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| panic!("Expected value after {arg}"));
+            match arg.as_str() {
+                "--config" => options.config = value(),
+                "--check-config" => options.check_config = true,
+                "--convert" => options.convert = Some(value()),
+                "--steps" => options.steps = value().parse().expect("Invalid step count"),
+                "--load-snapshot" => options.load_snapshot = Some(value()),
+                "--save-snapshot" => options.save_snapshot = Some(value()),
+                "--break" => options.breakpoints.push(value()),
+                "--watch" => options.watchpoints.push(value()),
+                "--step-back" => options.step_back = value().parse().expect("Invalid step count"),
+                "--reverse-continue" => options.reverse_continue = true,
+                "--symbols" => options.symbols = Some(value()),
+                "--monitor" => options.monitor = true,
+                "--gdb" => options.gdb = Some(value()),
+                "--vcd" => options.vcd = Some(value()),
+                "--vcd-pc" => options.vcd_pc = true,
+                "--realtime" => options.realtime = true,
+                "--lua-memory" => options.sandbox.memory_limit = Some(value().parse().expect("Invalid byte count")),
+                "--lua-instructions" => options.sandbox.instruction_limit = Some(value().parse().expect("Invalid instruction count")),
+                "--lua-libs" => options.sandbox.libs = scripting::Sandbox::parse_libs(&value()).expect("Invalid library list"),
+                _ => panic!("Unknown argument {arg}"),
+            }
+        }
+        options
+    }
+}
+
 fn main() {
     env_logger::init();
+    let options = Options::parse();
+
+    if let Some(to) = &options.convert {
+        if let Err(err) = config::convert(&options.config, to, &options.sandbox) {
+            eprintln!("Config error: {err}");
+            std::process::exit(1);
+        }
+        println!("Converted {} to {to}", options.config);
+        return;
+    }
 
     log::info!("Loading Config");
-    let mut address_space = config::load();
-    log::info!("Loaded Config");
-
-    let mut cpu = &mut registers::Registers {
-        r: [0; 16],
-        n: false,
-        z: false,
-        c: false,
-        v: false,
+    let machine = match config::load(&options.config, &options.sandbox) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("Config error: {err}");
+            std::process::exit(1);
+        },
     };
-    cpu.r[PC_IDX] = 2; // PC Points to currently executing instruction + 4
-    cpu.r[SP_IDX] = 2000; // kinda a hack to start with(at the top of a memory page defined in lua
-                          // config)
+    if options.realtime && machine.frequency.is_none() {
+        eprintln!("Config error: --realtime needs the core `frequency`");
+        std::process::exit(1);
+    }
+    let (address_space, mut hooks, frequency) = (machine.memory, machine.hooks, machine.frequency);
+    log::info!("Loaded Config, data accesses are {:?} endian", address_space.endian());
+    for line in address_space.to_string().lines() {
+        log::info!("{line}");
+    }
+    if options.check_config {
+        print!("{address_space}");
+        println!("{} is valid", options.config);
+        return;
+    }
 
+    let mut emulator = emulator::Emulator::new(address_space);
+    if let Some(path) = &options.load_snapshot {
+        snapshot::Snapshot::load(path)
+            .and_then(|snap| snap.restore(&mut emulator))
+            .expect("Failed to restore snapshot");
+        log::info!("Restored snapshot at cycle {}", emulator.cycles);
+    }
 
-    // Implement Instructions
-    let mut instructions = ins::LoaderExecuter::new();
-    load_basic_instructions(&mut instructions);
+    let symbols = match &options.symbols {
+        Some(path) => symbols::Symbols::load(path).expect("Failed to load symbols"),
+        None => symbols::Symbols::default(),
+    };
+    let resolve = |text: &String| core::parse_word(text).or_else(|| symbols.lookup(text))
+        .unwrap_or_else(|| panic!("Unknown address or symbol {text}"));
+    emulator.breakpoints = options.breakpoints.iter().map(resolve).collect();
+    emulator.watchpoints = options.watchpoints.iter().map(resolve).collect();
+    hooks.resolve(&symbols).unwrap_or_else(|err| panic!("{err}"));
+    if let Some(path) = &options.vcd {
+        vcd::Vcd::create(path, options.vcd_pc)
+            .and_then(|vcd| emulator.record(vcd))
+            .unwrap_or_else(|err| panic!("Failed to write {path}: {err}"));
+    }
+
+    let mut exit_code = 0;
+    let reason = if options.monitor {
+        let mut monitor = monitor::Monitor::new(emulator, symbols);
+        monitor.run();
+        emulator = monitor.emulator;
+        "quit"
+    } else if let Some(address) = &options.gdb {
+        let mut stub = gdb::GdbStub::new(emulator);
+        if let Err(err) = stub.serve(address) {
+            eprintln!("GDB connection failed: {err}");
+            exit_code = 1;
+        }
+        emulator = stub.emulator;
+        "quit"
+    } else {
+        let pacer = frequency.filter(|_| options.realtime)
+            .map(|frequency| realtime::Pacer::new(frequency, emulator.cycles));
+        match run(&options, &mut emulator, &hooks, pacer) {
+            Ok(reason) => reason,
+            Err(err) => {
+                eprintln!("Lua error: {err}");
+                exit_code = 1;
+                "error"
+            },
+        }
+    };
+    match hooks.on_exit(&mut emulator, reason) {
+        Ok(Some(code)) => exit_code = code,
+        Ok(None) => {},
+        Err(err) => {
+            eprintln!("Lua error: {err}");
+            exit_code = 1;
+        },
+    }
+
+    if let Some(path) = &options.save_snapshot {
+        snapshot::Snapshot::capture(&emulator).save(path)
+            .expect("Failed to save snapshot");
+        log::info!("Saved snapshot at cycle {}", emulator.cycles);
+    }
+    // Write back flash and nvram files
+    emulator.memory.flush().expect("Failed to write back memory");
+    if let Some(vcd) = &mut emulator.vcd {
+        vcd.finish(emulator.cycles).expect("Failed to write the VCD");
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Run without user interaction, returns why the run ended as handed to `on_exit`
+///
+/// With a pacer the steps go at the core frequency, going back in time afterwards doesn't wait.
+fn run(
+    options: &Options,
+    emulator: &mut emulator::Emulator,
+    hooks: &hooks::Hooks,
+    mut pacer: Option<realtime::Pacer>) -> mlua::Result<&'static str> {
+    let mut history = reverse::History::new(1000);
 
     // Run the program
-    let instruction_count = 2000;
-    for _ in 0..instruction_count {
-        step(&instructions, &mut cpu, &mut *address_space);
-        print_proc_state(&cpu);
+    let mut reason = "steps";
+    for _ in 0..options.steps {
+        if !hooks.before_step(emulator)? {
+            reason = "hook";
+            break;
+        }
+        let stop = history.step(emulator);
+        print_proc_state(&emulator.cpu);
+        if let Some(pacer) = &mut pacer {
+            pacer.pace(emulator.cycles);
+        }
+        if !matches!(stop, Some(emulator::Stop::Fault(_) | emulator::Stop::Error(_))) && !hooks.after_step(emulator)? {
+            reason = "hook";
+            break;
+        }
+        match stop {
+            Some(emulator::Stop::Fault(fault)) => {
+                log::error!("Bus fault at pc {:#010x}: {:?}", emulator.pc(), fault);
+                reason = "fault";
+                break;
+            },
+            Some(emulator::Stop::Error(err)) => return Err(mlua::Error::runtime(err)),
+            Some(stop) => {
+                log::info!("Stopped by {:?} at cycle {}", stop, emulator.cycles);
+                reason = match stop {
+                    emulator::Stop::Breakpoint(_) => "breakpoint",
+                    _ => "watchpoint",
+                };
+                break;
+            },
+            None => {},
+        }
+    }
+
+    // Travel back in time
+    for _ in 0..options.step_back {
+        history.step_back(emulator).expect("Failed to step back");
+    }
+    if options.reverse_continue {
+        match history.reverse_continue(emulator).expect("Failed to reverse continue") {
+            Some(stop) => log::info!("Reversed to {:?} at cycle {}", stop, emulator.cycles),
+            None => log::info!("Reversed to the start of history at cycle {}", emulator.cycles),
+        }
+    }
+    if 0 < options.step_back || options.reverse_continue {
+        print_proc_state(&emulator.cpu);
     }
+    Ok(reason)
 }
 
 pub fn step(
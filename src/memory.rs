@@ -1,42 +1,419 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::DerefMut;
-use crate::adr::AddressSpace;
+use std::rc::Rc;
+use crate::adr::{AddressSpace, Endian};
+use crate::context::SharedContext;
 use crate::core::*;
+use crate::snapshot::StateReader;
+use crate::vcd::Probe;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+impl Permissions {
+    pub const ALL: Self = Self {read: true, write: true, execute: true};
+    /// Any combination of `r`, `w` and `x`, eg `"rx"`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut perm = Self {read: false, write: false, execute: false};
+        for c in text.chars() {
+            match c {
+                'r' => perm.read = true,
+                'w' => perm.write = true,
+                'x' => perm.execute = true,
+                _ => return None,
+            }
+        }
+        Some(perm)
+    }
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+impl std::fmt::Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |set: bool, name: char| if set {name} else {'-'};
+        write!(f, "{}{}{}", flag(self.read, 'r'), flag(self.write, 'w'), flag(self.execute, 'x'))
+    }
+}
+
+/// An access the bus refused, the first one of an instruction is kept until taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusFault {
+    pub adr: AWord,
+    pub access: Access,
+    /// Whether a region exists at `adr` but doesn't permit the access
+    pub mapped: bool,
+}
+
+/// A region of the memory map, as configured
+pub struct MappedRegion<'a> {
+    pub label: String,
+    /// Region type from the config, eg `ram`
+    pub kind: &'static str,
+    /// Overlapping regions are only allowed with different priorities, the highest one wins
+    pub priority: i32,
+    pub perm: Permissions,
+    pub space: Box<dyn AddressSpace + 'a>,
+}
+impl MappedRegion<'_> {
+    /// Last address, inclusive so regions can reach the top of the address space
+    pub fn end(&self) -> AWord {
+        self.space.origin().wrapping_add(self.space.len()).wrapping_sub(1)
+    }
+    pub fn contains(&self, adr: AWord) -> bool {
+        self.space.origin() <= adr && adr - self.space.origin() < self.space.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapError {
+    Empty { label: String },
+    /// The region doesn't fit below 4GiB
+    Overflow { label: String },
+    Overlap { label: String, other: String },
+}
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty { label } => write!(f, "Region `{label}` has no length"),
+            Self::Overflow { label } => write!(f, "Region `{label}` extends past the end of the address space"),
+            Self::Overlap { label, other } =>
+                write!(f, "Region `{label}` overlaps `{other}`, give them different priorities to allow this"),
+        }
+    }
+}
+impl std::error::Error for MapError {}
+
 pub struct AddressDeMultiplexer<'a> {
     origin: AWord,
     length: AWord,
-    regions: Vec<Box<dyn AddressSpace + 'a>>,
+    /// Sorted by descending priority, so the first match wins
+    regions: Vec<MappedRegion<'a>>,
+    /// Storage of regions that have aliases, by label
+    shared: HashMap<String, SharedSpace<'a>>,
+    /// Per region, whether no higher priority region overlaps it, only those can be cached
+    unshadowed: Vec<bool>,
+    /// Indices of the regions used last, instruction fetches and data accesses alternate
+    recent: [Option<usize>; 2],
+    fault: Option<BusFault>,
+    /// Byte order of the core's data accesses
+    endian: Endian,
+    context: SharedContext,
+    /// Bus masters besides the core, in priority order
+    masters: Vec<Box<dyn BusMaster + 'a>>,
 }
 impl<'a> AddressDeMultiplexer<'a> {
     pub fn full() -> Self {
-        return Self::new(0, AWord::MAX)
+        Self::new(0, AWord::MAX)
     }
     pub fn new(origin: AWord, length: AWord) -> Self {
-        Self {origin, length, regions: Vec::new()}
+        Self {origin, length, regions: Vec::new(), shared: HashMap::new(), unshadowed: Vec::new(), recent: [None; 2], fault: None, endian: Endian::Little, context: SharedContext::default(), masters: Vec::new()}
     }
-    fn lookup(&mut self, idx: AWord) -> Option<(&mut dyn AddressSpace, AWord)> {
-        for region in self.regions.iter_mut() {
-            let contains_idx = region.origin() <= idx && idx < region.origin() + region.len();
-            if contains_idx {
-                let local_idx = idx - region.origin();
-                return Some((region.deref_mut(), local_idx));
+    /// Index of the region `adr` belongs to
+    fn find(&mut self, adr: AWord) -> Option<usize> {
+        let cached = self.recent.into_iter().flatten().find(|&idx| self.regions[idx].contains(adr));
+        if cached.is_some() {
+            return cached;
+        }
+        let idx = self.regions.iter().position(|region| region.contains(adr))?;
+        if self.unshadowed[idx] {
+            self.recent = [Some(idx), self.recent[0]];
+        }
+        Some(idx)
+    }
+    fn local(&mut self, idx: usize, adr: AWord) -> (&mut dyn AddressSpace, AWord) {
+        let region = &mut self.regions[idx];
+        let local_idx = adr - region.space.origin();
+        (region.space.deref_mut(), local_idx)
+    }
+    fn lookup(&mut self, adr: AWord) -> Option<(&mut dyn AddressSpace, AWord)> {
+        let idx = self.find(adr)?;
+        Some(self.local(idx, adr))
+    }
+    /// Like `lookup`, but records a fault when the access isn't allowed
+    fn access(&mut self, adr: AWord, access: Access) -> Option<(&mut dyn AddressSpace, AWord)> {
+        let region = self.find(adr);
+        let allowed = region.is_some_and(|idx| self.regions[idx].perm.allows(access));
+        if !allowed {
+            self.fault.get_or_insert(BusFault {adr, access, mapped: region.is_some()});
+            return None;
+        }
+        self.lookup(adr)
+    }
+    /// The region of an aligned `size` byte access, when it can take the whole access at once.
+    /// Otherwise the access is split into bytes, which also takes care of faults.
+    fn span(&mut self, adr: AWord, size: AWord, access: Access) -> Option<(&mut dyn AddressSpace, AWord)> {
+        let idx = self.find(adr)?;
+        let region = &self.regions[idx];
+        let local_idx = adr - region.space.origin();
+        let fits = local_idx.is_multiple_of(size) && local_idx as u64 + size as u64 <= region.space.len() as u64;
+        if !fits || !self.unshadowed[idx] || !region.perm.allows(access) {
+            return None;
+        }
+        Some(self.local(idx, adr))
+    }
+    /// Core state for peripherals, the emulator keeps it up to date
+    pub fn context(&self) -> SharedContext {
+        self.context.clone()
+    }
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+    /// Whether this is the map of a part, with a system control block, whose core boots from the
+    /// vector table at 0 rather than starting there
+    pub fn has_vector_table(&self) -> bool {
+        self.regions.iter().any(|region| region.kind == "scb")
+    }
+    pub fn take_fault(&mut self) -> Option<BusFault> {
+        self.fault.take()
+    }
+    /// Access for debuggers, which aren't bound by permissions
+    pub fn debug_view(&mut self) -> DebugView<'_, 'a> {
+        DebugView {inner: self}
+    }
+    pub fn add_region(&mut self, region: MappedRegion<'a>) -> Result<(), MapError> {
+        let (origin, len) = (region.space.origin() as u64, region.space.len() as u64);
+        if len == 0 {
+            return Err(MapError::Empty { label: region.label });
+        }
+        if AWord::MAX as u64 + 1 < origin + len {
+            return Err(MapError::Overflow { label: region.label });
+        }
+        let overlapping = self.regions.iter().find(|other| {
+            other.priority == region.priority
+                && region.space.origin() <= other.end() && other.space.origin() <= region.end()
+        });
+        if let Some(other) = overlapping {
+            return Err(MapError::Overlap { label: region.label, other: other.label.clone() });
+        }
+        let idx = self.regions.partition_point(|other| region.priority <= other.priority);
+        self.regions.insert(idx, region);
+        // Same priority regions never overlap, so any overlap is with a higher priority region
+        self.unshadowed = (0..self.regions.len()).map(|idx| {
+            let region = &self.regions[idx];
+            !self.regions[..idx].iter().any(|other| other.priority != region.priority
+                && region.space.origin() <= other.end() && other.space.origin() <= region.end())
+        }).collect();
+        self.recent = [None; 2];
+        Ok(())
+    }
+    /// Like `save_state`, only the volatile state of every region when `volatile`
+    fn save_regions(&self, out: &mut Vec<u8>, volatile: bool) {
+        out.extend((self.regions.len() as u32).to_le_bytes());
+        for region in self.regions.iter() {
+            let mut state = Vec::new();
+            match volatile {
+                true => region.space.save_volatile(&mut state),
+                false => region.space.save_state(&mut state),
+            }
+            out.extend((region.label.len() as u32).to_le_bytes());
+            out.extend(region.label.as_bytes());
+            out.extend((state.len() as u32).to_le_bytes());
+            out.extend(state);
+        }
+    }
+    /// Inverse of `save_regions`
+    fn restore_regions(&mut self, state: &[u8], volatile: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reader = StateReader::new(state, "Truncated memory state");
+        let count = reader.word()? as usize;
+        if count != self.regions.len() {
+            return Err(format!("Snapshot has {count} regions, config has {}", self.regions.len()).into());
+        }
+        for _ in 0..count {
+            let label = std::str::from_utf8(reader.counted()?)?.to_string();
+            let region = self.regions.iter_mut().find(|region| region.label == label)
+                .ok_or(format!("Snapshot has region `{label}` which isn't in the config"))?;
+            let state = reader.counted()?;
+            match volatile {
+                true => region.space.restore_volatile(state)?,
+                false => region.space.restore_state(state)?,
             }
         }
-        None
+        reader.finish()
+    }
+    pub fn add_master(&mut self, master: Box<dyn BusMaster + 'a>) {
+        self.masters.push(master);
+    }
+    /// Give the bus to the other masters after the regions ticked, returns the cycles they took.
+    /// Their writes are added to `writes` when given, so watchpoints see them.
+    pub fn run_masters(&mut self, writes: Option<&mut Vec<AWord>>) -> u64 {
+        if self.masters.is_empty() {
+            return 0;
+        }
+        let mut masters = std::mem::take(&mut self.masters);
+        let mut port = MasterPort {memory: self, writes};
+        let cycles = masters.iter_mut().map(|master| master.run(&mut port)).sum();
+        self.masters = masters;
+        cycles
     }
-    pub fn add_region(&mut self, region: Box<dyn AddressSpace + 'a>) {
-        self.regions.push(region);
+    pub fn regions(&self) -> impl Iterator<Item = &MappedRegion<'a>> {
+        self.regions.iter()
+    }
+    /// Storage of the region labeled `label`, to be used by an `Alias`
+    pub fn share(&mut self, label: &str) -> Option<SharedSpace<'a>> {
+        if let Some(shared) = self.shared.get(label) {
+            return Some(shared.clone());
+        }
+        let region = self.regions.iter_mut().find(|region| region.label == label)?;
+        let placeholder = Box::new(BufferMemory {origin: 0, buffer: Box::new([])});
+        let shared = Rc::new(RefCell::new(std::mem::replace(&mut region.space, placeholder)));
+        region.space = Box::new(Shared(shared.clone()));
+        self.shared.insert(label.to_string(), shared.clone());
+        Some(shared)
+    }
+}
+/// Anything besides the core that accesses the bus, eg a DMA controller
+pub trait BusMaster {
+    /// Called once after every step, returns the cycles the core was kept off the bus for
+    fn run(&mut self, port: &mut MasterPort) -> u64;
+}
+
+/// The bus as another master sees it, with the permissions the core has
+///
+/// Faults are returned to the master instead of stopping the core.
+pub struct MasterPort<'b, 'a> {
+    memory: &'b mut AddressDeMultiplexer<'a>,
+    writes: Option<&'b mut Vec<AWord>>,
+}
+impl MasterPort<'_, '_> {
+    /// Reads 1, 2 or 4 bytes in the byte order of the core
+    pub fn read(&mut self, adr: AWord, size: AWord) -> Result<AWord, BusFault> {
+        self.isolate(|memory| match size {
+            1 => memory.readb(adr) as AWord,
+            2 => memory.read_hw(adr) as AWord,
+            _ => memory.read_w(adr),
+        })
+    }
+    pub fn write(&mut self, adr: AWord, size: AWord, x: AWord) -> Result<(), BusFault> {
+        if let Some(writes) = &mut self.writes {
+            writes.extend((0..size).map(|offset| adr.wrapping_add(offset)));
+        }
+        self.isolate(|memory| match size {
+            1 => memory.writeb(adr, x as AByte),
+            2 => memory.write_hw(adr, x as AHalfWord),
+            _ => memory.write_w(adr, x),
+        })
+    }
+    /// Keeps a fault of the core apart from one of this access
+    fn isolate<T>(&mut self, access: impl FnOnce(&mut AddressDeMultiplexer) -> T) -> Result<T, BusFault> {
+        let core = self.memory.fault.take();
+        let result = access(self.memory);
+        match std::mem::replace(&mut self.memory.fault, core) {
+            Some(fault) => Err(fault),
+            None => Ok(result),
+        }
+    }
+}
+
+/// Memory map table, in address order
+impl std::fmt::Display for AddressDeMultiplexer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut sorted: Vec<&MappedRegion> = self.regions.iter().collect();
+        sorted.sort_by_key(|region| (region.space.origin(), -region.priority));
+        writeln!(f, "{:<16} {:>10} {:>10} {:>10} {:<8} {:<4} {:>8}",
+            "label", "origin", "end", "size", "kind", "perm", "priority")?;
+        for region in sorted {
+            writeln!(f, "{:<16} {:#010x} {:#010x} {:>10} {:<8} {:<4} {:>8}", region.label, region.space.origin(),
+                region.end(), region.space.len(), region.kind, region.perm.to_string(), region.priority)?;
+        }
+        Ok(())
     }
 }
 impl<'a> AddressSpace for AddressDeMultiplexer<'a> {
     fn origin(&self) -> AWord {self.origin}
     fn len(&self) -> AWord {self.length}
+    fn endian(&self) -> Endian {self.endian}
+    // Refused reads return 0 and refused writes are dropped, see `take_fault`
     fn readb(&mut self, adr: AWord) -> AByte {
-        let (region, lidx) = self.lookup(adr).expect("SEGFAULT");
-        region.readb(lidx)
+        match self.access(adr, Access::Read) {
+            Some((region, lidx)) => region.readb(lidx),
+            None => 0,
+        }
     }
     fn writeb(&mut self, adr: AWord, x: AByte) {
-        let (region, lidx) = self.lookup(adr).expect("SEGFAULT");
-        region.writeb(lidx, x);
+        if let Some((region, lidx)) = self.access(adr, Access::Write) {
+            region.writeb(lidx, x);
+        }
+    }
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {
+        match self.span(adr, 2, Access::Read) {
+            Some((region, lidx)) => region.read_hw_le(lidx),
+            None => AHalfWord::from_le_bytes([0, 1].map(|offset| self.readb(adr.wrapping_add(offset)))),
+        }
+    }
+    fn read_w_le(&mut self, adr: AWord) -> AWord {
+        match self.span(adr, 4, Access::Read) {
+            Some((region, lidx)) => region.read_w_le(lidx),
+            None => AWord::from_le_bytes([0, 1, 2, 3].map(|offset| self.readb(adr.wrapping_add(offset)))),
+        }
+    }
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
+        match self.span(adr, 2, Access::Write) {
+            Some((region, lidx)) => region.write_hw_le(lidx, x),
+            None => for (offset, byte) in x.to_le_bytes().into_iter().enumerate() {
+                self.writeb(adr.wrapping_add(offset as AWord), byte);
+            },
+        }
+    }
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {
+        match self.span(adr, 4, Access::Write) {
+            Some((region, lidx)) => region.write_w_le(lidx, x),
+            None => for (offset, byte) in x.to_le_bytes().into_iter().enumerate() {
+                self.writeb(adr.wrapping_add(offset as AWord), byte);
+            },
+        }
+    }
+    fn fetch_hw(&mut self, adr: AWord) -> AHalfWord {
+        if let Some((region, lidx)) = self.span(adr, 2, Access::Execute) {
+            return region.read_hw_le(lidx);
+        }
+        let bytes = [adr, adr.wrapping_add(1)].map(|adr| match self.access(adr, Access::Execute) {
+            Some((region, lidx)) => region.readb(lidx),
+            None => 0,
+        });
+        AHalfWord::from_le_bytes(bytes)
+    }
+    // Each region's state is prefixed by its label and length, the order of regions in the config
+    // isn't stable
+    fn save_state(&self, out: &mut Vec<u8>) {self.save_regions(out, false)}
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore_regions(state, false)
+    }
+    fn save_volatile(&self, out: &mut Vec<u8>) {self.save_regions(out, true)}
+    fn restore_volatile(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore_regions(state, true)
+    }
+    fn tick(&mut self, cycles: u64) {
+        for region in self.regions.iter_mut() {
+            region.space.tick(cycles);
+        }
+    }
+    fn probe(&self, probe: &mut Probe) {
+        for region in &self.regions {
+            probe.scope(&region.label);
+            region.space.probe(probe);
+        }
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for region in self.regions.iter_mut() {
+            region.space.flush()?;
+        }
+        Ok(())
     }
 }
 
@@ -49,25 +426,266 @@ pub struct BufferMemory {
 
 impl AddressSpace for BufferMemory {
     fn readb(&mut self, adr: AWord) -> AByte {
-        return self.buffer[adr as usize];
+        self.buffer[adr as usize]
     }
     fn writeb(&mut self, adr: AWord, x: AByte) {
         self.buffer[adr as usize] = x;
     }
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {
+        AHalfWord::from_le_bytes(self.buffer[adr as usize..adr as usize + 2].try_into().unwrap())
+    }
+    fn read_w_le(&mut self, adr: AWord) -> AWord {
+        AWord::from_le_bytes(self.buffer[adr as usize..adr as usize + 4].try_into().unwrap())
+    }
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
+        self.buffer[adr as usize..adr as usize + 2].copy_from_slice(&x.to_le_bytes());
+    }
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {
+        self.buffer[adr as usize..adr as usize + 4].copy_from_slice(&x.to_le_bytes());
+    }
     fn origin(&self) -> AWord {self.origin}
     fn len(&self) -> AWord {self.buffer.len() as AWord}
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.buffer);
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != self.buffer.len() {
+            return Err(format!("Region at {} is {} bytes, snapshot has {}",
+                self.origin, self.buffer.len(), state.len()).into());
+        }
+        self.buffer.copy_from_slice(state);
+        Ok(())
+    }
+}
+pub type SharedSpace<'a> = Rc<RefCell<Box<dyn AddressSpace + 'a>>>;
+
+/// A region whose storage is also reachable through aliases, behaves exactly like the original
+pub struct Shared<'a>(pub SharedSpace<'a>);
+impl AddressSpace for Shared<'_> {
+    fn origin(&self) -> AWord {self.0.borrow().origin()}
+    fn len(&self) -> AWord {self.0.borrow().len()}
+    fn readb(&mut self, adr: AWord) -> AByte {self.0.borrow_mut().readb(adr)}
+    fn writeb(&mut self, adr: AWord, x: AByte) {self.0.borrow_mut().writeb(adr, x)}
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {self.0.borrow_mut().read_hw_le(adr)}
+    fn read_w_le(&mut self, adr: AWord) -> AWord {self.0.borrow_mut().read_w_le(adr)}
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {self.0.borrow_mut().write_hw_le(adr, x)}
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {self.0.borrow_mut().write_w_le(adr, x)}
+    fn save_state(&self, out: &mut Vec<u8>) {self.0.borrow().save_state(out)}
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.0.borrow_mut().restore_state(state)
+    }
+    fn tick(&mut self, cycles: u64) {self.0.borrow_mut().tick(cycles)}
+    fn probe(&self, probe: &mut Probe) {self.0.borrow().probe(probe)}
+    fn non_volatile(&self) -> bool {self.0.borrow().non_volatile()}
+    fn save_volatile(&self, out: &mut Vec<u8>) {self.0.borrow().save_volatile(out)}
+    fn restore_volatile(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.0.borrow_mut().restore_volatile(state)
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {self.0.borrow_mut().flush()}
+}
+
+/// Another window onto a region's storage, eg flash mirrored at 0 or SRAM repeating every 8KiB
+///
+/// An address maps to `offset + (local & mask)` in the target. The state belongs to the target,
+/// so aliases have nothing to save.
+pub struct Alias<'a> {
+    pub origin: AWord,
+    pub length: AWord,
+    pub offset: AWord,
+    pub mask: AWord,
+    target: SharedSpace<'a>,
+}
+impl<'a> Alias<'a> {
+    /// `mask` is one less than a power of two, `AWord::MAX` for a plain alias
+    pub fn new(origin: AWord, length: AWord, offset: AWord, mask: AWord, target: SharedSpace<'a>) -> Result<Self, String> {
+        if mask & mask.wrapping_add(1) != 0 {
+            return Err(format!("Mask {mask:#x} is not one less than a power of two"));
+        }
+        let window = if mask == AWord::MAX {length as u64} else {(length as u64).min(mask as u64 + 1)};
+        let target_len = target.borrow().len() as u64;
+        if target_len < offset as u64 + window {
+            return Err(format!("Alias reaches {:#x} bytes into a target of {target_len:#x}", offset as u64 + window));
+        }
+        Ok(Self {origin, length, offset, mask, target})
+    }
+    fn translate(&self, adr: AWord) -> AWord {
+        self.offset + (adr & self.mask)
+    }
+}
+impl AddressSpace for Alias<'_> {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {self.length}
+    fn readb(&mut self, adr: AWord) -> AByte {self.target.borrow_mut().readb(self.translate(adr))}
+    fn writeb(&mut self, adr: AWord, x: AByte) {self.target.borrow_mut().writeb(self.translate(adr), x)}
+}
+
+/// RAM kept in a host file between runs, eg EEPROM or battery backed SRAM
+pub struct PersistentMemory {
+    pub memory: BufferMemory,
+    pub path: String,
+    /// The file is behind the buffer
+    dirty: bool,
+}
+impl PersistentMemory {
+    /// Loads `path`, or starts out filled with `fill` and creates the file on the first flush
+    pub fn open(origin: AWord, len: AWord, path: String, fill: AByte) -> Result<Self, Box<dyn std::error::Error>> {
+        let (buffer, dirty) = match std::fs::read(&path) {
+            Ok(contents) if contents.len() == len as usize => (contents.into_boxed_slice(), false),
+            Ok(contents) => return Err(format!("File is {} bytes, expected {len}", contents.len()).into()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (vec![fill; len as usize].into_boxed_slice(), true),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {memory: BufferMemory {origin, buffer}, path, dirty})
+    }
 }
+impl AddressSpace for PersistentMemory {
+    fn origin(&self) -> AWord {self.memory.origin}
+    fn len(&self) -> AWord {self.memory.len()}
+    fn readb(&mut self, adr: AWord) -> AByte {self.memory.readb(adr)}
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        self.memory.writeb(adr, x);
+        self.dirty = true;
+    }
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {self.memory.read_hw_le(adr)}
+    fn read_w_le(&mut self, adr: AWord) -> AWord {self.memory.read_w_le(adr)}
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
+        self.memory.write_hw_le(adr, x);
+        self.dirty = true;
+    }
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {
+        self.memory.write_w_le(adr, x);
+        self.dirty = true;
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {self.memory.save_state(out)}
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.dirty = true;
+        self.memory.restore_state(state)
+    }
+    fn non_volatile(&self) -> bool {true}
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dirty {
+            std::fs::write(&self.path, &self.memory.buffer)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Ignores permissions, unmapped reads return 0 and unmapped writes are dropped
+pub struct DebugView<'b, 'a> {
+    inner: &'b mut AddressDeMultiplexer<'a>,
+}
+impl AddressSpace for DebugView<'_, '_> {
+    fn origin(&self) -> AWord {self.inner.origin()}
+    fn endian(&self) -> Endian {self.inner.endian()}
+    fn len(&self) -> AWord {self.inner.len()}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        self.inner.lookup(adr).map_or(0, |(region, lidx)| region.readb(lidx))
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        if let Some((region, lidx)) = self.inner.lookup(adr) {
+            region.writeb(lidx, x);
+        }
+    }
+}
+
+/// Passes every access through while remembering which addresses were written
+pub struct WriteLog<'a> {
+    pub inner: &'a mut dyn AddressSpace,
+    pub writes: &'a mut Vec<AWord>,
+}
+impl AddressSpace for WriteLog<'_> {
+    fn origin(&self) -> AWord {self.inner.origin()}
+    fn endian(&self) -> Endian {self.inner.endian()}
+    fn len(&self) -> AWord {self.inner.len()}
+    fn readb(&mut self, adr: AWord) -> AByte {self.inner.readb(adr)}
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        self.writes.push(adr);
+        self.inner.writeb(adr, x);
+    }
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {self.inner.read_hw_le(adr)}
+    fn read_w_le(&mut self, adr: AWord) -> AWord {self.inner.read_w_le(adr)}
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
+        self.writes.extend([adr, adr.wrapping_add(1)]);
+        self.inner.write_hw_le(adr, x);
+    }
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {
+        self.writes.extend((0..4).map(|offset| adr.wrapping_add(offset)));
+        self.inner.write_w_le(adr, x);
+    }
+    fn fetch_hw(&mut self, adr: AWord) -> AHalfWord {self.inner.fetch_hw(adr)}
+}
+
+pub type ReadFn<T> = Box<dyn FnMut(AWord) -> T>;
+pub type WriteFn<T> = Box<dyn FnMut(AWord, T)>;
+pub type TickFn = Box<dyn FnMut(u64)>;
+pub type SaveFn = Box<dyn Fn() -> Vec<u8>>;
+pub type RestoreFn = Box<dyn FnMut(&[u8]) -> Result<(), Box<dyn std::error::Error>>>;
 pub struct FunctionalAddressSpace {
     pub origin: AWord,
     pub length: AWord,
-    pub readb_f: Box<dyn FnMut(AWord) -> AByte>,
-    pub writeb_f: Box<dyn FnMut(AWord, AByte)>,
+    pub readb_f: ReadFn<AByte>,
+    pub writeb_f: WriteFn<AByte>,
+    /// Optional whole halfword and word accesses, given and returning little endian values.
+    /// Without them, and for accesses the bus has to split, the byte functions are used.
+    pub readh_f: Option<ReadFn<AHalfWord>>,
+    pub writeh_f: Option<WriteFn<AHalfWord>>,
+    pub readw_f: Option<ReadFn<AWord>>,
+    pub writew_f: Option<WriteFn<AWord>>,
+    pub tick_f: Option<TickFn>,
+    /// Optional snapshot hooks, the region is treated as stateless without them
+    pub save_f: Option<SaveFn>,
+    pub restore_f: Option<RestoreFn>,
 }
 impl AddressSpace for FunctionalAddressSpace {
     fn origin(&self) -> AWord {self.origin}
     fn len(&self) -> AWord {self.length}
     fn readb(&mut self, adr: AWord) -> AByte {self.readb_f.deref_mut()(adr)}
     fn writeb(&mut self, adr: AWord, x: AByte) {self.writeb_f.deref_mut()(adr, x)}
+    fn read_hw_le(&mut self, adr: AWord) -> AHalfWord {
+        match &mut self.readh_f {
+            Some(readh_f) => readh_f(adr),
+            None => AHalfWord::from_le_bytes([0, 1].map(|offset| self.readb(adr + offset))),
+        }
+    }
+    fn read_w_le(&mut self, adr: AWord) -> AWord {
+        match &mut self.readw_f {
+            Some(readw_f) => readw_f(adr),
+            None => AWord::from_le_bytes([0, 1, 2, 3].map(|offset| self.readb(adr + offset))),
+        }
+    }
+    fn write_hw_le(&mut self, adr: AWord, x: AHalfWord) {
+        match &mut self.writeh_f {
+            Some(writeh_f) => writeh_f(adr, x),
+            None => for (offset, byte) in x.to_le_bytes().into_iter().enumerate() {
+                self.writeb(adr + offset as AWord, byte);
+            },
+        }
+    }
+    fn write_w_le(&mut self, adr: AWord, x: AWord) {
+        match &mut self.writew_f {
+            Some(writew_f) => writew_f(adr, x),
+            None => for (offset, byte) in x.to_le_bytes().into_iter().enumerate() {
+                self.writeb(adr + offset as AWord, byte);
+            },
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        if let Some(tick_f) = &mut self.tick_f {
+            tick_f(cycles);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        if let Some(save_f) = &self.save_f {
+            out.extend(save_f());
+        }
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.restore_f {
+            Some(restore_f) => restore_f(state),
+            None => Ok(()),
+        }
+    }
 }
 #[test]
 fn test_lsb_read() {
@@ -87,7 +705,9 @@ fn test_lookup() {
         buffer: Box::new(mem)
         };
     let mut de = AddressDeMultiplexer::full();
-    de.add_region(Box::new(sample_adr));
+    let space = Box::new(sample_adr);
+    de.add_region(MappedRegion {label: "sample".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space})
+        .unwrap();
 
     assert!(de.lookup(0).is_none());
     assert_eq!(de.lookup(3).unwrap().1, 1);
@@ -100,9 +720,190 @@ fn test_func_adr() {
         origin: 0,
         length: 100,
         readb_f: Box::new(|a| a as AByte),
-        writeb_f: Box::new(|_, _| {})
+        writeb_f: Box::new(|_, _| {}),
+        readh_f: None,
+        writeh_f: None,
+        readw_f: None,
+        writew_f: None,
+        tick_f: None,
+        save_f: None,
+        restore_f: None,
     };
 
     assert_eq!(fa.readb(0), 0);
     assert_eq!(fa.readb(69), 69);
 }
+
+#[test]
+fn test_func_wide_access() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let written = Rc::new(RefCell::new(Vec::new()));
+    let (bytes, words) = (written.clone(), written.clone());
+    let mut fa = FunctionalAddressSpace {
+        origin: 0,
+        length: 100,
+        readb_f: Box::new(|a| a as AByte),
+        writeb_f: Box::new(move |a, x| bytes.borrow_mut().push((a, x as AWord))),
+        readh_f: None,
+        writeh_f: None,
+        readw_f: Some(Box::new(|a| a << 16)),
+        writew_f: Some(Box::new(move |a, x| words.borrow_mut().push((a, x)))),
+        tick_f: None,
+        save_f: None,
+        restore_f: None,
+    };
+
+    // Halfwords fall back to the byte functions, words have their own
+    assert_eq!(fa.read_hw(4), 0x0504);
+    assert_eq!(fa.read_w(4), 0x40000);
+    fa.write_hw(8, 0x1234);
+    fa.write_w(12, 0xdeadbeef);
+    assert_eq!(*written.borrow(), [(8, 0x34), (9, 0x12), (12, 0xdeadbeef)]);
+}
+
+#[test]
+fn test_map_validation() {
+    let ram = |label: &str, origin: AWord, len: usize, priority: i32| MappedRegion {
+        label: label.to_string(),
+        kind: "ram",
+        priority,
+        perm: Permissions::ALL,
+        space: Box::new(BufferMemory {origin, buffer: vec![0; len].into_boxed_slice()}),
+    };
+    let mut de = AddressDeMultiplexer::full();
+    de.add_region(ram("low", 0, 16, 0)).unwrap();
+    de.add_region(ram("top", 0xfffffff0, 16, 0)).unwrap();
+    assert_eq!(de.add_region(ram("past_top", 0xfffffff8, 16, 0)),
+        Err(MapError::Overflow { label: "past_top".to_string() }));
+    assert_eq!(de.add_region(ram("clash", 8, 16, 0)),
+        Err(MapError::Overlap { label: "clash".to_string(), other: "low".to_string() }));
+
+    // Higher priority shadows the lower one
+    de.add_region(ram("window", 8, 4, 1)).unwrap();
+    de.writeb(8, 7);
+    assert_eq!(de.lookup(8).unwrap().0.readb(0), 7);
+    assert_eq!(de.lookup(0xffffffff).unwrap().1, 15);
+}
+
+#[test]
+fn test_permissions() {
+    let mut de = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new([1, 2, 3, 4])});
+    let perm = Permissions::parse("rx").unwrap();
+    de.add_region(MappedRegion {label: "flash".to_string(), kind: "file", priority: 0, perm, space}).unwrap();
+
+    assert_eq!(de.fetch_hw(2), 0x0403);
+    de.writeb(1, 9);
+    assert_eq!(de.take_fault(), Some(BusFault {adr: 1, access: Access::Write, mapped: true}));
+    assert_eq!(de.readb(1), 2);
+    assert_eq!(de.read_w(8), 0);
+    assert_eq!(de.take_fault(), Some(BusFault {adr: 8, access: Access::Read, mapped: false}));
+    assert_eq!(de.take_fault(), None);
+}
+
+#[test]
+fn test_persistent_memory() {
+    let path = std::env::temp_dir().join("cm0-nvram-test.bin").to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let mut nvram = PersistentMemory::open(0, 4, path.clone(), 0xff).unwrap();
+    assert_eq!(nvram.read_w(0), 0xffffffff);
+    nvram.write_hw(2, 0x1234);
+    nvram.flush().unwrap();
+
+    let mut nvram = PersistentMemory::open(0, 4, path.clone(), 0xff).unwrap();
+    assert_eq!(nvram.read_w(0), 0x1234ffff);
+    assert!(PersistentMemory::open(0, 8, path.clone(), 0xff).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_alias() {
+    let mut de = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0x100, buffer: Box::new([0; 8])});
+    de.add_region(MappedRegion {label: "sram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mirror = Alias::new(0x200, 0x20, 4, 3, de.share("sram").unwrap()).unwrap();
+    de.add_region(MappedRegion {label: "mirror".to_string(), kind: "alias", priority: 0, perm: Permissions::ALL, space: Box::new(mirror)}).unwrap();
+    assert!(Alias::new(0, 16, 0, AWord::MAX, de.share("sram").unwrap()).is_err());
+    assert!(Alias::new(0, 8, 0, 5, de.share("sram").unwrap()).is_err());
+
+    de.write_w(0x104, 0x44332211);
+    assert_eq!(de.read_w(0x200), 0x44332211);
+    assert_eq!(de.read_w(0x21c), 0x44332211);
+    de.writeb(0x209, 0x55);
+    assert_eq!(de.readb(0x105), 0x55);
+
+    // The state is only kept once, by the target
+    let mut state = Vec::new();
+    de.save_state(&mut state);
+    de.writeb(0x104, 0);
+    de.restore_state(&state).unwrap();
+    assert_eq!(de.readb(0x200), 0x11);
+}
+
+#[test]
+fn test_fast_path() {
+    // A word straddling two regions, and a higher priority region shadowing part of a lower one
+    let mut de = AddressDeMultiplexer::full();
+    let low = Box::new(BufferMemory {origin: 0, buffer: Box::new([0; 8])});
+    let high = Box::new(BufferMemory {origin: 8, buffer: Box::new([0; 8])});
+    let window = Box::new(BufferMemory {origin: 4, buffer: Box::new([0xaa; 2])});
+    de.add_region(MappedRegion {label: "low".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space: low}).unwrap();
+    de.add_region(MappedRegion {label: "high".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space: high}).unwrap();
+    de.add_region(MappedRegion {label: "window".to_string(), kind: "ram", priority: 1, perm: Permissions::ALL, space: window}).unwrap();
+
+    de.write_w(0, 0x44332211);
+    assert_eq!(de.read_w(0), 0x44332211);
+    assert_eq!(de.read_w(4), 0x0000aaaa);
+    de.write_w(6, 0x88776655);
+    assert_eq!(de.read_hw(6), 0x6655);
+    assert_eq!(de.read_hw(8), 0x8877);
+    assert_eq!(de.take_fault(), None);
+}
+
+/// `cargo test --release bench_word_access -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_word_access() {
+    let mut de = AddressDeMultiplexer::full();
+    for (idx, origin) in [0x0800_0000, 0x2000_0000, 0x4000_0000, 0x0].into_iter().enumerate() {
+        let space = Box::new(BufferMemory {origin, buffer: vec![0; 0x10000].into_boxed_slice()});
+        de.add_region(MappedRegion {label: format!("[{idx}]"), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    }
+    let rounds = 10_000_000u32;
+    let time = |de: &mut AddressDeMultiplexer, read: fn(&mut AddressDeMultiplexer, AWord) -> AWord| {
+        let start = std::time::Instant::now();
+        let mut sum: AWord = 0;
+        for idx in 0..rounds {
+            sum = sum.wrapping_add(read(de, (idx * 4) % 0x10000));
+        }
+        std::hint::black_box(sum);
+        start.elapsed()
+    };
+    let bytes = time(&mut de, |de, adr| AWord::from_le_bytes([0, 1, 2, 3].map(|offset| de.readb(adr + offset))));
+    let words = time(&mut de, |de, adr| de.read_w(adr));
+    println!("{rounds} word reads: bytewise {bytes:?}, fast path {words:?}");
+    assert!(words < bytes);
+}
+
+#[test]
+fn test_endianness() {
+    for endian in [Endian::Little, Endian::Big] {
+        let mut de = AddressDeMultiplexer::full();
+        de.set_endian(endian);
+        let space = Box::new(BufferMemory {origin: 0, buffer: Box::new([0; 8])});
+        de.add_region(MappedRegion {label: "ram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+
+        de.write_w(0, 0x11223344);
+        de.write_hw(6, 0x5566);
+        let bytes: Vec<AByte> = (0..8).map(|adr| de.readb(adr)).collect();
+        match endian {
+            Endian::Little => assert_eq!(bytes, [0x44, 0x33, 0x22, 0x11, 0, 0, 0x66, 0x55]),
+            Endian::Big => assert_eq!(bytes, [0x11, 0x22, 0x33, 0x44, 0, 0, 0x55, 0x66]),
+        }
+        assert_eq!(de.read_w(0), 0x11223344);
+        assert_eq!(de.read_hw(6), 0x5566);
+        // Instructions stay little endian
+        assert_eq!(de.fetch_hw(6), AHalfWord::from_le_bytes([bytes[6], bytes[7]]));
+    }
+}
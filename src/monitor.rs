@@ -0,0 +1,313 @@
+use std::io::{BufRead, Write};
+use crate::adr::AddressSpace;
+use crate::core::{parse_word, AWord};
+use crate::emulator::Emulator;
+use crate::fetch::fetch_instruction;
+use crate::registers::{register_index, LR_IDX, PC_IDX, SP_IDX};
+use crate::reverse::History;
+use crate::snapshot::Snapshot;
+use crate::symbols::Symbols;
+
+const HELP: &str = "\
+step [n]                 execute n instructions(default 1)
+continue                 run until a breakpoint or watchpoint
+rstep [n]                undo n instructions(default 1)
+rcontinue                run backwards to the previous breakpoint or watchpoint
+break [adr|symbol]       add a breakpoint, or list them
+watch [adr|symbol]       stop after writes to an address, or list them
+delete <adr|symbol>      remove a breakpoint or watchpoint
+regs                     show registers
+x/<n><b|h|w><x|d|u> adr  examine memory, eg x/16wx 0x100
+disas [adr|symbol] [n]   disassemble n instructions(default 8)
+set <reg> = <value>      eg set r3 = 5
+regions                  show the memory map
+trace on|off             print every executed instruction
+reset                    go back to the initial state
+snapshot <path>          save the state, and write back non volatile regions
+quit";
+
+/// Line based debugger driven from stdin
+pub struct Monitor {
+    pub emulator: Emulator,
+    history: History,
+    symbols: Symbols,
+    initial: Snapshot,
+    trace: bool,
+}
+
+impl Monitor {
+    pub fn new(emulator: Emulator, symbols: Symbols) -> Self {
+        let initial = Snapshot::capture(&emulator);
+        Self {emulator, history: History::new(1000), symbols, initial, trace: false}
+    }
+
+    pub fn run(&mut self) {
+        println!("Type `help` for a list of commands");
+        self.print_location(self.emulator.pc());
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(cm0) ");
+            std::io::stdout().flush().expect("Failed to flush stdout");
+            let Some(Ok(line)) = lines.next() else { break };
+            match self.execute(line.trim()) {
+                Ok(true) => break,
+                Ok(false) => {},
+                Err(err) => println!("Error: {err}"),
+            }
+        }
+    }
+
+    /// Run a single command, returns whether the monitor should exit
+    pub fn execute(&mut self, line: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(false) };
+        let args: Vec<&str> = words.collect();
+        let count = |default: u64| -> Result<u64, Box<dyn std::error::Error>> {
+            args.first().map_or(Ok(default), |arg| Ok(arg.parse()?))
+        };
+
+        match command {
+            "help" | "h" => println!("{HELP}"),
+            "quit" | "q" => return Ok(true),
+            "step" | "s" => {
+                for _ in 0..count(1)? {
+                    if self.step() {
+                        break;
+                    }
+                }
+                self.print_location(self.emulator.pc());
+            },
+            "continue" | "c" => {
+                while !self.step() {}
+                self.print_location(self.emulator.pc());
+            },
+            "rstep" | "rs" => {
+                for _ in 0..count(1)? {
+                    self.history.step_back(&mut self.emulator)?;
+                }
+                self.print_location(self.emulator.pc());
+            },
+            "rcontinue" | "rc" => {
+                match self.history.reverse_continue(&mut self.emulator)? {
+                    Some(stop) => println!("Stopped by {stop:?} at cycle {}", self.emulator.cycles),
+                    None => println!("Reached the start of history at cycle {}", self.emulator.cycles),
+                }
+                self.print_location(self.emulator.pc());
+            },
+            "break" | "b" => match args.first() {
+                Some(arg) => {
+                    let adr = self.address(arg)?;
+                    self.emulator.breakpoints.push(adr);
+                },
+                None => for &adr in self.emulator.breakpoints.iter() {
+                    println!("{}", self.describe(adr));
+                },
+            },
+            "watch" | "w" => match args.first() {
+                Some(arg) => {
+                    let adr = self.address(arg)?;
+                    self.emulator.watchpoints.push(adr);
+                },
+                None => for &adr in self.emulator.watchpoints.iter() {
+                    println!("{}", self.describe(adr));
+                },
+            },
+            "delete" | "d" => {
+                let adr = self.address(args.first().ok_or("Expected an address")?)?;
+                self.emulator.breakpoints.retain(|&b| b != adr);
+                self.emulator.watchpoints.retain(|&w| w != adr);
+            },
+            "regs" | "r" => self.print_registers(),
+            "disas" => {
+                let mut adr = match args.first() {
+                    Some(arg) => self.address(arg)?,
+                    None => self.emulator.pc(),
+                };
+                let count: u64 = args.get(1).map_or(Ok(8), |arg| arg.parse())?;
+                for _ in 0..count {
+                    adr = self.print_location(adr);
+                }
+            },
+            "set" => {
+                // Accepts both `set r3 = 5` and `set r3 5`
+                let args: Vec<&str> = args.into_iter().filter(|&arg| arg != "=").collect();
+                let [reg, value] = args[..] else { return Err("Expected `set <reg> = <value>`".into()) };
+                let idx = register_index(reg).ok_or(format!("Unknown register {reg}"))?;
+                let value = parse_word(value).ok_or(format!("Invalid value {value}"))?;
+                self.emulator.cpu.r[idx] = match idx {
+                    // Keep the +2 convention of the PC
+                    PC_IDX => value.wrapping_add(2),
+                    _ => value,
+                };
+                self.history.forget_from(self.emulator.cycles);
+            },
+            "regions" => print!("{}", self.emulator.memory),
+            "trace" => match args.first().copied() {
+                Some("on") => self.trace = true,
+                Some("off") => self.trace = false,
+                _ => return Err("Expected `trace on` or `trace off`".into()),
+            },
+            "snapshot" => {
+                let path = args.first().ok_or("Expected a path")?;
+                Snapshot::capture(&self.emulator).save(path)?;
+                self.emulator.memory.flush()?;
+                println!("Saved snapshot at cycle {}", self.emulator.cycles);
+            },
+            "reset" => {
+                self.initial.restore(&mut self.emulator)?;
+                self.history.forget_from(0);
+                self.print_location(self.emulator.pc());
+            },
+            _ if command.starts_with("x") => {
+                let spec = command.strip_prefix("x").unwrap_or_default().trim_start_matches('/');
+                let adr = self.address(args.first().ok_or("Expected an address")?)?;
+                self.examine(spec, adr)?;
+            },
+            _ => return Err(format!("Unknown command {command}, try `help`").into()),
+        }
+        Ok(false)
+    }
+
+    /// Returns whether execution stopped
+    fn step(&mut self) -> bool {
+        if self.trace {
+            self.print_location(self.emulator.pc());
+        }
+        match self.history.step(&mut self.emulator) {
+            Some(stop) => {
+                println!("Stopped by {stop:?} at cycle {}", self.emulator.cycles);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn address(&self, text: &str) -> Result<AWord, Box<dyn std::error::Error>> {
+        parse_word(text).or_else(|| self.symbols.lookup(text))
+            .ok_or(format!("Unknown address or symbol {text}").into())
+    }
+    fn describe(&self, adr: AWord) -> String {
+        match self.symbols.name_at(adr) {
+            Some(name) => format!("{adr:#010x} <{name}>"),
+            None => format!("{adr:#010x}"),
+        }
+    }
+    fn is_mapped(&self, adr: AWord) -> bool {
+        self.emulator.memory.regions().any(|region| region.contains(adr))
+    }
+
+    /// Print the instruction at `adr`, returns the address of the following one
+    fn print_location(&mut self, adr: AWord) -> AWord {
+        if !self.is_mapped(adr) || !self.is_mapped(adr.wrapping_add(1)) {
+            println!("{} <unmapped>", self.describe(adr));
+            return adr.wrapping_add(2);
+        }
+        let mut ip = adr.wrapping_add(2);
+        let ins = fetch_instruction(&mut ip, &mut self.emulator.memory.debug_view());
+        let name = self.emulator.instructions.identify(&ins).unwrap_or("<unknown>");
+        let raw = match ins.ext {
+            Some(ext) => format!("{:04x} {:04x}", ins.hdr, ext),
+            None => format!("{:04x}", ins.hdr),
+        };
+        println!("{:<30} {raw:<10} {name}", self.describe(adr));
+        ip.wrapping_sub(2)
+    }
+
+    fn print_registers(&self) {
+        let cpu = &self.emulator.cpu;
+        for row in 0..4 {
+            let line: Vec<String> = (0..4).map(|col| {
+                let idx = row * 4 + col;
+                let name = match idx {
+                    SP_IDX => "sp".to_string(),
+                    LR_IDX => "lr".to_string(),
+                    PC_IDX => "pc".to_string(),
+                    _ => format!("r{idx}"),
+                };
+                let value = match idx {
+                    PC_IDX => self.emulator.pc(),
+                    _ => cpu.r[idx],
+                };
+                format!("{name:>3} {value:#010x}")
+            }).collect();
+            println!("{}", line.join("  "));
+        }
+        let flag = |set: bool, name: char| if set {name} else {'-'};
+        println!("flags {}{}{}{}  cycles {}",
+            flag(cpu.n, 'N'), flag(cpu.z, 'Z'), flag(cpu.c, 'C'), flag(cpu.v, 'V'), self.emulator.cycles);
+    }
+
+    /// `spec` is the part after `x/`, eg `16wx`
+    fn examine(&mut self, spec: &str, adr: AWord) -> Result<(), Box<dyn std::error::Error>> {
+        let digits = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let count: u32 = if digits == 0 {1} else {spec[..digits].parse()?};
+        let mut size = 4;
+        let mut format = 'x';
+        for c in spec[digits..].chars() {
+            match c {
+                'b' => size = 1,
+                'h' => size = 2,
+                'w' => size = 4,
+                'x' | 'd' | 'u' => format = c,
+                _ => return Err(format!("Unknown format {c}").into()),
+            }
+        }
+
+        let per_line = 16 / size;
+        for idx in 0..count {
+            let at = adr.wrapping_add(idx * size);
+            if !(0..size).all(|offset| self.is_mapped(at.wrapping_add(offset))) {
+                return Err(format!("Cannot access memory at {at:#010x}").into());
+            }
+            let mut memory = self.emulator.memory.debug_view();
+            let value = match size {
+                1 => memory.readb(at) as AWord,
+                2 => memory.read_hw(at) as AWord,
+                _ => memory.read_w(at),
+            };
+            if idx % per_line == 0 {
+                print!("{}:", self.describe(at));
+            }
+            match (format, size) {
+                ('x', _) => print!(" {value:#0width$x}", width = 2 + 2 * size as usize),
+                ('d', 1) => print!(" {}", value as u8 as i8),
+                ('d', 2) => print!(" {}", value as u16 as i16),
+                ('d', _) => print!(" {}", value as i32),
+                _ => print!(" {value}"),
+            }
+            if idx % per_line == per_line - 1 || idx + 1 == count {
+                println!();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_monitor_commands() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    // movs r0, #0; loop: adds r0, #1; strb r0, [r1]; b loop
+    let program = [0x00, 0x20, 0x01, 0x30, 0x08, 0x70, 0xfc, 0xe7];
+    let mut buffer = [0u8; 64];
+    buffer[..program.len()].copy_from_slice(&program);
+    let mut memory = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+    memory.add_region(MappedRegion {label: "flash".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut monitor = Monitor::new(Emulator::new(memory), Symbols::default());
+
+    monitor.execute("set r1 = 0x20").unwrap();
+    monitor.execute("break 6").unwrap();
+    monitor.execute("continue").unwrap();
+    assert_eq!(monitor.emulator.pc(), 6);
+    assert_eq!(monitor.emulator.memory.readb(0x20), 1);
+    monitor.execute("step 3").unwrap();
+    assert_eq!(monitor.emulator.pc(), 6);
+    monitor.execute("rstep").unwrap();
+    assert_eq!(monitor.emulator.pc(), 4);
+    monitor.execute("reset").unwrap();
+    assert_eq!(monitor.emulator.cycles, 0);
+    assert!(monitor.execute("x/4bx 0x10000").is_err());
+    assert!(monitor.execute("set r16 = 1").is_err());
+    assert!(monitor.execute("quit").unwrap());
+}
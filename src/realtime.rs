@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+/// How far behind host time the emulator can fall before the pacer starts over, rather than
+/// running flat out to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Holds the emulator back so its cycles go by at the core frequency in host time
+pub struct Pacer {
+    frequency: u64,
+    start: Instant,
+    start_cycles: u64,
+    /// Cycle count of the next look at the host clock
+    next_check: u64,
+}
+
+impl Pacer {
+    /// Starting at cycle `cycles`
+    pub fn new(frequency: u64, cycles: u64) -> Self {
+        Self {frequency, start: Instant::now(), start_cycles: cycles, next_check: cycles}
+    }
+    /// Host time since the start `cycles` should take
+    fn due(&self, cycles: u64) -> Duration {
+        let nanos = cycles.saturating_sub(self.start_cycles) as u128 * 1_000_000_000 / self.frequency as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+    /// Sleep until host time catches up with `cycles`, looking at the clock once per emulated
+    /// millisecond
+    pub fn pace(&mut self, cycles: u64) {
+        if cycles < self.next_check {
+            return;
+        }
+        self.next_check = cycles + (self.frequency / 1000).max(1);
+        let (due, elapsed) = (self.due(cycles), self.start.elapsed());
+        if elapsed < due {
+            std::thread::sleep(due - elapsed);
+        } else if MAX_LAG < elapsed - due {
+            log::debug!("Real time fell {:?} behind at cycle {cycles}", elapsed - due);
+            (self.start, self.start_cycles) = (Instant::now(), cycles);
+        }
+    }
+}
+
+#[test]
+fn test_pacer() {
+    // 50 ms worth of cycles at 10 kHz
+    let mut pacer = Pacer::new(10_000, 1000);
+    let start = Instant::now();
+    for cycles in 1000..=1500 {
+        pacer.pace(cycles);
+    }
+    assert!(Duration::from_millis(50) <= start.elapsed());
+    assert_eq!(pacer.next_check, 1510);
+}
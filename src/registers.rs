@@ -4,7 +4,7 @@ pub const SP_IDX: usize = 13;
 pub const LR_IDX: usize = 14;
 pub const PC_IDX: usize = 15;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Registers {
     pub r: [AWord; 16], // General Purpose Registers
 
@@ -17,3 +17,13 @@ pub struct Registers {
     // Special(could be memory accessed)
     // CPUID, ICSR, AIRCR, CCR, PRIMASK, CONTROL, CPSR
 }
+
+/// Index of a register by name, eg `r3`, `sp`, `lr` or `pc`
+pub fn register_index(name: &str) -> Option<usize> {
+    match name {
+        "sp" => Some(SP_IDX),
+        "lr" => Some(LR_IDX),
+        "pc" => Some(PC_IDX),
+        _ => name.strip_prefix("r")?.parse().ok().filter(|&idx| idx < 16),
+    }
+}
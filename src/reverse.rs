@@ -0,0 +1,133 @@
+use crate::emulator::{Emulator, Stop};
+use crate::snapshot::Snapshot;
+
+/// Past this many checkpoints every other one is dropped and the interval doubled
+const MAX_CHECKPOINTS: usize = 256;
+
+/// Periodic checkpoints that let the emulator run backwards
+///
+/// Going back restores the closest earlier checkpoint and replays forward, which relies on
+/// `Emulator::step` being deterministic. Side effects of `func` regions(eg printing) are repeated
+/// during a replay.
+pub struct History {
+    interval: u64,
+    checkpoints: Vec<Snapshot>,
+}
+
+impl History {
+    pub fn new(interval: u64) -> Self {
+        assert!(0 < interval, "Checkpoint interval must be positive");
+        Self {interval, checkpoints: Vec::new()}
+    }
+
+    /// Step forward, checkpointing first when one is due
+    pub fn step(&mut self, emulator: &mut Emulator) -> Option<Stop> {
+        self.record(emulator);
+        emulator.step()
+    }
+    fn record(&mut self, emulator: &Emulator) {
+        let due = emulator.cycles.is_multiple_of(self.interval) || self.checkpoints.is_empty();
+        if !due {
+            return;
+        }
+        if let Err(idx) = self.checkpoints.binary_search_by_key(&emulator.cycles, |c| c.cycles) {
+            self.checkpoints.insert(idx, Snapshot::capture(emulator));
+        }
+        if MAX_CHECKPOINTS < self.checkpoints.len() {
+            // Even indices are kept, so the start of history stays reachable
+            let mut idx = 0;
+            self.checkpoints.retain(|_| {idx += 1; idx % 2 == 1});
+            self.interval *= 2;
+        }
+    }
+
+    /// Restore the state as it was after the last step ending by `cycle`
+    pub fn seek(&mut self, emulator: &mut Emulator, cycle: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let checkpoint = self.checkpoints.iter().rev().find(|c| c.cycles <= cycle)
+            .ok_or(format!("No history before cycle {cycle}"))?;
+        checkpoint.restore(emulator)?;
+        let mut start = emulator.cycles;
+        while emulator.cycles < cycle {
+            start = emulator.cycles;
+            self.step(emulator);
+        }
+        // A step the core was stalled in can take several cycles, then replay up to the one before
+        if cycle < emulator.cycles {
+            let checkpoint = self.checkpoints.iter().rev().find(|c| c.cycles <= start).unwrap();
+            checkpoint.restore(emulator)?;
+            while emulator.cycles < start {
+                self.step(emulator);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop checkpoints from `cycle` on, needed whenever the state is changed from outside
+    pub fn forget_from(&mut self, cycle: u64) {
+        self.checkpoints.retain(|c| c.cycles < cycle);
+    }
+
+    pub fn step_back(&mut self, emulator: &mut Emulator) -> Result<(), Box<dyn std::error::Error>> {
+        let cycle = emulator.cycles.checked_sub(1).ok_or("Already at cycle 0")?;
+        self.seek(emulator, cycle)
+    }
+
+    /// Run backwards to the last breakpoint or watchpoint hit, or to the start of history when
+    /// there was none
+    pub fn reverse_continue(&mut self, emulator: &mut Emulator) -> Result<Option<Stop>, Box<dyn std::error::Error>> {
+        let end = emulator.cycles;
+        let mut limit = end;
+        // Replay one checkpoint interval at a time, latest first
+        for idx in (0..self.checkpoints.len()).rev() {
+            if limit <= self.checkpoints[idx].cycles {
+                continue;
+            }
+            self.checkpoints[idx].restore(emulator)?;
+            let mut last = None;
+            while emulator.cycles < limit {
+                // A stop right where we started from doesn't count
+                if let Some(stop) = emulator.step() && emulator.cycles < end {
+                    last = Some((emulator.cycles, stop));
+                }
+            }
+            if let Some((cycle, stop)) = last {
+                self.seek(emulator, cycle)?;
+                return Ok(Some(stop));
+            }
+            limit = self.checkpoints[idx].cycles;
+        }
+        let start = self.checkpoints.first().map_or(end, |c| c.cycles);
+        self.seek(emulator, start)?;
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_reverse_continue() {
+    use crate::adr::AddressSpace;
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    // movs r0, #0; loop: adds r0, #1; strb r0, [r1]; b loop
+    let program = [0x00, 0x20, 0x01, 0x30, 0x08, 0x70, 0xfc, 0xe7];
+    let mut buffer = [0u8; 64];
+    buffer[..program.len()].copy_from_slice(&program);
+    let mut memory = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)});
+    memory.add_region(MappedRegion {label: "flash".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut emulator = Emulator::new(memory);
+    emulator.cpu.r[1] = 32;
+    let mut history = History::new(4);
+
+    for _ in 0..50 {
+        history.step(&mut emulator);
+    }
+    let r0 = emulator.cpu.r[0];
+    history.step_back(&mut emulator).unwrap();
+    assert_eq!(emulator.cycles, 49);
+
+    emulator.watchpoints.push(32);
+    let stop = history.reverse_continue(&mut emulator).unwrap();
+    assert_eq!(stop, Some(crate::emulator::Stop::Watchpoint(32)));
+    assert_eq!(emulator.cycles % 3, 0);
+    assert_eq!(emulator.cpu.r[0], r0 - 1);
+    assert_eq!(emulator.memory.readb(32) as u32, r0 - 1);
+}
@@ -0,0 +1,173 @@
+use crate::adr::AddressSpace;
+use crate::context::SharedContext;
+use crate::core::*;
+
+// Register offsets
+/// Seconds since 1970-01-01 00:00 UTC, writing sets the time
+const SECONDS: AWord = 0x00;
+/// Flags once SECONDS reaches it
+const ALARM: AWord = 0x04;
+/// Interrupt enables, laid out like FLAGS
+const CTRL: AWord = 0x08;
+/// Bit 0 alarm, bit 1 a second passed, write 1 to clear
+const FLAGS: AWord = 0x0c;
+/// Read only, `year << 16 | month << 8 | day`
+const DATE: AWord = 0x10;
+/// Read only, `weekday << 24 | hour << 16 | minute << 8 | second`, Sunday is 0
+const TIME: AWord = 0x14;
+const RTC_LEN: AWord = 0x18;
+
+const FLAG_ALARM: AWord = 1 << 0;
+const FLAG_SECOND: AWord = 1 << 1;
+
+/// Year, month and day of a day counted from 1970-01-01
+fn civil(days: u32) -> (AWord, AWord, AWord) {
+    // Years starting in March, so the leap day comes last
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {shifted_month + 3} else {shifted_month - 9};
+    (era * 400 + year_of_era + (month <= 2) as AWord, month, day)
+}
+
+/// A real time clock counting seconds of emulated time, with an alarm and a calendar
+///
+/// Time follows the cycle count at `frequency`, so runs starting from a fixed epoch are
+/// repeatable. Like an RTC in a backup domain it keeps running through system resets.
+pub struct Rtc {
+    pub origin: AWord,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    pub frequency: u64,
+    /// SECONDS as of the cycle `since`
+    base: AWord,
+    since: u64,
+    alarm: AWord,
+    ctrl: AWord,
+    flags: AWord,
+    /// SECONDS as of the last tick
+    last: AWord,
+}
+
+impl Rtc {
+    /// Starting at `epoch` seconds since 1970
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, frequency: u64, epoch: AWord) -> Self {
+        let since = context.borrow().cycles;
+        Self {origin, context, irq, frequency, base: epoch, since, alarm: 0, ctrl: 0, flags: 0, last: epoch}
+    }
+    fn seconds(&self) -> AWord {
+        let elapsed = self.context.borrow().cycles.saturating_sub(self.since) / self.frequency;
+        self.base.wrapping_add(elapsed as AWord)
+    }
+    fn date(&self) -> AWord {
+        let (year, month, day) = civil(self.seconds() / 86_400);
+        year << 16 | month << 8 | day
+    }
+    fn time(&self) -> AWord {
+        let seconds = self.seconds();
+        let weekday = (seconds / 86_400 + 4) % 7;
+        let of_day = seconds % 86_400;
+        weekday << 24 | (of_day / 3600) << 16 | (of_day / 60 % 60) << 8 | (of_day % 60)
+    }
+}
+
+impl AddressSpace for Rtc {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {RTC_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            SECONDS => self.seconds(),
+            ALARM => self.alarm,
+            CTRL => self.ctrl,
+            FLAGS => self.flags,
+            DATE => self.date(),
+            TIME => self.time(),
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let lane = |word: AWord| word & !(0xff << shift) | (x as AWord) << shift;
+        match adr & !3 {
+            SECONDS => {
+                // The new second starts now
+                self.base = lane(self.seconds());
+                self.since = self.context.borrow().cycles;
+                self.last = self.base;
+            },
+            ALARM => self.alarm = lane(self.alarm),
+            CTRL => self.ctrl = lane(self.ctrl),
+            FLAGS => self.flags &= !((x as AWord) << shift),
+            _ => {},
+        }
+    }
+    fn tick(&mut self, _cycles: u64) {
+        let now = self.seconds();
+        if now != self.last {
+            self.flags |= FLAG_SECOND;
+            // Reached the alarm since the last tick
+            if self.alarm.wrapping_sub(self.last).wrapping_sub(1) < now.wrapping_sub(self.last) {
+                self.flags |= FLAG_ALARM;
+            }
+            self.last = now;
+        }
+        if let Some(irq) = self.irq && self.flags & self.ctrl != 0 {
+            self.context.borrow_mut().nvic.raise(irq);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for word in [self.base, self.alarm, self.ctrl, self.flags, self.last] {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(self.since.to_le_bytes());
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 5 * 4 + 8 {
+            return Err("Invalid RTC state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[idx * 4..idx * 4 + 4].try_into().unwrap());
+        (self.base, self.alarm, self.ctrl, self.flags, self.last) = (word(0), word(1), word(2), word(3), word(4));
+        self.since = u64::from_le_bytes(state[20..].try_into().unwrap());
+        Ok(())
+    }
+    fn non_volatile(&self) -> bool {true}
+}
+
+#[test]
+fn test_rtc() {
+    assert_eq!(civil(0), (1970, 1, 1));
+    assert_eq!(civil(11_016), (2000, 2, 29));
+    assert_eq!(civil(19_782), (2024, 2, 29));
+
+    let context = SharedContext::default();
+    // 2024-02-29 23:59:58, a Thursday, at 10 cycles a second
+    let mut rtc = Rtc::new(0, context.clone(), Some(9), 10, 1_709_251_198);
+    assert_eq!(rtc.read_w_le(DATE), 2024 << 16 | 2 << 8 | 29);
+    assert_eq!(rtc.read_w_le(TIME), 4 << 24 | 23 << 16 | 59 << 8 | 58);
+    rtc.write_w_le(ALARM, 1_709_251_200);
+    rtc.write_w_le(CTRL, FLAG_ALARM);
+
+    context.borrow_mut().cycles = 15;
+    rtc.tick(15);
+    assert_eq!(rtc.read_w_le(FLAGS), FLAG_SECOND);
+    assert_eq!(context.borrow().nvic.pending, 0);
+    context.borrow_mut().cycles = 20;
+    rtc.tick(5);
+    assert_eq!(rtc.read_w_le(FLAGS), FLAG_SECOND | FLAG_ALARM);
+    assert_eq!(context.borrow().nvic.pending, 1 << 9);
+    assert_eq!(rtc.read_w_le(DATE), 2024 << 16 | 3 << 8 | 1);
+    assert_eq!(rtc.read_w_le(TIME), 5 << 24);
+
+    // Setting the time restarts the second
+    context.borrow_mut().cycles = 25;
+    rtc.write_w_le(SECONDS, 86_400 - 1);
+    context.borrow_mut().cycles = 34;
+    assert_eq!(rtc.read_w_le(SECONDS), 86_400 - 1);
+    context.borrow_mut().cycles = 35;
+    assert_eq!(rtc.read_w_le(DATE), 1970 << 16 | 1 << 8 | 2);
+}
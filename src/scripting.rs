@@ -0,0 +1,119 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Limits for the Lua code of a config, eg `--lua-memory 1000000 --lua-libs string,math`
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// Bytes the Lua state may allocate
+    pub memory_limit: Option<usize>,
+    /// VM instructions for the config itself and for each callback into it
+    pub instruction_limit: Option<u64>,
+    pub libs: mlua::StdLib,
+}
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {memory_limit: None, instruction_limit: None, libs: mlua::StdLib::ALL_SAFE}
+    }
+}
+impl Sandbox {
+    /// Comma separated standard libraries, eg `table,string,math`
+    pub fn parse_libs(text: &str) -> Option<mlua::StdLib> {
+        text.split(',').filter(|name| !name.is_empty()).try_fold(mlua::StdLib::NONE, |libs, name| {
+            Some(libs | match name {
+                "coroutine" => mlua::StdLib::COROUTINE,
+                "table" => mlua::StdLib::TABLE,
+                "io" => mlua::StdLib::IO,
+                "os" => mlua::StdLib::OS,
+                "string" => mlua::StdLib::STRING,
+                "utf8" => mlua::StdLib::UTF8,
+                "math" => mlua::StdLib::MATH,
+                "package" => mlua::StdLib::PACKAGE,
+                _ => return None,
+            })
+        })
+    }
+}
+
+/// The Lua state of a machine, shared by its func regions and hooks and dropped with them
+#[derive(Clone)]
+pub struct Script {
+    pub lua: mlua::Lua,
+    /// Instructions used since the last call into Lua
+    used: Rc<Cell<u64>>,
+}
+
+impl Script {
+    pub fn new(sandbox: &Sandbox) -> mlua::Result<Self> {
+        let lua = mlua::Lua::new_with(sandbox.libs, mlua::LuaOptions::default())?;
+        if let Some(limit) = sandbox.memory_limit {
+            lua.set_memory_limit(limit)?;
+        }
+        let used = Rc::new(Cell::new(0));
+        if let Some(limit) = sandbox.instruction_limit {
+            // Checked in batches, the hook is too slow to run on every instruction
+            let batch = limit.clamp(1, 1000);
+            let hook_used = used.clone();
+            let triggers = mlua::HookTriggers::new().every_nth_instruction(batch as u32);
+            lua.set_hook(triggers, move |_, _| {
+                hook_used.set(hook_used.get() + batch);
+                match hook_used.get() <= limit {
+                    true => Ok(mlua::VmState::Continue),
+                    false => Err(mlua::Error::runtime(format!("Instruction limit of {limit} exceeded"))),
+                }
+            })?;
+        }
+        Ok(Self {lua, used})
+    }
+
+    /// Call into Lua with a fresh instruction budget
+    pub fn call<R: mlua::FromLuaMulti>(&self, function: &mlua::Function, args: impl mlua::IntoLuaMulti) -> mlua::Result<R> {
+        self.used.set(0);
+        function.call(args)
+    }
+    pub fn eval<R: mlua::FromLuaMulti>(&self, chunk: mlua::Chunk) -> mlua::Result<R> {
+        self.used.set(0);
+        chunk.eval()
+    }
+}
+
+#[test]
+fn test_sandbox() {
+    use crate::adr::AddressSpace;
+    use crate::emulator::{Emulator, Stop};
+    let load = |name: &str, lua_code: &str, sandbox: Sandbox| {
+        let path = std::env::temp_dir().join(format!("cm0-sandbox-test-{name}.lua"));
+        std::fs::write(&path, lua_code).unwrap();
+        let result = crate::config::load(path.to_str().unwrap(), &sandbox);
+        std::fs::remove_file(&path).unwrap();
+        result
+    };
+    let config = "use_config = true addresses = {
+        ram = { origin = 0, type = \"ram\", len = 0x100 },
+        spin = { origin = 0x100, type = \"func\", len = 4,
+            readb = function(adr) while adr % 4 == 0 do end return 7 end,
+            writeb = function(adr, x) end },
+    }";
+    let limited = Sandbox {instruction_limit: Some(10_000), ..Default::default()};
+
+    // Machines are independent, dropping one leaves the other working
+    let first = load("spin", config, limited.clone()).unwrap();
+    let mut memory = load("spin", config, limited).unwrap().memory;
+    drop(first);
+    assert_eq!(memory.readb(0x101), 7);
+
+    // ldrb r0, [r1]
+    memory.write_hw(0, 0x7808);
+    let mut emulator = Emulator::new(memory);
+    emulator.cpu.r[1] = 0x100;
+    let stop = emulator.step();
+    let Some(Stop::Error(error)) = stop else { panic!("Expected an error, got {stop:?}") };
+    assert!(error.starts_with("Region `spin`: `readb` failed") && error.contains("Instruction limit"), "{error}");
+    assert_eq!(emulator.pc(), 0);
+
+    let hungry = "use_config = true addresses = {} local t = {} for i = 1, 1000000 do t[i] = i end";
+    let small = Sandbox {memory_limit: Some(1 << 20), ..Default::default()};
+    assert!(load("memory", hungry, small).is_err());
+    let bare = Sandbox {libs: Sandbox::parse_libs("table").unwrap(), ..Default::default()};
+    assert!(load("libs", "use_config = true addresses = {} string.len(\"\")", bare).is_err());
+    assert!(Sandbox::parse_libs("table,debug").is_none());
+}
@@ -0,0 +1,188 @@
+use crate::adr::AddressSpace;
+use crate::core::AWord;
+use crate::emulator::Emulator;
+use crate::exceptions::Nvic;
+use crate::registers::Registers;
+
+const MAGIC: &[u8; 8] = b"CM0SNAP\0";
+/// Bump whenever the layout below changes, old snapshots are rejected rather than misread
+const VERSION: u32 = 3;
+
+/// Complete emulator state, enough to resume execution bit-exactly
+///
+/// File layout(all little endian):
+/// magic, version: u32, r0-r15: 16 * u32, nzcv: u8, cycles: u64, NVIC enabled, pending and
+/// active: 3 * u32, DMA requests: u32, reset causes: u32, memory length: u64, memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub cpu: Registers,
+    pub cycles: u64,
+    /// The parts of the `CoreContext` that outlive a step
+    pub nvic: Nvic,
+    pub requests: AWord,
+    pub resets: AWord,
+    /// Opaque blob produced by `AddressSpace::save_state`
+    pub memory: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn capture(emulator: &Emulator) -> Self {
+        let mut memory = Vec::new();
+        emulator.memory.save_state(&mut memory);
+        let context = emulator.memory.context();
+        let context = context.borrow();
+        Self {
+            cpu: emulator.cpu.clone(),
+            cycles: emulator.cycles,
+            nvic: context.nvic,
+            requests: context.requests,
+            resets: context.resets,
+            memory,
+        }
+    }
+    pub fn restore(&self, emulator: &mut Emulator) -> Result<(), Box<dyn std::error::Error>> {
+        emulator.memory.restore_state(&self.memory)?;
+        emulator.cpu = self.cpu.clone();
+        emulator.cycles = self.cycles;
+        let context = emulator.memory.context();
+        let mut context = context.borrow_mut();
+        (context.nvic, context.requests, context.resets) = (self.nvic, self.requests, self.resets);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 100);
+        out.extend(MAGIC);
+        out.extend(VERSION.to_le_bytes());
+        for r in self.cpu.r {
+            out.extend(r.to_le_bytes());
+        }
+        let flags = [self.cpu.n, self.cpu.z, self.cpu.c, self.cpu.v].iter()
+            .fold(0u8, |acc, &flag| (acc << 1) | flag as u8);
+        out.push(flags);
+        out.extend(self.cycles.to_le_bytes());
+        for word in [self.nvic.enabled, self.nvic.pending, self.nvic.active, self.requests, self.resets] {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend((self.memory.len() as u64).to_le_bytes());
+        out.extend(&self.memory);
+        out
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = StateReader::new(bytes, "Truncated snapshot");
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("Not a snapshot file".into());
+        }
+        let version = reader.word()?;
+        if version != VERSION {
+            return Err(format!("Unsupported snapshot version {version}, expected {VERSION}").into());
+        }
+        let mut cpu = Registers::default();
+        for r in cpu.r.iter_mut() {
+            *r = reader.word()?;
+        }
+        let flags = reader.take(1)?[0];
+        cpu.n = flags & 0b1000 != 0;
+        cpu.z = flags & 0b0100 != 0;
+        cpu.c = flags & 0b0010 != 0;
+        cpu.v = flags & 0b0001 != 0;
+        let cycles = reader.u64()?;
+        let nvic = Nvic {enabled: reader.word()?, pending: reader.word()?, active: reader.word()?};
+        let (requests, resets) = (reader.word()?, reader.word()?);
+        let memory_len = reader.u64()? as usize;
+        let memory = reader.take(memory_len)?.to_vec();
+        Ok(Self {cpu, cycles, nvic, requests, resets, memory})
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+/// Reads saved state front to back, any read past the end or bytes left over fail with `error`
+pub struct StateReader<'a> {
+    rest: &'a [u8],
+    error: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(state: &'a [u8], error: &'static str) -> Self {
+        Self {rest: state, error}
+    }
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        let (taken, rest) = self.rest.split_at_checked(len).ok_or(self.error)?;
+        self.rest = rest;
+        Ok(taken)
+    }
+    pub fn word(&mut self) -> Result<AWord, Box<dyn std::error::Error>> {
+        Ok(AWord::from_le_bytes(self.take(4)?.try_into()?))
+    }
+    pub fn u64(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+    /// Bytes preceded by their count as a word
+    pub fn counted(&mut self) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        let len = self.word()? as usize;
+        self.take(len)
+    }
+    /// Fails unless everything was read
+    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.rest.is_empty() {
+            true => Ok(()),
+            false => Err(self.error.into()),
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions};
+    let mut memory = AddressDeMultiplexer::full();
+    let space = Box::new(BufferMemory {origin: 0, buffer: Box::new([0; 8])});
+    memory.add_region(MappedRegion {label: "ram".to_string(), kind: "ram", priority: 0, perm: Permissions::ALL, space}).unwrap();
+    let mut emulator = Emulator::new(memory);
+    emulator.cpu.r[3] = 0xdeadbeef;
+    emulator.cpu.c = true;
+    emulator.cycles = 42;
+    emulator.memory.write_w(4, 0x12345678);
+
+    let snapshot = Snapshot::from_bytes(&Snapshot::capture(&emulator).to_bytes()).unwrap();
+    emulator.cpu.r[3] = 0;
+    emulator.cpu.c = false;
+    emulator.cycles = 0;
+    emulator.memory.write_w(4, 0);
+    snapshot.restore(&mut emulator).unwrap();
+
+    assert_eq!(emulator.cpu.r[3], 0xdeadbeef);
+    assert!(emulator.cpu.c);
+    assert_eq!(emulator.cycles, 42);
+    assert_eq!(emulator.memory.read_w(4), 0x12345678);
+}
+
+#[test]
+fn test_snapshot_context() {
+    use crate::context::ResetCause;
+    use crate::memory::AddressDeMultiplexer;
+    let mut emulator = Emulator::new(AddressDeMultiplexer::full());
+    let context = emulator.memory.context();
+    {
+        let mut context = context.borrow_mut();
+        context.nvic.raise(3);
+        context.nvic.enabled = 1 << 3 | 1 << 5;
+        context.nvic.active = 21;
+        context.requests = 1 << 2;
+        context.resets |= ResetCause::Watchdog as AWord;
+    }
+    let saved = (context.borrow().nvic, context.borrow().requests, context.borrow().resets);
+
+    let snapshot = Snapshot::from_bytes(&Snapshot::capture(&emulator).to_bytes()).unwrap();
+    *context.borrow_mut() = Default::default();
+    snapshot.restore(&mut emulator).unwrap();
+
+    assert_eq!((context.borrow().nvic, context.borrow().requests, context.borrow().resets), saved);
+    assert_eq!(context.borrow().nvic.pending, 1 << 3);
+}
@@ -0,0 +1,424 @@
+use std::collections::VecDeque;
+use crate::adr::{restore_devices, save_devices, AddressSpace, DeviceState};
+use crate::context::{DmaLines, SharedContext};
+use crate::core::*;
+use crate::memory::{BufferMemory, PersistentMemory};
+use crate::snapshot::StateReader;
+use crate::vcd::Probe;
+
+// Register offsets
+const DATA: AWord = 0x00;
+const STATUS: AWord = 0x04;
+const CTRL: AWord = 0x08;
+/// Chip select lines, bit n selects device n
+const CS: AWord = 0x0c;
+/// Cycles per bit, 0 exchanges bytes without delay
+const DIV: AWord = 0x10;
+const SPI_LEN: AWord = 0x14;
+
+const STATUS_RXNE: AWord = 1 << 0;
+/// Every byte written was exchanged
+const STATUS_TXE: AWord = 1 << 1;
+const STATUS_TXF: AWord = 1 << 2;
+/// A byte was received while the RX FIFO was full and was lost, write 1 to clear
+const STATUS_OVR: AWord = 1 << 3;
+const CTRL_RXIE: AWord = 1 << 0;
+const CTRL_TXEIE: AWord = 1 << 1;
+
+/// A device on the other side of the bus, eg a flash chip or a display
+///
+/// Bytes are exchanged full duplex, a device that isn't selected sees none of them.
+pub trait SpiDevice: DeviceState {
+    /// Chip select became active, a transaction starts
+    fn select(&mut self) {}
+    /// Take the byte sent by the controller and return the one sent back
+    fn transfer(&mut self, mosi: u8) -> u8;
+    /// Chip select became inactive, the transaction ends
+    fn deselect(&mut self) {}
+}
+
+/// Sends back every byte it receives, MISO wired to MOSI
+pub struct Loopback;
+impl SpiDevice for Loopback {
+    fn transfer(&mut self, mosi: u8) -> u8 {mosi}
+}
+impl DeviceState for Loopback {}
+
+// Commands of a JEDEC serial NOR flash
+const WRITE_ENABLE: u8 = 0x06;
+const WRITE_DISABLE: u8 = 0x04;
+const READ_STATUS: u8 = 0x05;
+const READ_ID: u8 = 0x9f;
+const READ: u8 = 0x03;
+/// Like `READ` with a dummy byte after the address
+const FAST_READ: u8 = 0x0b;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const BLOCK_ERASE: u8 = 0xd8;
+const CHIP_ERASE: u8 = 0xc7;
+const CHIP_ERASE_ALT: u8 = 0x60;
+
+/// Manufacturer and memory type of a Winbond W25Q, the third ID byte is log2 of the size
+const NOR_ID: [u8; 2] = [0xef, 0x40];
+const NOR_PAGE: AWord = 0x100;
+const NOR_SECTOR: AWord = 0x1000;
+const NOR_BLOCK: AWord = 0x10000;
+/// Status register bit, reads as 0 since programming and erasing take no time
+const NOR_WEL: u8 = 1 << 1;
+
+/// Serial NOR flash with 24 bit addresses, programming only clears bits and erased bytes read
+/// 0xFF. Program and erase commands need a write enable first, erases happen when the chip is
+/// deselected.
+pub struct NorFlash {
+    storage: Box<dyn AddressSpace>,
+    write_enabled: bool,
+    command: u8,
+    /// Bytes exchanged since the chip was selected
+    position: usize,
+    address: AWord,
+}
+
+impl NorFlash {
+    /// Kept in `path` if given, created erased when missing, otherwise erased on every start
+    pub fn new(len: AWord, path: Option<String>) -> Result<Self, String> {
+        if !len.is_power_of_two() || !(NOR_BLOCK..=1 << 24).contains(&len) {
+            return Err(format!("A NOR flash has a power of 2 size from 64KiB to 16MiB, not {len:#x}"));
+        }
+        let storage: Box<dyn AddressSpace> = match path {
+            Some(path) => Box::new(PersistentMemory::open(0, len, path.clone(), 0xff)
+                .map_err(|err| format!("Failed to open {path}: {err}"))?),
+            None => Box::new(BufferMemory {origin: 0, buffer: vec![0xff; len as usize].into_boxed_slice()}),
+        };
+        Ok(Self {storage, write_enabled: false, command: 0, position: 0, address: 0})
+    }
+    fn len(&self) -> AWord {self.storage.len()}
+    /// Addresses past the end wrap around
+    fn wrap(&self, adr: AWord) -> AWord {
+        adr & (self.len() - 1)
+    }
+    fn erase(&mut self, size: AWord) {
+        let start = self.wrap(self.address) & !(size - 1);
+        for adr in start..start + size {
+            self.storage.writeb(adr, 0xff);
+        }
+    }
+}
+
+impl SpiDevice for NorFlash {
+    fn select(&mut self) {
+        (self.position, self.address) = (0, 0);
+    }
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let idx = self.position;
+        self.position += 1;
+        if idx == 0 {
+            self.command = mosi;
+            match mosi {
+                WRITE_ENABLE => self.write_enabled = true,
+                WRITE_DISABLE => self.write_enabled = false,
+                _ => {},
+            }
+            return 0xff;
+        }
+        let addressed = matches!(self.command, READ | FAST_READ | PAGE_PROGRAM | SECTOR_ERASE | BLOCK_ERASE);
+        if addressed && idx <= 3 {
+            self.address = self.address << 8 | mosi as AWord;
+            return 0xff;
+        }
+        // Bytes after the address
+        let data = (idx as AWord).wrapping_sub(4);
+        match self.command {
+            READ_ID => NOR_ID.get(idx - 1).copied()
+                .unwrap_or(if idx == 3 {self.len().trailing_zeros() as u8} else {0}),
+            READ_STATUS => if self.write_enabled {NOR_WEL} else {0},
+            READ => self.storage.readb(self.wrap(self.address.wrapping_add(data))),
+            FAST_READ if data == 0 => 0xff,
+            FAST_READ => self.storage.readb(self.wrap(self.address.wrapping_add(data - 1))),
+            // Wraps around within the page
+            PAGE_PROGRAM if self.write_enabled => {
+                let page = self.wrap(self.address) & !(NOR_PAGE - 1);
+                let adr = page | self.address.wrapping_add(data) & (NOR_PAGE - 1);
+                let programmed = self.storage.readb(adr) & mosi;
+                self.storage.writeb(adr, programmed);
+                0xff
+            },
+            _ => 0xff,
+        }
+    }
+    fn deselect(&mut self) {
+        if !self.write_enabled {
+            return;
+        }
+        match (self.command, self.position) {
+            (SECTOR_ERASE, 4) => self.erase(NOR_SECTOR),
+            (BLOCK_ERASE, 4) => self.erase(NOR_BLOCK),
+            (CHIP_ERASE | CHIP_ERASE_ALT, 1) => {
+                self.address = 0;
+                self.erase(self.len());
+            },
+            (PAGE_PROGRAM, 5..) => {},
+            _ => return,
+        }
+        self.write_enabled = false;
+    }
+}
+
+impl DeviceState for NorFlash {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.write_enabled as u8, self.command]);
+        out.extend((self.position as AWord).to_le_bytes());
+        out.extend(self.address.to_le_bytes());
+        self.storage.save_state(out);
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() < 10 {
+            return Err("Invalid NOR flash state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[idx..idx + 4].try_into().unwrap());
+        (self.write_enabled, self.command) = (state[0] != 0, state[1]);
+        (self.position, self.address) = (word(2) as usize, word(6));
+        self.storage.restore_state(&state[10..])
+    }
+    fn non_volatile(&self) -> bool {true}
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.storage.flush()
+    }
+}
+
+/// An SPI controller with FIFOs and a chip select line per device
+///
+/// Bytes written to DATA are shifted out one after the other, each taking 8 bits, and what the
+/// selected devices send back goes into the RX FIFO. MISO reads 0xFF without a device selected,
+/// several selected devices drive it together and zeros win. RX not empty and TX empty are the
+/// interrupt flags. The DMA requests ask for a byte to be taken from RX or put into TX while
+/// either is possible.
+pub struct Spi {
+    pub origin: AWord,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    pub dma: DmaLines,
+    /// Entries of each FIFO
+    pub depth: usize,
+    devices: Vec<Box<dyn SpiDevice>>,
+    ctrl: AWord,
+    cs: AWord,
+    div: AWord,
+    overrun: bool,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    /// Cycles spent on the byte being exchanged
+    elapsed: u64,
+}
+
+impl Spi {
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, dma: DmaLines, depth: usize, devices: Vec<Box<dyn SpiDevice>>) -> Result<Self, String> {
+        if 32 < devices.len() {
+            return Err(format!("{} devices for 32 chip select lines", devices.len()));
+        }
+        Ok(Self {
+            origin, context, irq, dma, depth, devices,
+            ctrl: 0, cs: 0, div: 0, overrun: false,
+            tx: VecDeque::new(), rx: VecDeque::new(), elapsed: 0,
+        })
+    }
+    fn status(&self) -> AWord {
+        let flag = |set: bool, bit: AWord| if set {bit} else {0};
+        flag(!self.rx.is_empty(), STATUS_RXNE) | flag(self.tx.is_empty(), STATUS_TXE)
+            | flag(self.tx.len() >= self.depth, STATUS_TXF) | flag(self.overrun, STATUS_OVR)
+    }
+    fn selected(&self, line: usize) -> bool {
+        self.cs & 1 << line != 0
+    }
+    fn set_cs(&mut self, cs: AWord) {
+        let changed = self.cs ^ cs;
+        self.cs = cs;
+        for (line, device) in self.devices.iter_mut().enumerate() {
+            match (changed & 1 << line != 0, cs & 1 << line != 0) {
+                (true, true) => device.select(),
+                (true, false) => device.deselect(),
+                _ => {},
+            }
+        }
+    }
+    fn exchange(&mut self, mosi: u8) {
+        let mut miso = 0xff;
+        for line in 0..self.devices.len() {
+            if self.selected(line) {
+                miso &= self.devices[line].transfer(mosi);
+            }
+        }
+        match self.rx.len() < self.depth {
+            true => self.rx.push_back(miso),
+            false => self.overrun = true,
+        }
+    }
+    /// Everything but non volatile devices when `volatile`, see `AddressSpace::save_volatile`
+    fn save(&self, out: &mut Vec<u8>, volatile: bool) {
+        for word in [self.ctrl, self.cs, self.div, self.overrun as AWord] {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(self.elapsed.to_le_bytes());
+        for fifo in [&self.tx, &self.rx] {
+            out.extend((fifo.len() as AWord).to_le_bytes());
+            out.extend(fifo);
+        }
+        save_devices(self.devices.iter().map(|device| device.as_ref() as &dyn DeviceState), out, volatile);
+    }
+    fn restore(&mut self, state: &[u8], volatile: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reader = StateReader::new(state, "Invalid SPI state");
+        let (ctrl, cs, div, overrun) = (reader.word()?, reader.word()?, reader.word()?, reader.word()? != 0);
+        let elapsed = reader.u64()?;
+        let tx = reader.counted()?.iter().copied().collect();
+        let rx = reader.counted()?.iter().copied().collect();
+        restore_devices(self.devices.iter_mut().map(|device| device.as_mut() as &mut dyn DeviceState), &mut reader, volatile)?;
+        reader.finish()?;
+        (self.ctrl, self.cs, self.div, self.overrun, self.elapsed, self.tx, self.rx) = (ctrl, cs, div, overrun, elapsed, tx, rx);
+        Ok(())
+    }
+}
+
+impl AddressSpace for Spi {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {SPI_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            DATA if adr == DATA => return self.rx.pop_front().unwrap_or(0),
+            STATUS => self.status(),
+            CTRL => self.ctrl,
+            CS => self.cs,
+            DIV => self.div,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let lane = |word: AWord| word & !(0xff << shift) | (x as AWord) << shift;
+        match adr & !3 {
+            DATA if adr == DATA && self.tx.len() < self.depth => self.tx.push_back(x),
+            STATUS if (x as AWord) << shift & STATUS_OVR != 0 => self.overrun = false,
+            CTRL => self.ctrl = lane(self.ctrl),
+            CS => self.set_cs(lane(self.cs)),
+            DIV => self.div = lane(self.div),
+            _ => {},
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        let byte_cycles = self.div as u64 * 8;
+        let mut left = cycles;
+        while let Some(&mosi) = self.tx.front() {
+            let needed = byte_cycles - self.elapsed.min(byte_cycles);
+            if left < needed {
+                self.elapsed += left;
+                break;
+            }
+            left -= needed;
+            self.elapsed = 0;
+            self.tx.pop_front();
+            self.exchange(mosi);
+        }
+        let rx_irq = self.ctrl & CTRL_RXIE != 0 && !self.rx.is_empty();
+        let tx_irq = self.ctrl & CTRL_TXEIE != 0 && self.tx.is_empty();
+        let mut context = self.context.borrow_mut();
+        context.request(self.dma.rx, !self.rx.is_empty());
+        context.request(self.dma.tx, self.tx.len() < self.depth);
+        if let Some(irq) = self.irq && (rx_irq || tx_irq) {
+            context.nvic.raise(irq);
+        }
+    }
+    fn probe(&self, probe: &mut Probe) {
+        for line in 0..self.devices.len() {
+            probe.signal(format_args!("cs{line}"), 1, self.selected(line) as u64);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {self.save(out, false)}
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore(state, false)
+    }
+    fn save_volatile(&self, out: &mut Vec<u8>) {self.save(out, true)}
+    fn restore_volatile(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.restore(state, true)
+    }
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for device in self.devices.iter_mut() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_spi() {
+    let context = SharedContext::default();
+    let flash = NorFlash::new(0x10000, None).unwrap();
+    assert!(NorFlash::new(0x3000, None).is_err());
+    let mut spi = Spi::new(0, context.clone(), Some(1), Default::default(), 8, vec![Box::new(flash), Box::new(Loopback)]).unwrap();
+    let mut transaction = |cs: AWord, bytes: &[u8]| -> Vec<u8> {
+        spi.write_w_le(CS, cs);
+        let mut received = Vec::new();
+        for &byte in bytes {
+            spi.writeb(DATA, byte);
+            spi.tick(1);
+            received.push(spi.readb(DATA));
+        }
+        spi.write_w_le(CS, 0);
+        received
+    };
+
+    assert_eq!(transaction(1, &[READ_ID, 0, 0, 0]), [0xff, 0xef, 0x40, 16]);
+    assert_eq!(transaction(2, &[1, 2, 3]), [1, 2, 3]);
+    // Both drive MISO
+    assert_eq!(transaction(3, &[READ_ID, 0x0f]), [READ_ID, 0x0f]);
+
+    // Programming needs a write enable and only clears bits, the page wraps around
+    transaction(1, &[PAGE_PROGRAM, 0, 0x01, 0xfe, 0x12, 0x34]);
+    assert_eq!(transaction(1, &[READ, 0, 0x01, 0xfe, 0, 0]), [0xff; 6]);
+    transaction(1, &[WRITE_ENABLE]);
+    assert_eq!(transaction(1, &[READ_STATUS, 0]), [0xff, NOR_WEL]);
+    transaction(1, &[PAGE_PROGRAM, 0, 0x01, 0xfe, 0x12, 0x34, 0x56]);
+    assert_eq!(transaction(1, &[READ_STATUS, 0]), [0xff, 0]);
+    assert_eq!(transaction(1, &[READ, 0, 0x01, 0xfe, 0, 0, 0])[4..], [0x12, 0x34, 0xff]);
+    assert_eq!(transaction(1, &[FAST_READ, 0, 0x01, 0x00, 0, 0])[5], 0x56);
+
+    transaction(1, &[WRITE_ENABLE]);
+    transaction(1, &[SECTOR_ERASE, 0, 0x0f, 0xff]);
+    assert_eq!(transaction(1, &[READ, 0, 0x01, 0xfe, 0, 0])[4..], [0xff, 0xff]);
+
+    // Shifting takes 8 bits
+    spi.write_w_le(DIV, 2);
+    spi.write_w_le(CTRL, CTRL_RXIE);
+    spi.write_w_le(CS, 2);
+    spi.writeb(DATA, 0x5a);
+    spi.tick(15);
+    assert_eq!(spi.read_w_le(STATUS), 0);
+    spi.tick(1);
+    assert_eq!(spi.read_w_le(STATUS), STATUS_RXNE | STATUS_TXE);
+    assert_eq!(context.borrow().nvic.pending, 1 << 1);
+    assert_eq!(spi.readb(DATA), 0x5a);
+}
+#[test]
+fn test_spi_reset() {
+    let flash = NorFlash::new(0x10000, None).unwrap();
+    let mut spi = Spi::new(0, SharedContext::default(), None, Default::default(), 8, vec![Box::new(flash)]).unwrap();
+    let transaction = |spi: &mut Spi, bytes: &[u8]| -> Vec<u8> {
+        spi.write_w_le(CS, 1);
+        let received = bytes.iter().map(|&byte| {
+            spi.writeb(DATA, byte);
+            spi.tick(1);
+            spi.readb(DATA)
+        }).collect();
+        spi.write_w_le(CS, 0);
+        received
+    };
+    let mut power_on = Vec::new();
+    spi.save_volatile(&mut power_on);
+    transaction(&mut spi, &[WRITE_ENABLE]);
+    transaction(&mut spi, &[PAGE_PROGRAM, 0, 0, 0, 0x12]);
+    spi.write_w_le(CTRL, CTRL_RXIE);
+    spi.write_w_le(DIV, 4);
+
+    // A system reset puts the controller back and leaves the flash as it is
+    spi.restore_volatile(&power_on).unwrap();
+    assert_eq!((spi.read_w_le(CTRL), spi.read_w_le(DIV)), (0, 0));
+    assert_eq!(transaction(&mut spi, &[READ, 0, 0, 0, 0])[4], 0x12);
+}
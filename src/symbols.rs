@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use crate::core::AWord;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// Symbol table from an ELF32 little endian file, eg `build/program.elf`
+#[derive(Debug, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, AWord>,
+    by_address: HashMap<AWord, String>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&std::fs::read(path)?)
+    }
+    pub fn parse(elf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let u16_at = |at: usize| -> Result<u16, Box<dyn std::error::Error>> {
+            Ok(u16::from_le_bytes(elf.get(at..at + 2).ok_or("Truncated ELF")?.try_into()?))
+        };
+        let u32_at = |at: usize| -> Result<u32, Box<dyn std::error::Error>> {
+            Ok(u32::from_le_bytes(elf.get(at..at + 4).ok_or("Truncated ELF")?.try_into()?))
+        };
+        // Magic, 32 bit, little endian
+        if elf.get(0..6) != Some(&[0x7f, b'E', b'L', b'F', 1, 1]) {
+            return Err("Not an ELF32 little endian file".into());
+        }
+
+        let section_offset = u32_at(0x20)? as usize;
+        let section_size = u16_at(0x2e)? as usize;
+        let section_count = u16_at(0x30)? as usize;
+        let section = |idx: usize| section_offset + idx * section_size;
+
+        let mut symbols = Self::default();
+        for idx in 0..section_count {
+            if u32_at(section(idx) + 0x4)? != SHT_SYMTAB {
+                continue;
+            }
+            let table_offset = u32_at(section(idx) + 0x10)? as usize;
+            let table_size = u32_at(section(idx) + 0x14)? as usize;
+            let strings = section(u32_at(section(idx) + 0x18)? as usize);
+            let strings_offset = u32_at(strings + 0x10)? as usize;
+            let entry_size = (u32_at(section(idx) + 0x24)? as usize).max(16);
+
+            for entry in (table_offset..table_offset + table_size).step_by(entry_size) {
+                let name_start = strings_offset + u32_at(entry)? as usize;
+                let name_len = elf.get(name_start..).ok_or("Truncated ELF")?
+                    .iter().position(|&c| c == 0).ok_or("Unterminated symbol name")?;
+                let name = std::str::from_utf8(&elf[name_start..name_start + name_len])?;
+                let info = *elf.get(entry + 0xc).ok_or("Truncated ELF")?;
+                let mut address = u32_at(entry + 0x4)?;
+                // Section and file symbols, and ARM mapping symbols like `$t`
+                if name.is_empty() || name.starts_with('$') || 2 < info & 0xf {
+                    continue;
+                }
+                // Thumb functions have their lowest bit set
+                if info & 0xf == STT_FUNC {
+                    address &= !1;
+                }
+                symbols.by_name.insert(name.to_string(), address);
+                symbols.by_address.insert(address, name.to_string());
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<AWord> {
+        self.by_name.get(name).copied()
+    }
+    pub fn name_at(&self, adr: AWord) -> Option<&str> {
+        self.by_address.get(&adr).map(|name| name.as_str())
+    }
+}
+
+#[test]
+fn test_program_symbols() {
+    let symbols = Symbols::load("build/program.elf").unwrap();
+    assert_eq!(symbols.lookup("_start"), Some(0));
+    assert_eq!(symbols.name_at(symbols.lookup("centry").unwrap()), Some("centry"));
+    assert!(symbols.lookup("not_a_symbol").is_none());
+}
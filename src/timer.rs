@@ -0,0 +1,247 @@
+use crate::adr::AddressSpace;
+use crate::context::SharedContext;
+use crate::core::*;
+use crate::gpio::SharedPins;
+use crate::vcd::Probe;
+
+// Register offsets
+/// Bit 0 enables counting
+const CTRL: AWord = 0x00;
+/// The counter advances every `PSC + 1` cycles
+const PSC: AWord = 0x04;
+/// The counter wraps to 0 after reaching `ARR`
+const ARR: AWord = 0x08;
+const CNT: AWord = 0x0c;
+/// Interrupt enables, laid out like FLAGS
+const IER: AWord = 0x10;
+/// Bit 0 overflow, bit 1 + n compare or capture on channel n, write 1 to clear
+const FLAGS: AWord = 0x14;
+/// Two bits per channel, see `Mode`
+const MODES: AWord = 0x18;
+/// Compare value of each channel, a word apart
+const CCR: AWord = 0x20;
+const TIMER_LEN: AWord = 0x30;
+
+const CTRL_EN: AWord = 1 << 0;
+const FLAG_OVF: AWord = 1 << 0;
+pub const MAX_CHANNELS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Off,
+    /// Flag when the counter reaches CCR
+    Compare,
+    /// Drive the pin high while the counter is below CCR, and flag like `Compare`
+    Pwm,
+    /// Latch the counter into CCR on a rising edge of the pin, and flag
+    Capture,
+}
+
+/// A general purpose timer with up to 4 channels, counting cycles of the core
+///
+/// Channels can drive or capture from `pins` of a GPIO region, `outputs[n]` being the pin of
+/// channel n.
+pub struct Timer {
+    pub origin: AWord,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    pub channels: usize,
+    pins: Option<SharedPins>,
+    outputs: Vec<u32>,
+    ctrl: AWord,
+    psc: AWord,
+    arr: AWord,
+    cnt: AWord,
+    ier: AWord,
+    flags: AWord,
+    modes: AWord,
+    ccr: [AWord; MAX_CHANNELS],
+    /// Cycles towards the next count
+    prescaled: u64,
+    /// Levels of the pins as of the last tick, for capture edges
+    levels: AWord,
+}
+
+impl Timer {
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, channels: usize, pins: Option<SharedPins>, outputs: Vec<u32>) -> Result<Self, String> {
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(format!("A timer has 1 to {MAX_CHANNELS} channels, not {channels}"));
+        }
+        if channels < outputs.len() {
+            return Err(format!("{} pins for {channels} channels", outputs.len()));
+        }
+        if let Some(pin) = outputs.iter().find(|&&pin| 32 <= pin) {
+            return Err(format!("Pin {pin} doesn't exist, there are 32"));
+        }
+        if pins.is_none() && !outputs.is_empty() {
+            return Err("`pins` needs the `gpio` region they belong to".to_string());
+        }
+        Ok(Self {
+            origin, context, irq, channels, pins, outputs,
+            ctrl: 0, psc: 0, arr: AWord::MAX, cnt: 0, ier: 0, flags: 0, modes: 0, ccr: [0; MAX_CHANNELS],
+            prescaled: 0, levels: 0,
+        })
+    }
+    fn mode(&self, channel: usize) -> Mode {
+        match self.modes >> (channel * 2) & 3 {
+            0 => Mode::Off,
+            1 => Mode::Compare,
+            2 => Mode::Pwm,
+            _ => Mode::Capture,
+        }
+    }
+    /// Level of a PWM output, other channels don't drive their pin
+    fn output(&self, channel: usize) -> Option<bool> {
+        (self.mode(channel) == Mode::Pwm).then(|| self.cnt < self.ccr[channel])
+    }
+    fn register(&mut self, offset: AWord) -> Option<&mut AWord> {
+        let channel = offset.wrapping_sub(CCR) as usize / 4;
+        match offset {
+            CTRL => Some(&mut self.ctrl),
+            PSC => Some(&mut self.psc),
+            ARR => Some(&mut self.arr),
+            CNT => Some(&mut self.cnt),
+            IER => Some(&mut self.ier),
+            MODES => Some(&mut self.modes),
+            _ if channel < self.channels => Some(&mut self.ccr[channel]),
+            _ => None,
+        }
+    }
+
+    fn count(&mut self) {
+        if self.arr <= self.cnt {
+            self.cnt = 0;
+            self.flags |= FLAG_OVF;
+        } else {
+            self.cnt += 1;
+        }
+        for channel in 0..self.channels {
+            if matches!(self.mode(channel), Mode::Compare | Mode::Pwm) && self.cnt == self.ccr[channel] {
+                self.flags |= 2 << channel;
+            }
+        }
+    }
+    /// Capture rising edges and drive PWM outputs
+    fn connect(&mut self) {
+        let Some(pins) = self.pins.clone() else { return };
+        let mut pins = pins.borrow_mut();
+        let rising = pins.levels & !self.levels;
+        self.levels = pins.levels;
+        for (channel, &pin) in self.outputs.iter().enumerate() {
+            let bit = 1 << pin;
+            if self.mode(channel) == Mode::Capture && rising & bit != 0 {
+                self.ccr[channel] = self.cnt;
+                self.flags |= 2 << channel;
+            }
+            match self.output(channel) {
+                Some(high) => {
+                    pins.driven |= bit;
+                    pins.drive = if high {pins.drive | bit} else {pins.drive & !bit};
+                },
+                None => pins.driven &= !bit,
+            }
+        }
+    }
+}
+
+impl AddressSpace for Timer {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {TIMER_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            FLAGS => self.flags,
+            offset => self.register(offset).map_or(0, |register| *register),
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        match adr & !3 {
+            FLAGS => self.flags &= !((x as AWord) << shift),
+            offset => if let Some(register) = self.register(offset) {
+                *register = *register & !(0xff << shift) | (x as AWord) << shift;
+            },
+        }
+        // Channels that don't exist stay off
+        self.modes &= (1 << (self.channels * 2)) - 1;
+    }
+    fn tick(&mut self, cycles: u64) {
+        if self.ctrl & CTRL_EN != 0 {
+            self.prescaled += cycles;
+            while (self.psc as u64) < self.prescaled {
+                self.prescaled -= self.psc as u64 + 1;
+                self.count();
+            }
+        }
+        self.connect();
+        if let Some(irq) = self.irq && self.flags & self.ier != 0 {
+            self.context.borrow_mut().nvic.raise(irq);
+        }
+    }
+    fn probe(&self, probe: &mut Probe) {
+        for channel in 0..self.channels {
+            probe.signal(format_args!("out{channel}"), 1, self.output(channel).unwrap_or(false) as u64);
+        }
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        let words = [self.ctrl, self.psc, self.arr, self.cnt, self.ier, self.flags, self.modes, self.levels];
+        for word in words.iter().chain(&self.ccr) {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(self.prescaled.to_le_bytes());
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 12 * 4 + 8 {
+            return Err("Invalid timer state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[idx * 4..idx * 4 + 4].try_into().unwrap());
+        (self.ctrl, self.psc, self.arr, self.cnt) = (word(0), word(1), word(2), word(3));
+        (self.ier, self.flags, self.modes, self.levels) = (word(4), word(5), word(6), word(7));
+        self.ccr = [word(8), word(9), word(10), word(11)];
+        self.prescaled = u64::from_le_bytes(state[48..].try_into().unwrap());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_timer() {
+    use crate::gpio::Gpio;
+    let context = SharedContext::default();
+    let pins = SharedPins::default();
+    let mut gpio = Gpio::new(0, "gpio".to_string(), context.clone(), None, Vec::new(), None, pins.clone()).unwrap();
+    let mut timer = Timer::new(0x100, context.clone(), Some(4), 2, Some(pins), vec![6, 7]).unwrap();
+    assert!(Timer::new(0, context.clone(), None, 5, None, Vec::new()).is_err());
+
+    // Counting 0..=9 every other cycle, 30% duty on channel 0 and capture on channel 1
+    timer.write_w_le(PSC, 1);
+    timer.write_w_le(ARR, 9);
+    timer.write_w_le(CCR, 3);
+    timer.write_w_le(MODES, 0b10_11_10);
+    assert_eq!(timer.read_w_le(MODES), 0b11_10, "Only 2 channels");
+    timer.write_w_le(IER, FLAG_OVF);
+    timer.write_w_le(CTRL, CTRL_EN);
+    let mut high = 0;
+    for _ in 0..40 {
+        timer.tick(1);
+        gpio.tick(1);
+        high += gpio.levels() >> 6 & 1;
+    }
+    assert_eq!(high, 12);
+    assert_eq!(timer.read_w_le(CNT), 0);
+    assert_eq!(timer.read_w_le(FLAGS), FLAG_OVF | 2);
+    assert_eq!(context.borrow().nvic.pending, 1 << 4);
+    timer.write_w_le(FLAGS, FLAG_OVF | 2);
+
+    // A rising edge on pin 7 latches the counter
+    gpio.write_w_le(0x00, 1 << 7);
+    for _ in 0..5 {
+        timer.tick(1);
+        gpio.tick(1);
+    }
+    gpio.write_w_le(0x0c, 1 << 7);
+    gpio.tick(1);
+    timer.tick(1);
+    assert_eq!(timer.read_w_le(CCR + 4), 3);
+    // Channel 0 reached its compare value at the same count
+    assert_eq!(timer.read_w_le(FLAGS), 2 | 4);
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use crate::adr::AddressSpace;
+use crate::context::{DmaLines, SharedContext};
+use crate::core::*;
+use crate::snapshot::StateReader;
+use crate::vcd::Probe;
+
+// Register offsets
+const DATA: AWord = 0x00;
+const STATUS: AWord = 0x04;
+const CTRL: AWord = 0x08;
+/// Cycles per bit, 0 sends and receives without delay
+const BAUD: AWord = 0x0c;
+const UART_LEN: AWord = 0x10;
+
+const STATUS_RXNE: AWord = 1 << 0;
+const STATUS_TXE: AWord = 1 << 1;
+const STATUS_TXF: AWord = 1 << 2;
+/// A byte arrived while the RX FIFO was full and was lost, write 1 to clear
+const STATUS_OVR: AWord = 1 << 3;
+const CTRL_RXIE: AWord = 1 << 0;
+const CTRL_TXEIE: AWord = 1 << 1;
+
+/// Start bit, 8 data bits and a stop bit
+const BITS_PER_CHAR: u64 = 10;
+
+/// The host side of a UART, eg `"stdout"`, `"pty"`, `"tcp:4000"` or `"file:uart.log"`
+pub struct Host {
+    out: Box<dyn Write>,
+    /// Filled by a thread reading the host, if the host can send anything
+    input: Option<Receiver<u8>>,
+}
+
+impl Host {
+    pub fn open(spec: &str) -> Result<Self, String> {
+        let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+        match kind {
+            "stdout" => Ok(Self {out: Box::new(std::io::stdout()), input: None}),
+            // Shares stdin with the monitor
+            "stdio" => Ok(Self {out: Box::new(std::io::stdout()), input: Some(reader(std::io::stdin()))}),
+            "file" => {
+                let file = std::fs::File::create(arg).map_err(|err| format!("Failed to create {arg}: {err}"))?;
+                Ok(Self {out: Box::new(file), input: None})
+            },
+            "tcp" => {
+                // A bare port is only reachable from this machine
+                let address = if arg.contains(':') {arg.to_string()} else {format!("127.0.0.1:{arg}")};
+                let listener = std::net::TcpListener::bind(&address)
+                    .map_err(|err| format!("Failed to listen on {address}: {err}"))?;
+                eprintln!("UART listening on {address}");
+                let client = Arc::new(Mutex::new(None));
+                let (sender, input) = channel();
+                let accepted = client.clone();
+                // One client at a time, the next one is accepted once it disconnects
+                std::thread::spawn(move || for stream in listener.incoming().flatten() {
+                    let Ok(reading) = stream.try_clone() else { continue };
+                    *accepted.lock().unwrap() = Some(stream);
+                    forward(reading, &sender);
+                });
+                Ok(Self {out: Box::new(TcpClient(client)), input: Some(input)})
+            },
+            #[cfg(unix)]
+            "pty" => {
+                let (master, path) = open_pty()?;
+                eprintln!("UART on {path}");
+                let reading = master.try_clone().map_err(|err| err.to_string())?;
+                Ok(Self {out: Box::new(master), input: Some(reader(reading))})
+            },
+            _ => Err(format!("Unknown UART backend \"{spec}\", expected stdout, stdio, pty, tcp:<port> or file:<path>")),
+        }
+    }
+}
+
+fn reader(source: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, input) = channel();
+    std::thread::spawn(move || forward(source, &sender));
+    input
+}
+fn forward(source: impl Read, sender: &Sender<u8>) {
+    // Buffered reads return whatever is available, so bytes still arrive one by one
+    for byte in std::io::BufReader::new(source).bytes() {
+        let Ok(byte) = byte else { break };
+        if sender.send(byte).is_err() {
+            break;
+        }
+    }
+}
+
+/// Output before a client connects is dropped
+struct TcpClient(Arc<Mutex<Option<std::net::TcpStream>>>);
+impl Write for TcpClient {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut client = self.0.lock().unwrap();
+        if let Some(stream) = client.as_mut() && stream.write_all(buf).is_err() {
+            *client = None;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {Ok(())}
+}
+
+/// A new pseudo terminal, returns its master side and the path of its slave side
+#[cfg(unix)]
+fn open_pty() -> Result<(std::fs::File, String), String> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: plain libc calls, the descriptor is owned by the returned file
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(format!("Failed to open a pseudo terminal: {}", std::io::Error::last_os_error()));
+        }
+        let master = std::fs::File::from_raw_fd(fd);
+        let mut name = [0 as libc::c_char; 128];
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(format!("Failed to set up a pseudo terminal: {}", std::io::Error::last_os_error()));
+        }
+        let path = std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+        Ok((master, path))
+    }
+}
+
+/// A UART with TX and RX FIFOs, timed in cycles per bit
+///
+/// The byte at the head of the TX FIFO is the one being shifted out. Received bytes are taken
+/// from the host one character time apart and raise overrun when the RX FIFO is full. RX not
+/// empty and TX empty are the interrupt flags. The RX DMA request is asserted while RX is not
+/// empty, the TX one while TX is not full.
+pub struct Uart {
+    pub origin: AWord,
+    pub context: SharedContext,
+    pub irq: Option<u32>,
+    pub dma: DmaLines,
+    /// Entries of each FIFO
+    pub depth: usize,
+    pub host: Host,
+    ctrl: AWord,
+    baud: AWord,
+    overrun: bool,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+    /// Cycles spent on the character being sent or received
+    tx_elapsed: u64,
+    rx_elapsed: u64,
+}
+
+impl Uart {
+    pub fn new(origin: AWord, context: SharedContext, irq: Option<u32>, dma: DmaLines, depth: usize, host: Host) -> Self {
+        Self {
+            origin, context, irq, dma, depth, host,
+            ctrl: 0, baud: 0, overrun: false,
+            tx: VecDeque::new(), rx: VecDeque::new(), tx_elapsed: 0, rx_elapsed: 0,
+        }
+    }
+    fn char_cycles(&self) -> u64 {
+        self.baud as u64 * BITS_PER_CHAR
+    }
+    fn status(&self) -> AWord {
+        let flag = |set: bool, bit: AWord| if set {bit} else {0};
+        flag(!self.rx.is_empty(), STATUS_RXNE) | flag(self.tx.is_empty(), STATUS_TXE)
+            | flag(self.tx.len() >= self.depth, STATUS_TXF) | flag(self.overrun, STATUS_OVR)
+    }
+    fn register(&mut self, offset: AWord) -> &mut AWord {
+        match offset {
+            CTRL => &mut self.ctrl,
+            BAUD => &mut self.baud,
+            _ => unreachable!("The UART has 4 registers"),
+        }
+    }
+
+    fn transmit(&mut self, cycles: u64) {
+        let mut left = cycles;
+        while !self.tx.is_empty() {
+            let needed = self.char_cycles() - self.tx_elapsed.min(self.char_cycles());
+            if left < needed {
+                self.tx_elapsed += left;
+                return;
+            }
+            left -= needed;
+            self.tx_elapsed = 0;
+            let byte = self.tx.pop_front().expect("Checked above");
+            // A host that went away doesn't stop the firmware
+            let _ = self.host.out.write_all(&[byte]).and_then(|_| self.host.out.flush());
+        }
+    }
+    fn receive(&mut self, cycles: u64) {
+        let Some(input) = &self.host.input else { return };
+        self.rx_elapsed += cycles;
+        // Without a baud rate everything waiting is taken, as far as it fits
+        while self.rx_elapsed >= self.char_cycles() && (self.char_cycles() != 0 || self.rx.len() < self.depth) {
+            let Ok(byte) = input.try_recv() else { break };
+            self.rx_elapsed -= self.char_cycles();
+            match self.rx.len() < self.depth {
+                true => self.rx.push_back(byte),
+                false => self.overrun = true,
+            }
+        }
+        // An idle line doesn't save up time
+        self.rx_elapsed = self.rx_elapsed.min(self.char_cycles());
+    }
+}
+
+impl AddressSpace for Uart {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {UART_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            DATA if adr == DATA => return self.rx.pop_front().unwrap_or(0),
+            DATA => 0,
+            STATUS => self.status(),
+            offset => *self.register(offset),
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        match adr & !3 {
+            DATA if adr == DATA => if self.tx.len() < self.depth {
+                self.tx.push_back(x);
+            },
+            DATA => {},
+            STATUS => if (x as AWord) << shift & STATUS_OVR != 0 {
+                self.overrun = false;
+            },
+            offset => {
+                let register = self.register(offset);
+                *register = *register & !(0xff << shift) | (x as AWord) << shift;
+            },
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        self.transmit(cycles);
+        self.receive(cycles);
+        let rx_irq = self.ctrl & CTRL_RXIE != 0 && !self.rx.is_empty();
+        let tx_irq = self.ctrl & CTRL_TXEIE != 0 && self.tx.is_empty();
+        let mut context = self.context.borrow_mut();
+        context.request(self.dma.rx, !self.rx.is_empty());
+        context.request(self.dma.tx, self.tx.len() < self.depth);
+        if let Some(irq) = self.irq && (rx_irq || tx_irq) {
+            context.nvic.raise(irq);
+        }
+    }
+    /// The TX line, idle high, without a baud rate characters take no time and don't show
+    fn probe(&self, probe: &mut Probe) {
+        let bit = self.tx_elapsed / (self.baud as u64).max(1);
+        let level = match self.tx.front() {
+            Some(_) if self.baud == 0 => 1,
+            // Start bit
+            Some(_) if bit == 0 => 0,
+            Some(&byte) if bit <= 8 => (byte >> (bit - 1) & 1) as u64,
+            // Stop bit
+            _ => 1,
+        };
+        probe.signal(format_args!("tx"), 1, level);
+    }
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for word in [self.ctrl, self.baud, self.overrun as AWord] {
+            out.extend(word.to_le_bytes());
+        }
+        for elapsed in [self.tx_elapsed, self.rx_elapsed] {
+            out.extend(elapsed.to_le_bytes());
+        }
+        for fifo in [&self.tx, &self.rx] {
+            out.extend((fifo.len() as AWord).to_le_bytes());
+            out.extend(fifo);
+        }
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reader = StateReader::new(state, "Invalid UART state");
+        let (ctrl, baud, overrun) = (reader.word()?, reader.word()?, reader.word()? != 0);
+        let (tx_elapsed, rx_elapsed) = (reader.u64()?, reader.u64()?);
+        let tx = reader.counted()?.iter().copied().collect();
+        let rx = reader.counted()?.iter().copied().collect();
+        reader.finish()?;
+        (self.ctrl, self.baud, self.overrun, self.tx_elapsed, self.rx_elapsed, self.tx, self.rx)
+            = (ctrl, baud, overrun, tx_elapsed, rx_elapsed, tx, rx);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_uart() {
+    let path = std::env::temp_dir().join("cm0-uart-test.log");
+    let mut host = Host::open(&format!("file:{}", path.to_str().unwrap())).unwrap();
+    let (sender, input) = channel();
+    host.input = Some(input);
+    let context = SharedContext::default();
+    let mut uart = Uart::new(0x4000_0000, context.clone(), Some(5), DmaLines {rx: Some(0), tx: Some(3)}, 2, host);
+
+    // 2 cycles per bit, 20 per character
+    uart.write_w_le(BAUD, 2);
+    for &byte in b"abc" {
+        uart.writeb(DATA, byte);
+    }
+    assert_eq!(uart.read_w_le(STATUS), STATUS_TXF);
+    uart.tick(19);
+    assert_eq!(std::fs::read(&path).unwrap(), b"");
+    uart.tick(1);
+    assert_eq!(std::fs::read(&path).unwrap(), b"a");
+    uart.tick(20);
+    assert_eq!(uart.read_w_le(STATUS), STATUS_TXE);
+
+    uart.writeb(CTRL, CTRL_RXIE as AByte);
+    for &byte in b"xyz" {
+        sender.send(byte).unwrap();
+    }
+    // The line was idle for a character already, so three arrive in two more
+    uart.tick(40);
+    assert_eq!(context.borrow().nvic.pending, 1 << 5);
+    assert_eq!(context.borrow().requests, 1 << 0 | 1 << 3);
+    assert_eq!(uart.read_w_le(STATUS), STATUS_RXNE | STATUS_TXE | STATUS_OVR);
+    let mut state = Vec::new();
+    uart.save_state(&mut state);
+    assert_eq!([uart.readb(DATA), uart.readb(DATA), uart.readb(DATA)], [b'x', b'y', 0]);
+    uart.restore_state(&state).unwrap();
+    assert_eq!(uart.readb(DATA), b'x');
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,213 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use crate::adr::AddressSpace;
+use crate::core::AWord;
+use crate::exceptions::IRQ_BASE;
+use crate::memory::AddressDeMultiplexer;
+
+struct Var {
+    scope: String,
+    name: String,
+    width: u32,
+}
+
+/// Collects the signals of the core and of every region after a step
+#[derive(Default)]
+pub struct Probe {
+    scope: String,
+    /// Only collected for the first sample, which declares the signals
+    vars: Option<Vec<Var>>,
+    values: Vec<u64>,
+}
+
+impl Probe {
+    /// Signals reported from here on belong to `scope`, eg the label of a region
+    pub fn scope(&mut self, scope: &str) {
+        if self.vars.is_some() {
+            self.scope = scope.to_string();
+        }
+    }
+    /// A region must report the same signals in the same order every time
+    pub fn signal(&mut self, name: std::fmt::Arguments, width: u32, value: u64) {
+        if let Some(vars) = &mut self.vars {
+            vars.push(Var {scope: self.scope.clone(), name: name.to_string(), width});
+        }
+        self.values.push(value);
+    }
+}
+
+/// A Value Change Dump of a run, a time unit per cycle, eg for GTKWave
+///
+/// Time only moves forward in the file, samples taken after going back(eg when reversing) are
+/// left out until the run passes the last one written.
+pub struct Vcd {
+    out: Box<dyn Write>,
+    /// Whether the PC is recorded
+    pc: bool,
+    probe: Probe,
+    /// Values written last, empty until the header is
+    last: Vec<u64>,
+    widths: Vec<u32>,
+    /// Cycle of the last sample written
+    time: Option<u64>,
+}
+
+impl Vcd {
+    pub fn create(path: &str, pc: bool) -> std::io::Result<Self> {
+        let out = Box::new(std::io::BufWriter::new(std::fs::File::create(path)?));
+        Ok(Self::new(out, pc))
+    }
+    pub fn new(out: Box<dyn Write>, pc: bool) -> Self {
+        Self {out, pc, probe: Probe::default(), last: Vec::new(), widths: Vec::new(), time: None}
+    }
+
+    /// Record the state after `cycles`, `pc` is the address of the next instruction
+    pub fn sample(&mut self, cycles: u64, pc: AWord, memory: &AddressDeMultiplexer) -> std::io::Result<()> {
+        if self.time.is_some_and(|time| cycles <= time) {
+            return Ok(());
+        }
+        let probe = &mut self.probe;
+        probe.values.clear();
+        if self.time.is_none() {
+            probe.vars = Some(Vec::new());
+        }
+        probe.scope("core");
+        let nvic = memory.context().borrow().nvic;
+        let active_irq = nvic.active.checked_sub(IRQ_BASE).map_or(0, |irq| 1 << irq);
+        probe.signal(format_args!("irq_pending"), 32, nvic.pending as u64);
+        probe.signal(format_args!("irq_active"), 32, active_irq);
+        probe.signal(format_args!("exception"), 6, nvic.active as u64);
+        if self.pc {
+            probe.signal(format_args!("pc"), 32, pc as u64);
+        }
+        memory.probe(probe);
+
+        if let Some(vars) = probe.vars.take() {
+            self.out.write_all(header(&vars).as_bytes())?;
+            let mut dump = format!("#{cycles}\n$dumpvars\n");
+            for (idx, (var, &value)) in vars.iter().zip(&probe.values).enumerate() {
+                dump += &change(idx, var.width, value);
+            }
+            dump += "$end\n";
+            self.out.write_all(dump.as_bytes())?;
+            self.widths = vars.iter().map(|var| var.width).collect();
+        } else {
+            let mut changes = String::new();
+            for (idx, (&value, &last)) in probe.values.iter().zip(&self.last).enumerate() {
+                if value != last {
+                    changes += &change(idx, self.widths[idx], value);
+                }
+            }
+            if !changes.is_empty() {
+                write!(self.out, "#{cycles}\n{changes}")?;
+            }
+        }
+        self.last.clone_from(&probe.values);
+        self.time = Some(cycles);
+        Ok(())
+    }
+
+    /// End the waveform at `cycles` and write out everything
+    pub fn finish(&mut self, cycles: u64) -> std::io::Result<()> {
+        if self.time.is_some_and(|time| time < cycles) {
+            writeln!(self.out, "#{cycles}")?;
+            self.time = Some(cycles);
+        }
+        self.out.flush()
+    }
+}
+
+/// Declarations of every signal, grouped by scope
+fn header(vars: &[Var]) -> String {
+    let mut out = "$version Cortex-M0-Emulator $end\n$comment one time unit per cycle $end\n$timescale 1ns $end\n".to_string();
+    let mut scope: Option<&str> = None;
+    for (idx, var) in vars.iter().enumerate() {
+        if scope != Some(&var.scope) {
+            if scope.is_some() {
+                out += "$upscope $end\n";
+            }
+            let _ = writeln!(out, "$scope module {} $end", sanitize(&var.scope));
+            scope = Some(&var.scope);
+        }
+        let _ = writeln!(out, "$var wire {} {} {} $end", var.width, identifier(idx), sanitize(&var.name));
+    }
+    if scope.is_some() {
+        out += "$upscope $end\n";
+    }
+    out + "$enddefinitions $end\n"
+}
+
+/// Labels may contain anything, names in a VCD can't contain spaces and viewers split on `.`
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' {c} else {'_'}).collect()
+}
+
+/// Short codes made of the printable characters, `!` for the first signal
+fn identifier(mut idx: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (idx % 94) as u8) as char);
+        idx /= 94;
+        if idx == 0 {
+            return code;
+        }
+        idx -= 1;
+    }
+}
+
+fn change(idx: usize, width: u32, value: u64) -> String {
+    match width {
+        1 => format!("{value}{}\n", identifier(idx)),
+        _ => format!("b{value:b} {}\n", identifier(idx)),
+    }
+}
+
+#[test]
+fn test_vcd() {
+    use crate::emulator::Emulator;
+    use crate::gpio::Gpio;
+    use crate::memory::{BufferMemory, MappedRegion, Permissions};
+    use crate::uart::{Host, Uart};
+    let path = |name: &str| std::env::temp_dir().join(format!("cm0-vcd-test-{name}")).to_str().unwrap().to_string();
+    // str r0, [r1]; str r0, [r1, #8]; strb r3, [r2]
+    let mut buffer = [0u8; 0x100];
+    buffer[..6].copy_from_slice(&[0x08, 0x60, 0x88, 0x60, 0x13, 0x70]);
+    let mut memory = AddressDeMultiplexer::full();
+    let context = memory.context();
+    let mut add = |label: &str, space: Box<dyn AddressSpace>| memory.add_region(MappedRegion {
+        label: label.to_string(), kind: "test", priority: 0, perm: Permissions::ALL, space
+    }).unwrap();
+    add("ram", Box::new(BufferMemory {origin: 0, buffer: Box::new(buffer)}));
+    add("gpio", Box::new(Gpio::new(0x100, "gpio".to_string(), context.clone(), None, Vec::new(), None, Default::default()).unwrap()));
+    let host = Host::open(&format!("file:{}", path("uart"))).unwrap();
+    add("serial port", Box::new(Uart::new(0x200, context.clone(), None, Default::default(), 4, host)));
+    // 4 cycles per bit
+    memory.write_w(0x20c, 4);
+
+    let mut emulator = Emulator::new(memory);
+    (emulator.cpu.r[0], emulator.cpu.r[1], emulator.cpu.r[2], emulator.cpu.r[3]) = (1, 0x100, 0x200, 0x41);
+    emulator.record(Vcd::create(&path("out.vcd"), true).unwrap()).unwrap();
+    for _ in 0..8 {
+        assert_eq!(emulator.step(), None);
+    }
+    context.borrow_mut().nvic.raise(3);
+    emulator.step();
+    emulator.vcd.as_mut().unwrap().finish(emulator.cycles).unwrap();
+
+    let vcd = std::fs::read_to_string(path("out.vcd")).unwrap();
+    std::fs::remove_file(path("out.vcd")).unwrap();
+    std::fs::remove_file(path("uart")).unwrap();
+    let (header, changes) = vcd.split_once("$enddefinitions $end\n").unwrap();
+    assert!(header.contains("$scope module core $end\n$var wire 32 ! irq_pending $end"), "{header}");
+    assert!(header.contains("$var wire 32 $ pc $end\n$upscope $end\n$scope module gpio $end\n$var wire 1 % pin0 $end"), "{header}");
+    assert!(header.contains("$scope module serial_port $end\n$var wire 1 E tx $end"), "{header}");
+    // Every change by cycle, the PC moves every step. Lines only start with `#` for the time
+    let lines = format!("\n{}", changes.trim_end());
+    let at = |cycle: u64| lines.split("\n#").find_map(|block| block.strip_prefix(&format!("{cycle}\n"))).map(|block| format!("{block}\n")).unwrap_or_default();
+    assert!(at(0).starts_with("$dumpvars\nb0 !\nb0 \"\nb0 #\nb0 $\n0%\n"), "{changes}");
+    assert_eq!(at(2), "b100 $\n1%\n");
+    // Start bit, then bit 0 of 0x41 a bit time later
+    assert_eq!(at(3), "b110 $\n0E\n");
+    assert_eq!(at(6), "b1100 $\n1E\n");
+    assert_eq!(at(9), "b1000 !\nb10010 $\n");
+}
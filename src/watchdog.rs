@@ -0,0 +1,170 @@
+use crate::adr::AddressSpace;
+use crate::context::{ResetCause, SharedContext};
+use crate::core::*;
+
+// Register offsets
+/// Write only, see the `KEY_` values
+const KEY: AWord = 0x00;
+/// The counter goes down every `PSC + 1` cycles
+const PSC: AWord = 0x04;
+/// Loaded into the counter when starting or kicking the watchdog
+const RLR: AWord = 0x08;
+const CNT: AWord = 0x0c;
+/// A bit per `ResetCause`, write 1 to clear
+const RSR: AWord = 0x10;
+const WATCHDOG_LEN: AWord = 0x14;
+
+const KEY_RELOAD: AWord = 0xaaaa;
+const KEY_START: AWord = 0xcccc;
+/// Allows writing PSC and RLR until any other key is written
+const KEY_UNLOCK: AWord = 0x5555;
+
+/// An independent watchdog, resetting the system when its counter runs out
+///
+/// Once started it can't be stopped, only kicked by writing the reload key. Keys are 16 bits and
+/// taken once their upper byte is written, so halfword and word writes both work.
+pub struct Watchdog {
+    pub origin: AWord,
+    pub context: SharedContext,
+    key: AWord,
+    psc: AWord,
+    rlr: AWord,
+    cnt: AWord,
+    running: bool,
+    unlocked: bool,
+    /// Cycles towards the next count
+    prescaled: u64,
+}
+
+impl Watchdog {
+    pub fn new(origin: AWord, context: SharedContext) -> Self {
+        Self {origin, context, key: 0, psc: 0, rlr: 0xfff, cnt: 0xfff, running: false, unlocked: false, prescaled: 0}
+    }
+    fn take_key(&mut self, key: AWord) {
+        self.unlocked = key == KEY_UNLOCK;
+        match key {
+            KEY_RELOAD => self.cnt = self.rlr,
+            KEY_START => {
+                self.running = true;
+                self.cnt = self.rlr;
+            },
+            _ => {},
+        }
+    }
+}
+
+impl AddressSpace for Watchdog {
+    fn origin(&self) -> AWord {self.origin}
+    fn len(&self) -> AWord {WATCHDOG_LEN}
+    fn readb(&mut self, adr: AWord) -> AByte {
+        let word = match adr & !3 {
+            PSC => self.psc,
+            RLR => self.rlr,
+            CNT => self.cnt,
+            RSR => self.context.borrow().resets,
+            _ => 0,
+        };
+        word.to_le_bytes()[(adr % 4) as usize]
+    }
+    fn writeb(&mut self, adr: AWord, x: AByte) {
+        let shift = (adr % 4) * 8;
+        let lane = |word: AWord| word & !(0xff << shift) | (x as AWord) << shift;
+        match adr {
+            KEY => self.key = lane(self.key),
+            _ if adr == KEY + 1 => {
+                self.key = lane(self.key);
+                self.take_key(self.key & 0xffff);
+            },
+            _ => match adr & !3 {
+                PSC if self.unlocked => self.psc = lane(self.psc),
+                RLR if self.unlocked => self.rlr = lane(self.rlr),
+                RSR => self.context.borrow_mut().resets &= !((x as AWord) << shift),
+                _ => {},
+            },
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        if !self.running {
+            return;
+        }
+        self.prescaled += cycles;
+        while (self.psc as u64) < self.prescaled {
+            self.prescaled -= self.psc as u64 + 1;
+            self.cnt = self.cnt.saturating_sub(1);
+        }
+        if self.cnt == 0 {
+            self.context.borrow_mut().reset = Some(ResetCause::Watchdog);
+        }
+    }
+    // The reset causes belong to the context, they're saved here as only this region shows them
+    fn save_state(&self, out: &mut Vec<u8>) {
+        let words = [self.key, self.psc, self.rlr, self.cnt, self.running as AWord, self.unlocked as AWord, self.context.borrow().resets];
+        for word in words {
+            out.extend(word.to_le_bytes());
+        }
+        out.extend(self.prescaled.to_le_bytes());
+    }
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if state.len() != 7 * 4 + 8 {
+            return Err("Invalid watchdog state".into());
+        }
+        let word = |idx: usize| AWord::from_le_bytes(state[idx * 4..idx * 4 + 4].try_into().unwrap());
+        (self.key, self.psc, self.rlr, self.cnt) = (word(0), word(1), word(2), word(3));
+        (self.running, self.unlocked) = (word(4) != 0, word(5) != 0);
+        self.context.borrow_mut().resets = word(6);
+        self.prescaled = u64::from_le_bytes(state[28..].try_into().unwrap());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_watchdog() {
+    use crate::emulator::Emulator;
+    use crate::memory::{AddressDeMultiplexer, BufferMemory, MappedRegion, Permissions, PersistentMemory};
+    use crate::adr::Endian;
+    use crate::exceptions::{ScbRegisters, CPUID_M0};
+    use crate::registers::SP_IDX;
+    let path = std::env::temp_dir().join("cm0-watchdog-test.bin").to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let mut flash = [0u8; 0x100];
+    // Initial SP and reset vector, then `str r1, [r0]; str r1, [r2]; b .` at 0x40
+    flash[..8].copy_from_slice(&[0x00, 0x11, 0x00, 0x20, 0x41, 0x00, 0x00, 0x00]);
+    flash[0x40..0x46].copy_from_slice(&[0x01, 0x60, 0x11, 0x60, 0xfe, 0xe7]);
+    let mut memory = AddressDeMultiplexer::full();
+    let context = memory.context();
+    let mut add = |label: &str, kind: &'static str, space: Box<dyn AddressSpace>| memory.add_region(MappedRegion {
+        label: label.to_string(), kind, priority: 0, perm: Permissions::ALL, space
+    }).unwrap();
+    add("flash", "ram", Box::new(BufferMemory {origin: 0, buffer: Box::new(flash)}));
+    add("sram", "ram", Box::new(BufferMemory {origin: 0x2000_0000, buffer: Box::new([0; 0x100])}));
+    add("nvram", "nvram", Box::new(PersistentMemory::open(0x3000_0000, 4, path.clone(), 0).unwrap()));
+    add("iwdg", "watchdog", Box::new(Watchdog::new(0x4000_3000, context.clone())));
+    // Boots from the vector table
+    add("scb", "scb", Box::new(ScbRegisters {origin: 0xe000_ed00, cpuid: CPUID_M0, endian: Endian::Little}));
+    let mut emulator = Emulator::new(memory);
+    assert_eq!(emulator.memory.read_w(0x4000_3000 + RSR), ResetCause::PowerOn as AWord);
+    emulator.memory.write_w(0x4000_3000 + RSR, ResetCause::PowerOn as AWord);
+
+    // Counting down from 7 every other cycle
+    emulator.memory.write_w(0x4000_3000 + KEY, KEY_UNLOCK);
+    emulator.memory.write_w(0x4000_3000 + PSC, 1);
+    emulator.memory.write_w(0x4000_3000 + RLR, 7);
+    emulator.memory.write_hw(0x4000_3000 + KEY, KEY_START as AHalfWord);
+    (emulator.cpu.r[0], emulator.cpu.r[1], emulator.cpu.r[2]) = (0x2000_0000, 0x55, 0x3000_0000);
+    for _ in 0..10 {
+        emulator.step();
+        emulator.memory.write_w(0x4000_3000 + KEY, KEY_RELOAD);
+    }
+    assert_eq!(emulator.memory.read_w(0x4000_3000 + CNT), 7);
+    for _ in 0..13 {
+        emulator.step();
+    }
+    assert_eq!(emulator.memory.read_w(0x4000_3000 + CNT), 1);
+    emulator.step();
+    // Back at the reset vector with the watchdog stopped, nvram kept its contents
+    assert_eq!((emulator.pc(), emulator.cpu.r[SP_IDX], emulator.cpu.r[1]), (0x40, 0x2000_1100, 0));
+    assert_eq!(emulator.memory.read_w(0x2000_0000), 0);
+    assert_eq!(emulator.memory.read_w(0x3000_0000), 0x55);
+    assert_eq!(emulator.memory.read_w(0x4000_3000 + CNT), 0xfff);
+    assert_eq!(emulator.memory.read_w(0x4000_3000 + RSR), ResetCause::Watchdog as AWord);
+}